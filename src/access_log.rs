@@ -0,0 +1,283 @@
+use std::fmt::Write as FmtWrite;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::str::FromStr;
+use std::sync::Mutex;
+use serde_json::json;
+use tracing::error;
+use crate::request_info::RequestInfo;
+use crate::transfer_stats::{TransferStats, TransferOutcome};
+
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LogFormat {
+    // Combined Log Format, as used by Apache and nginx
+    Combined,
+    // One JSON object per line
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format {
+            "combined" => Ok(LogFormat::Combined),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("Unknown format '{}', expected combined or json", format)),
+        }
+    }
+}
+
+enum Output {
+    Stdout,
+    File(RotatingFile),
+}
+
+// Appends to a file, shifting it to <path>.1, <path>.2, ... once it grows
+// past max_bytes and keeping at most max_files old files.
+struct RotatingFile {
+    path: String,
+    max_bytes: u64,
+    max_files: usize,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    fn open(path: &str, max_bytes: u64, max_files: usize) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+
+        Ok(Self {
+            path: path.to_string(),
+            max_bytes,
+            max_files,
+            file,
+            size,
+        })
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        for i in (1..self.max_files).rev() {
+            let from = format!("{}.{}", self.path, i);
+            if fs::metadata(&from).is_ok() {
+                fs::rename(&from, format!("{}.{}", self.path, i + 1))?;
+            }
+        }
+
+        if self.max_files > 0 {
+            fs::rename(&self.path, format!("{}.1", self.path))?;
+        }
+        else {
+            fs::remove_file(&self.path)?;
+        }
+
+        self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        if self.size > 0 && self.size + line.len() as u64 + 1 > self.max_bytes {
+            self.rotate()?;
+        }
+
+        writeln!(self.file, "{}", line)?;
+        self.size += line.len() as u64 + 1;
+        Ok(())
+    }
+}
+
+pub struct AccessLog {
+    format: LogFormat,
+    output: Mutex<Output>,
+}
+
+impl AccessLog {
+    pub fn stdout(format: LogFormat) -> Self {
+        Self {
+            format,
+            output: Mutex::new(Output::Stdout),
+        }
+    }
+
+    pub fn file(format: LogFormat, path: &str, max_bytes: u64, max_files: usize) -> io::Result<Self> {
+        Ok(Self {
+            format,
+            output: Mutex::new(Output::File(RotatingFile::open(path, max_bytes, max_files)?)),
+        })
+    }
+
+    // Subscribed to the TransferAggregator, so streamed responses are logged
+    // once the body has been fully sent or the transfer ends early.
+    pub fn log_transfer(&self, stats: &TransferStats) {
+        self.log(&stats.hoster_id, &stats.request, stats.status, stats.bytes, stats.outcome);
+    }
+
+    pub fn log(&self, hoster_id: &str, request: &RequestInfo, status: u16, bytes: usize, outcome: TransferOutcome) {
+
+        let line = match self.format {
+            LogFormat::Combined => {
+                format!("{} - - [{}] \"{} {} {:?}\" {} {} \"{}\" \"{}\"",
+                    request.client_ip().unwrap_or_else(|| "-".to_string()),
+                    request.received_at.format("%d/%b/%Y:%H:%M:%S %z"),
+                    request.method,
+                    escape(&request.path),
+                    request.version,
+                    status,
+                    bytes,
+                    escape(request.referer.as_ref().map(|s| s.as_str()).unwrap_or("-")),
+                    escape(request.user_agent.as_ref().map(|s| s.as_str()).unwrap_or("-")))
+            },
+            LogFormat::Json => {
+                let duration = request.started.elapsed();
+                json!({
+                    "time": request.received_at.to_rfc3339(),
+                    "clientIp": request.client_ip(),
+                    "hosterId": hoster_id,
+                    "method": request.method,
+                    "path": request.path,
+                    "status": status,
+                    "bytes": bytes,
+                    "durationMs": duration.as_secs() * 1000 + u64::from(duration.subsec_millis()),
+                    "userAgent": request.user_agent,
+                    "referer": request.referer,
+                    "outcome": outcome.as_str(),
+                }).to_string()
+            },
+        };
+
+        match &mut *self.output.lock().expect("lock access log") {
+            Output::Stdout => {
+                println!("{}", line);
+            },
+            Output::File(file) => {
+                if let Err(e) = file.write_line(&line) {
+                    error!("Failed to write access log: {}", e);
+                }
+            },
+        }
+    }
+}
+
+// Escapes a quoted Combined Log Format field the way Apache does, so clients
+// can't end the field early or forge lines of their own
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if c.is_control() => {
+                write!(escaped, "\\x{:02x}", c as u32).expect("write");
+            },
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+    use serde_json::Value;
+    use warp::http::{HeaderMap, Version};
+
+    fn temp_path(name: &str) -> String {
+        env::temp_dir().join(format!("fibridge-{}-{}", name, process::id())).to_string_lossy().to_string()
+    }
+
+    fn request(path: &str) -> RequestInfo {
+        let mut headers = HeaderMap::new();
+        headers.insert("user-agent", "curl/7.64.0 \"quoted\" \\ \t".parse().unwrap());
+        headers.insert("referer", "https://example.com/".parse().unwrap());
+
+        let client_addr = "192.0.2.1:4711".parse().ok();
+        RequestInfo::new("GET", path.to_string(), Version::HTTP_2, client_addr, &headers)
+    }
+
+    fn logged(format: LogFormat, name: &str, path: &str) -> String {
+        let file = temp_path(name);
+        let _ = fs::remove_file(&file);
+
+        let log = AccessLog::file(format, &file, 1024 * 1024, 0).unwrap();
+        log.log("my-files", &request(path), 200, 1024, TransferOutcome::Completed);
+
+        let line = fs::read_to_string(&file).unwrap();
+        fs::remove_file(&file).unwrap();
+        line
+    }
+
+    #[test]
+    fn logs_the_combined_format() {
+        let line = logged(LogFormat::Combined, "combined-log", "/my-files/a\"b\n\x01.txt");
+
+        assert_eq!(line.lines().count(), 1);
+        assert!(line.starts_with("192.0.2.1 - - ["));
+        assert!(line.ends_with(concat!(
+            "] \"GET /my-files/a\\\"b\\n\\x01.txt HTTP/2.0\" 200 1024 ",
+            "\"https://example.com/\" \"curl/7.64.0 \\\"quoted\\\" \\\\ \\t\"\n")));
+    }
+
+    #[test]
+    fn logs_json() {
+        let line = logged(LogFormat::Json, "json-log", "/my-files/a\"b.txt");
+        let entry: Value = serde_json::from_str(&line).unwrap();
+
+        assert_eq!(entry["clientIp"], "192.0.2.1");
+        assert_eq!(entry["hosterId"], "my-files");
+        assert_eq!(entry["method"], "GET");
+        assert_eq!(entry["path"], "/my-files/a\"b.txt");
+        assert_eq!(entry["status"], 200);
+        assert_eq!(entry["bytes"], 1024);
+        assert_eq!(entry["referer"], "https://example.com/");
+        assert_eq!(entry["outcome"], "completed");
+    }
+
+    #[test]
+    fn rotates_files() {
+        let path = temp_path("rotating-log");
+        let rotated = |i: usize| format!("{}.{}", path, i);
+        for file in &[path.clone(), rotated(1), rotated(2), rotated(3)] {
+            let _ = fs::remove_file(file);
+        }
+
+        // Two 10 byte lines per file
+        let mut file = RotatingFile::open(&path, 20, 2).unwrap();
+        for line in &["line 0001", "line 0002", "line 0003", "line 0004", "line 0005", "line 0006", "line 0007"] {
+            file.write_line(line).unwrap();
+        }
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "line 0007\n");
+        assert_eq!(fs::read_to_string(rotated(1)).unwrap(), "line 0005\nline 0006\n");
+        assert_eq!(fs::read_to_string(rotated(2)).unwrap(), "line 0003\nline 0004\n");
+        assert!(fs::metadata(rotated(3)).is_err());
+
+        for file in &[path.clone(), rotated(1), rotated(2)] {
+            fs::remove_file(file).unwrap();
+        }
+    }
+
+    #[test]
+    fn rotates_without_keeping_old_files() {
+        let path = temp_path("unkept-log");
+        let _ = fs::remove_file(&path);
+
+        let mut file = RotatingFile::open(&path, 10, 0).unwrap();
+        file.write_line("line 0001").unwrap();
+        file.write_line("line 0002").unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "line 0002\n");
+        assert!(fs::metadata(format!("{}.1", path)).is_err());
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,304 @@
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use acme_lib::{Directory, DirectoryUrl, create_p384_key};
+use acme_lib::persist::FilePersist;
+use warp::{self, Filter};
+use tracing::{info, error};
+use crate::tls::{CertFiles, CertResolver};
+
+
+pub const LETS_ENCRYPT_URL: &str = "https://acme-v02.api.letsencrypt.org/directory";
+
+// How long to wait between checking whether the certificate needs renewing,
+// and before trying again after a failure.
+const CHECK_INTERVAL: Duration = Duration::from_secs(12 * 60 * 60);
+const RETRY_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+// What a check does about the certificate, given how many days the stored
+// one has left, if there is one
+#[derive(Debug, PartialEq)]
+enum Plan {
+    Keep,
+    // Still good, but it may have been issued before a crash, before being
+    // written out
+    WriteOut,
+    Request,
+}
+
+// HTTP-01 tokens waiting to be fetched by the ACME server, mapped to the
+// proof it expects back.
+pub type Challenges = Arc<Mutex<HashMap<String, String>>>;
+
+// Obtains and renews a certificate for host from an ACME server such as
+// Let's Encrypt. The account key and issued certificates are kept in
+// state_dir, so restarts don't ask for a new one.
+pub struct AcmeClient {
+    host: String,
+    email: String,
+    directory_url: String,
+    state_dir: String,
+    renew_days: i64,
+    challenges: Challenges,
+}
+
+impl AcmeClient {
+    pub fn new(host: String, email: String, directory_url: String, state_dir: &str, renew_days: i64) -> Self {
+        Self {
+            host,
+            email,
+            directory_url,
+            state_dir: state_dir.to_string(),
+            renew_days,
+            challenges: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn cert_files(&self) -> CertFiles {
+        cert_files(&self.state_dir, &self.host)
+    }
+
+    pub fn challenges(&self) -> Challenges {
+        self.challenges.clone()
+    }
+
+    // Checks the certificate now and every CHECK_INTERVAL after on a thread
+    // of its own, since the ACME client blocks. resolvers are reloaded
+    // whenever a new certificate is written, and only then, so they never
+    // see a new key with the old certificate.
+    pub fn start(self, resolvers: Vec<Arc<CertResolver>>) {
+        thread::spawn(move || {
+            loop {
+                let result = self.renew_if_needed();
+
+                match &result {
+                    Ok(true) => {
+                        for resolver in &resolvers {
+                            resolver.reload_provisioned();
+                        }
+                    },
+                    Ok(false) => (),
+                    Err(e) => {
+                        error!(host = %self.host, "Failed to obtain certificate: {}", e);
+                    },
+                }
+
+                thread::sleep(wait_after(&result));
+            }
+        });
+    }
+
+    fn renew_if_needed(&self) -> Result<bool, String> {
+        fs::create_dir_all(&self.state_dir).map_err(|e| e.to_string())?;
+
+        let persist = FilePersist::new(&self.state_dir);
+        let url = DirectoryUrl::Other(&self.directory_url);
+        let directory = Directory::from_url(persist, url).map_err(|e| e.to_string())?;
+        let account = directory.account(&self.email).map_err(|e| e.to_string())?;
+        let files = self.cert_files();
+
+        let cert = account.certificate(&self.host).map_err(|e| e.to_string())?;
+        let days_left = cert.as_ref().map(|cert| cert.valid_days_left());
+
+        match (plan(days_left, self.renew_days, Path::new(&files.cert).is_file()), cert) {
+            (Plan::Keep, _) => return Ok(false),
+            (Plan::WriteOut, Some(cert)) => {
+                write_cert_files(&files, cert.private_key(), cert.certificate()).map_err(|e| e.to_string())?;
+                return Ok(true);
+            },
+            _ => {
+                match days_left {
+                    Some(days_left) => info!(host = %self.host, days_left = days_left, "renewing certificate"),
+                    None => info!(host = %self.host, "requesting certificate"),
+                }
+            },
+        }
+
+        let mut order = account.new_order(&self.host, &[]).map_err(|e| e.to_string())?;
+
+        let csr = loop {
+            if let Some(csr) = order.confirm_validations() {
+                break csr;
+            }
+
+            let authorizations = order.authorizations().map_err(|e| e.to_string())?;
+
+            for authorization in authorizations.iter().filter(|authorization| authorization.need_challenge()) {
+                let challenge = authorization.http_challenge();
+                let token = challenge.http_token().to_string();
+
+                self.challenges.lock().expect("get lock").insert(token.clone(), challenge.http_proof());
+                let result = challenge.validate(5000);
+                self.challenges.lock().expect("get lock").remove(&token);
+
+                result.map_err(|e| e.to_string())?;
+            }
+
+            order.refresh().map_err(|e| e.to_string())?;
+        };
+
+        let cert = csr.finalize_pkey(create_p384_key(), 5000)
+            .and_then(|order| order.download_and_save_cert())
+            .map_err(|e| e.to_string())?;
+
+        write_cert_files(&files, cert.private_key(), cert.certificate()).map_err(|e| e.to_string())?;
+
+        info!(host = %self.host, days_left = cert.valid_days_left(), "certificate issued");
+
+        Ok(true)
+    }
+}
+
+// A certificate is renewed once it has renew_days or fewer left
+fn plan(days_left: Option<i64>, renew_days: i64, written: bool) -> Plan {
+    match days_left {
+        Some(days_left) if days_left > renew_days => {
+            if written {
+                Plan::Keep
+            }
+            else {
+                Plan::WriteOut
+            }
+        },
+        _ => Plan::Request,
+    }
+}
+
+// How long until the next check, sooner after a failure
+fn wait_after(result: &Result<bool, String>) -> Duration {
+    match result {
+        Ok(_) => CHECK_INTERVAL,
+        Err(_) => RETRY_INTERVAL,
+    }
+}
+
+// Where the current certificate for host is written, for the TLS listeners
+// to load.
+pub fn cert_files(state_dir: &str, host: &str) -> CertFiles {
+    let path = |suffix: &str| {
+        Path::new(state_dir).join(format!("{}{}", host, suffix)).to_string_lossy().to_string()
+    };
+
+    CertFiles {
+        cert: path("-cert.pem"),
+        key: path("-key.pem"),
+    }
+}
+
+// Written to temporary files and renamed, so a reload never sees half a
+// certificate. The pair only matches again once both are renamed, which is
+// why the ACME thread does its own reloading.
+fn write_cert_files(files: &CertFiles, key: &str, cert: &str) -> io::Result<()> {
+    let key_tmp = format!("{}.tmp", files.key);
+    let cert_tmp = format!("{}.tmp", files.cert);
+
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    // Only we should be able to read the private key
+    #[cfg(unix)]
+    options.mode(0o600);
+    options.open(&key_tmp)?.write_all(key.as_bytes())?;
+    fs::write(&cert_tmp, cert)?;
+    fs::rename(&key_tmp, &files.key)?;
+    fs::rename(&cert_tmp, &files.cert)?;
+
+    Ok(())
+}
+
+// Answers the ACME server's GET /.well-known/acme-challenge/<token>
+pub fn challenge_route(challenges: Challenges) -> impl Filter<Extract = (String,), Error = warp::Rejection> + Clone {
+    warp::path(".well-known")
+        .and(warp::path("acme-challenge"))
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .and_then(move |token: String| {
+            match challenges.lock().expect("get lock").get(&token) {
+                Some(proof) => Ok(proof.clone()),
+                None => Err(warp::reject::not_found()),
+            }
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    #[test]
+    fn renews_within_renew_days() {
+        assert_eq!(plan(None, 30, false), Plan::Request);
+        assert_eq!(plan(Some(60), 30, true), Plan::Keep);
+        assert_eq!(plan(Some(31), 30, true), Plan::Keep);
+        assert_eq!(plan(Some(30), 30, true), Plan::Request);
+        assert_eq!(plan(Some(-1), 30, true), Plan::Request);
+        assert_eq!(plan(Some(60), 30, false), Plan::WriteOut);
+        assert_eq!(plan(Some(30), 30, false), Plan::Request);
+    }
+
+    #[test]
+    fn retries_failures_sooner() {
+        assert_eq!(wait_after(&Ok(true)), CHECK_INTERVAL);
+        assert_eq!(wait_after(&Ok(false)), CHECK_INTERVAL);
+        assert_eq!(wait_after(&Err("rate limited".to_string())), RETRY_INTERVAL);
+        assert!(RETRY_INTERVAL < CHECK_INTERVAL);
+    }
+
+    #[test]
+    fn keeps_certificates_in_the_state_dir() {
+        let files = cert_files("/var/lib/fibridge/acme", "example.com");
+        assert_eq!(Path::new(&files.cert), Path::new("/var/lib/fibridge/acme/example.com-cert.pem"));
+        assert_eq!(Path::new(&files.key), Path::new("/var/lib/fibridge/acme/example.com-key.pem"));
+
+        let client = AcmeClient::new("example.com".to_string(), "admin@example.com".to_string(),
+            LETS_ENCRYPT_URL.to_string(), "acme", 30);
+        assert_eq!(Path::new(&client.cert_files().cert), Path::new("acme/example.com-cert.pem"));
+    }
+
+    #[test]
+    fn writes_both_files_whole() {
+        let state_dir = env::temp_dir().join(format!("fibridge-acme-{}", process::id()));
+        fs::create_dir_all(&state_dir).unwrap();
+        let files = cert_files(&state_dir.to_string_lossy(), "example.com");
+
+        write_cert_files(&files, "old key", "old cert").unwrap();
+        write_cert_files(&files, "new key", "new cert").unwrap();
+
+        assert_eq!(fs::read_to_string(&files.key).unwrap(), "new key");
+        assert_eq!(fs::read_to_string(&files.cert).unwrap(), "new cert");
+        assert!(!Path::new(&format!("{}.tmp", files.key)).exists());
+        assert!(!Path::new(&format!("{}.tmp", files.cert)).exists());
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(&files.key).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+
+        fs::remove_dir_all(&state_dir).unwrap();
+    }
+
+    #[test]
+    fn answers_pending_challenges() {
+        let challenges: Challenges = Arc::new(Mutex::new(HashMap::new()));
+        challenges.lock().unwrap().insert("token".to_string(), "token.proof".to_string());
+        let route = challenge_route(challenges);
+
+        let proof = warp::test::request()
+            .path("/.well-known/acme-challenge/token")
+            .filter(&route)
+            .unwrap();
+        assert_eq!(proof, "token.proof");
+
+        assert!(warp::test::request()
+            .path("/.well-known/acme-challenge/other")
+            .filter(&route)
+            .is_err());
+    }
+}
@@ -0,0 +1,340 @@
+use std::sync::Arc;
+use serde_json::{json, Value};
+use warp::{self, Filter};
+use warp::http::{HeaderMap, Response};
+use hyper::Body;
+use crate::{HosterManagers, BlockedIds};
+use crate::metrics::Metrics;
+use crate::basic_auth::constant_time_eq;
+
+
+// Operator API, served on its own listen address. Every request must carry
+// the admin token as "Authorization: Bearer <token>".
+//
+//   GET    /hosters                   snapshot of all connected hosters
+//   GET    /hosters/<id>              a single hoster
+//   DELETE /hosters/<id>              disconnect a hoster
+//   POST   /hosters/<id>/purge-cache  drop a hoster's cached files
+//   GET    /blocked                   list blocked ids
+//   PUT    /blocked/<id>              block an id, disconnecting it if connected
+//   DELETE /blocked/<id>              unblock an id
+//   GET    /metrics                   Prometheus metrics
+pub fn routes(
+    hoster_managers: HosterManagers,
+    blocked_ids: BlockedIds,
+    metrics: Arc<Metrics>,
+    token: String,
+) -> impl Filter<Extract = (Response<Body>,), Error = warp::Rejection> + Clone {
+
+    let token = Arc::new(token);
+    let authorized = warp::header::headers_cloned()
+        .map(move |headers: HeaderMap| is_authorized(&token, &headers));
+
+    let managers = warp::any().map(move || hoster_managers.clone());
+    let blocked = warp::any().map(move || blocked_ids.clone());
+
+    let list_hosters = warp::get2()
+        .and(warp::path("hosters"))
+        .and(warp::path::end())
+        .and(authorized.clone())
+        .and(managers.clone())
+        .map(|authorized: bool, hoster_managers: HosterManagers| {
+            if !authorized {
+                return unauthorized();
+            }
+
+            let lock = hoster_managers.lock().expect("get lock");
+            let snapshot = lock.iter()
+                .map(|(id, manager)| (id.clone(), json!(manager.info())))
+                .collect::<serde_json::Map<String, Value>>();
+
+            json_response(200, Value::Object(snapshot))
+        });
+
+    let get_hoster = warp::get2()
+        .and(warp::path("hosters"))
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and(authorized.clone())
+        .and(managers.clone())
+        .map(|id: String, authorized: bool, hoster_managers: HosterManagers| {
+            if !authorized {
+                return unauthorized();
+            }
+
+            let lock = hoster_managers.lock().expect("get lock");
+
+            match lock.get(&id) {
+                Some(manager) => json_response(200, json!(manager.info())),
+                None => not_found(&id),
+            }
+        });
+
+    let disconnect_hoster = warp::delete2()
+        .and(warp::path("hosters"))
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and(authorized.clone())
+        .and(managers.clone())
+        .map(|id: String, authorized: bool, hoster_managers: HosterManagers| {
+            if !authorized {
+                return unauthorized();
+            }
+
+            let removed = hoster_managers.lock().expect("get lock").remove(&id);
+
+            match removed {
+                Some(manager) => {
+                    manager.disconnect();
+                    json_response(200, json!({ "disconnected": id }))
+                },
+                None => not_found(&id),
+            }
+        });
+
+    let purge_cache = warp::post2()
+        .and(warp::path("hosters"))
+        .and(warp::path::param())
+        .and(warp::path("purge-cache"))
+        .and(warp::path::end())
+        .and(authorized.clone())
+        .and(managers.clone())
+        .map(|id: String, authorized: bool, hoster_managers: HosterManagers| {
+            if !authorized {
+                return unauthorized();
+            }
+
+            let lock = hoster_managers.lock().expect("get lock");
+
+            match lock.get(&id) {
+                Some(manager) => {
+                    manager.purge_cache();
+                    json_response(200, json!(manager.info()))
+                },
+                None => not_found(&id),
+            }
+        });
+
+    let list_blocked = warp::get2()
+        .and(warp::path("blocked"))
+        .and(warp::path::end())
+        .and(authorized.clone())
+        .and(blocked.clone())
+        .map(|authorized: bool, blocked_ids: BlockedIds| {
+            if !authorized {
+                return unauthorized();
+            }
+
+            let mut ids = blocked_ids.lock().expect("get lock").iter().cloned().collect::<Vec<_>>();
+            ids.sort();
+            json_response(200, json!(ids))
+        });
+
+    let block_id = warp::put2()
+        .and(warp::path("blocked"))
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and(authorized.clone())
+        .and(managers.clone())
+        .and(blocked.clone())
+        .map(|id: String, authorized: bool, hoster_managers: HosterManagers, blocked_ids: BlockedIds| {
+            if !authorized {
+                return unauthorized();
+            }
+
+            blocked_ids.lock().expect("get lock").insert(id.clone());
+
+            let disconnected = match hoster_managers.lock().expect("get lock").remove(&id) {
+                Some(manager) => {
+                    manager.disconnect();
+                    true
+                },
+                None => false,
+            };
+
+            json_response(200, json!({ "blocked": id, "disconnected": disconnected }))
+        });
+
+    let unblock_id = warp::delete2()
+        .and(warp::path("blocked"))
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and(authorized.clone())
+        .and(blocked.clone())
+        .map(|id: String, authorized: bool, blocked_ids: BlockedIds| {
+            if !authorized {
+                return unauthorized();
+            }
+
+            if blocked_ids.lock().expect("get lock").remove(&id) {
+                json_response(200, json!({ "unblocked": id }))
+            }
+            else {
+                not_found(&id)
+            }
+        });
+
+    let get_metrics = warp::get2()
+        .and(warp::path("metrics"))
+        .and(warp::path::end())
+        .and(authorized.clone())
+        .and(managers.clone())
+        .map(move |authorized: bool, hoster_managers: HosterManagers| {
+            if !authorized {
+                return unauthorized();
+            }
+
+            let hosters = hoster_managers.lock().expect("get lock").values()
+                .map(|manager| {
+                    let info = manager.info();
+                    (info.id, info.bytes_served)
+                })
+                .collect::<Vec<_>>();
+
+            Response::builder()
+                .header("Content-Type", "text/plain; version=0.0.4")
+                .body(metrics.render(&hosters).into())
+                .expect("metrics response")
+        });
+
+    list_hosters
+        .or(get_hoster)
+        .unify()
+        .or(disconnect_hoster)
+        .unify()
+        .or(purge_cache)
+        .unify()
+        .or(list_blocked)
+        .unify()
+        .or(block_id)
+        .unify()
+        .or(unblock_id)
+        .unify()
+        .or(get_metrics)
+        .unify()
+}
+
+fn is_authorized(token: &str, headers: &HeaderMap) -> bool {
+    headers.get("authorization")
+        .and_then(|value| value.to_str().ok())
+        .map(|value| constant_time_eq(value.as_bytes(), format!("Bearer {}", token).as_bytes()))
+        .unwrap_or(false)
+}
+
+fn json_response(status: u16, body: Value) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(body.to_string().into())
+        .expect("json response")
+}
+
+fn unauthorized() -> Response<Body> {
+    let mut response = json_response(401, json!({ "error": "Unauthorized" }));
+    response.headers_mut().insert("WWW-Authenticate", "Bearer".parse().expect("header value"));
+    response
+}
+
+fn not_found(id: &str) -> Response<Body> {
+    json_response(404, json!({ "error": format!("No hoster or block for '{}'", id) }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::{HashMap, HashSet};
+    use std::sync::Mutex;
+    use warp::http::StatusCode;
+
+    fn admin() -> (impl Filter<Extract = (Response<Body>,), Error = warp::Rejection> + Clone, BlockedIds) {
+        let hoster_managers: HosterManagers = Arc::new(Mutex::new(HashMap::new()));
+        let blocked_ids: BlockedIds = Arc::new(Mutex::new(HashSet::new()));
+        let routes = routes(hoster_managers, blocked_ids.clone(), Arc::new(Metrics::new()), "hunter2".to_string());
+        (routes, blocked_ids)
+    }
+
+    fn body<B: AsRef<[u8]>>(response: &Response<B>) -> Value {
+        serde_json::from_slice(response.body().as_ref()).expect("json body")
+    }
+
+    #[test]
+    fn requires_the_bearer_token() {
+        let (admin, _) = admin();
+
+        let response = warp::test::request().path("/hosters").reply(&admin);
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(response.headers()["WWW-Authenticate"], "Bearer");
+
+        for authorization in &["Bearer hunter3", "Bearer hunter22", "hunter2", "Basic aHVudGVyMg=="] {
+            let response = warp::test::request()
+                .path("/metrics")
+                .header("authorization", *authorization)
+                .reply(&admin);
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        }
+
+        let response = warp::test::request()
+            .method("PUT")
+            .path("/blocked/my-files")
+            .header("authorization", "Bearer nope")
+            .reply(&admin);
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response = warp::test::request()
+            .path("/hosters")
+            .header("authorization", "Bearer hunter2")
+            .reply(&admin);
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body(&response), json!({}));
+    }
+
+    #[test]
+    fn unknown_hosters_are_not_found() {
+        let (admin, _) = admin();
+
+        for (method, path) in &[("GET", "/hosters/nope"), ("DELETE", "/hosters/nope"), ("POST", "/hosters/nope/purge-cache")] {
+            let response = warp::test::request()
+                .method(method)
+                .path(path)
+                .header("authorization", "Bearer hunter2")
+                .reply(&admin);
+            assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        }
+    }
+
+    #[test]
+    fn blocks_and_unblocks_ids() {
+        let (admin, blocked_ids) = admin();
+
+        let request = |method: &str, path: &str| {
+            warp::test::request()
+                .method(method)
+                .path(path)
+                .header("authorization", "Bearer hunter2")
+                .reply(&admin)
+        };
+
+        let response = request("PUT", "/blocked/my-files");
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body(&response), json!({ "blocked": "my-files", "disconnected": false }));
+        assert!(blocked_ids.lock().unwrap().contains("my-files"));
+
+        assert_eq!(body(&request("GET", "/blocked")), json!(["my-files"]));
+
+        assert_eq!(request("DELETE", "/blocked/my-files").status(), StatusCode::OK);
+        assert_eq!(request("DELETE", "/blocked/my-files").status(), StatusCode::NOT_FOUND);
+        assert!(blocked_ids.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn serves_metrics() {
+        let (admin, _) = admin();
+
+        let response = warp::test::request()
+            .path("/metrics")
+            .header("authorization", "Bearer hunter2")
+            .reply(&admin);
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["Content-Type"], "text/plain; version=0.0.4");
+    }
+}
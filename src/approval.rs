@@ -0,0 +1,72 @@
+use std::collections::HashMap;
+use crate::basic_auth::normalize_path;
+
+
+// Which of a hoster's files need the hoster to approve each download. A rule
+// for a specific path takes precedence over the hoster-wide setting, so a
+// single file can be exempted from hoster-wide approval.
+#[derive(Default)]
+pub struct ApprovalRules {
+    hoster_wide: bool,
+    paths: HashMap<String, bool>,
+}
+
+impl ApprovalRules {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn set(&mut self, path: Option<String>, required: bool) {
+        match path {
+            Some(path) => {
+                self.paths.insert(normalize_path(&path), required);
+            },
+            None => {
+                self.hoster_wide = required;
+                // Hoster-wide changes start from a clean slate
+                self.paths.clear();
+            },
+        }
+    }
+
+    pub fn required(&self, path: &str) -> bool {
+        *self.paths.get(&normalize_path(path)).unwrap_or(&self.hoster_wide)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nothing_needs_approval_by_default() {
+        assert!(!ApprovalRules::new().required("/file.txt"));
+    }
+
+    #[test]
+    fn paths_are_matched_normalized() {
+        let mut rules = ApprovalRules::new();
+        rules.set(Some("dir/my%20file.txt".to_string()), true);
+
+        assert!(rules.required("/dir/my file.txt"));
+        assert!(rules.required("/dir/./other/../my%20file.txt"));
+        assert!(!rules.required("/dir/other.txt"));
+    }
+
+    #[test]
+    fn paths_override_the_hoster_wide_setting() {
+        let mut rules = ApprovalRules::new();
+        rules.set(None, true);
+        rules.set(Some("/public.txt".to_string()), false);
+
+        assert!(rules.required("/file.txt"));
+        assert!(!rules.required("/public.txt"));
+
+        // Until the hoster-wide setting changes again
+        rules.set(None, true);
+        assert!(rules.required("/public.txt"));
+
+        rules.set(None, false);
+        assert!(!rules.required("/file.txt"));
+    }
+}
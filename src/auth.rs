@@ -0,0 +1,408 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::collections::HashMap;
+use serde::Deserialize;
+use warp::http::HeaderMap;
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use crate::basic_auth::constant_time_eq;
+use crate::now;
+
+
+#[derive(Debug)]
+pub enum AuthError {
+    Missing,
+    Invalid(String),
+    Expired,
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AuthError::Missing => write!(f, "Missing credentials"),
+            AuthError::Invalid(reason) => write!(f, "Invalid credentials: {}", reason),
+            AuthError::Expired => write!(f, "Credentials expired"),
+        }
+    }
+}
+
+// What an authenticated hoster is allowed to do. Every field is optional, and
+// None means unrestricted.
+#[derive(Clone, Debug, Default)]
+pub struct HosterGrant {
+    pub id: Option<String>,
+    pub max_file_size: Option<u64>,
+    pub expires: Option<u64>,
+}
+
+impl HosterGrant {
+    fn check_expiry(self) -> Result<Self, AuthError> {
+        match self.expires {
+            Some(expires) if expires <= now() => Err(AuthError::Expired),
+            _ => Ok(self),
+        }
+    }
+}
+
+pub trait HosterAuthenticator {
+    fn authenticate(&self, token: &str) -> Result<HosterGrant, AuthError>;
+}
+
+// Accepts a token if any of the wrapped authenticators does.
+pub struct AnyAuthenticator {
+    authenticators: Vec<Box<dyn HosterAuthenticator + Send + Sync>>,
+}
+
+impl AnyAuthenticator {
+    pub fn new(authenticators: Vec<Box<dyn HosterAuthenticator + Send + Sync>>) -> Self {
+        Self {
+            authenticators,
+        }
+    }
+}
+
+impl HosterAuthenticator for AnyAuthenticator {
+    fn authenticate(&self, token: &str) -> Result<HosterGrant, AuthError> {
+        let mut last_error = AuthError::Missing;
+
+        for authenticator in &self.authenticators {
+            match authenticator.authenticate(token) {
+                Ok(grant) => return Ok(grant),
+                Err(e) => last_error = e,
+            }
+        }
+
+        Err(last_error)
+    }
+}
+
+// Static list of keys, one per line. Each key may be followed by
+// space-separated constraints:
+//
+//   # comment
+//   3f9a1c0e7b5d id=my-files max-file-size=1048576 expires=1893456000
+pub struct ApiKeyAuthenticator {
+    keys: Vec<(String, HosterGrant)>,
+}
+
+impl ApiKeyAuthenticator {
+    pub fn from_file(path: &str) -> io::Result<Self> {
+        let contents = fs::read_to_string(path)?;
+
+        let mut keys = Vec::new();
+
+        for (line_num, line) in contents.lines().enumerate() {
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut parts = line.split_whitespace();
+            let key = parts.next().expect("key");
+
+            let mut grant = HosterGrant::default();
+
+            for constraint in parts {
+                let invalid = || {
+                    io::Error::new(io::ErrorKind::InvalidData,
+                        format!("{}:{}: invalid constraint '{}'", path, line_num + 1, constraint))
+                };
+
+                let kv: Vec<&str> = constraint.splitn(2, '=').collect();
+                if kv.len() != 2 {
+                    return Err(invalid());
+                }
+
+                match kv[0] {
+                    "id" => grant.id = Some(kv[1].to_string()),
+                    "max-file-size" => {
+                        grant.max_file_size = Some(kv[1].parse().map_err(|_| invalid())?);
+                    },
+                    "expires" => {
+                        grant.expires = Some(kv[1].parse().map_err(|_| invalid())?);
+                    },
+                    _ => return Err(invalid()),
+                }
+            }
+
+            keys.push((key.to_string(), grant));
+        }
+
+        Ok(Self {
+            keys,
+        })
+    }
+}
+
+impl HosterAuthenticator for ApiKeyAuthenticator {
+    fn authenticate(&self, token: &str) -> Result<HosterGrant, AuthError> {
+        // Every key is compared, so the time taken doesn't give away how
+        // close a guess was
+        let mut matched = None;
+
+        for (key, grant) in &self.keys {
+            if constant_time_eq(key.as_bytes(), token.as_bytes()) {
+                matched = Some(grant);
+            }
+        }
+
+        match matched {
+            Some(grant) => grant.clone().check_expiry(),
+            None => Err(AuthError::Invalid("unknown api key".to_string())),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct HosterClaims {
+    exp: Option<u64>,
+    hoster_id: Option<String>,
+    max_file_size: Option<u64>,
+}
+
+enum JwtKey {
+    Secret(Vec<u8>),
+    RsaPem(Vec<u8>),
+    // (kid, modulus, exponent), base64url encoded as they appear in the JWKS
+    Jwks(Vec<(Option<String>, String, String)>),
+}
+
+#[derive(Deserialize)]
+struct Jwks {
+    keys: Vec<Jwk>,
+}
+
+#[derive(Deserialize)]
+struct Jwk {
+    kty: String,
+    kid: Option<String>,
+    n: Option<String>,
+    e: Option<String>,
+}
+
+pub struct JwtAuthenticator {
+    key: JwtKey,
+}
+
+impl JwtAuthenticator {
+    // HS256 with a shared secret
+    pub fn from_secret_file(path: &str) -> io::Result<Self> {
+        let secret = fs::read_to_string(path)?.trim().as_bytes().to_vec();
+
+        // Anyone could sign tokens with an empty secret
+        if secret.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                format!("{}: empty JWT secret", path)));
+        }
+
+        Ok(Self {
+            key: JwtKey::Secret(secret),
+        })
+    }
+
+    // RS256 with a PEM encoded public key
+    pub fn from_rsa_pem_file(path: &str) -> io::Result<Self> {
+        let pem = fs::read(path)?;
+
+        DecodingKey::from_rsa_pem(&pem).map_err(|e| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path, e))
+        })?;
+
+        Ok(Self {
+            key: JwtKey::RsaPem(pem),
+        })
+    }
+
+    // RS256 with the RSA keys from a JWKS document
+    pub fn from_jwks_file(path: &str) -> io::Result<Self> {
+        let contents = fs::read_to_string(path)?;
+
+        let jwks: Jwks = serde_json::from_str(&contents).map_err(|e| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path, e))
+        })?;
+
+        let keys = jwks.keys.into_iter()
+            .filter(|jwk| jwk.kty == "RSA")
+            .filter_map(|jwk| match (jwk.n, jwk.e) {
+                (Some(n), Some(e)) => Some((jwk.kid, n, e)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        if keys.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                format!("{}: no RSA keys found", path)));
+        }
+
+        Ok(Self {
+            key: JwtKey::Jwks(keys),
+        })
+    }
+
+    fn decode_claims(&self, token: &str) -> Result<HosterClaims, AuthError> {
+
+        let invalid = |e: jsonwebtoken::errors::Error| AuthError::Invalid(e.to_string());
+
+        // Expiry is optional, so it's checked by HosterGrant instead.
+        let validation = |alg| {
+            let mut validation = Validation::new(alg);
+            validation.validate_exp = false;
+            validation
+        };
+
+        let claims = match &self.key {
+            JwtKey::Secret(secret) => {
+                decode::<HosterClaims>(token, &DecodingKey::from_secret(secret),
+                    &validation(Algorithm::HS256)).map_err(invalid)?
+            },
+            JwtKey::RsaPem(pem) => {
+                let key = DecodingKey::from_rsa_pem(pem).map_err(invalid)?;
+                decode::<HosterClaims>(token, &key, &validation(Algorithm::RS256)).map_err(invalid)?
+            },
+            JwtKey::Jwks(keys) => {
+                let header = decode_header(token).map_err(invalid)?;
+
+                // Without a kid, any of the keys may have signed it
+                let mut result = Err(AuthError::Invalid("no matching key".to_string()));
+
+                for (_, n, e) in keys.iter().filter(|(kid, _, _)| header.kid.is_none() || *kid == header.kid) {
+                    let key = DecodingKey::from_rsa_components(n, e);
+                    result = decode::<HosterClaims>(token, &key, &validation(Algorithm::RS256)).map_err(invalid);
+                    if result.is_ok() {
+                        break;
+                    }
+                }
+
+                result?
+            },
+        };
+
+        Ok(claims.claims)
+    }
+}
+
+impl HosterAuthenticator for JwtAuthenticator {
+    fn authenticate(&self, token: &str) -> Result<HosterGrant, AuthError> {
+        let claims = self.decode_claims(token)?;
+
+        HosterGrant {
+            id: claims.hoster_id,
+            max_file_size: claims.max_file_size,
+            expires: claims.exp,
+        }.check_expiry()
+    }
+}
+
+// Browsers can't set headers on WebSocket connections, so the token may also
+// be passed as a query parameter.
+pub fn request_token(headers: &HeaderMap, params: &HashMap<String, String>) -> Option<String> {
+    let bearer = headers.get("authorization")
+        .and_then(|value| value.to_str().ok())
+        .filter(|value| value.starts_with("Bearer "))
+        .map(|value| value["Bearer ".len()..].trim().to_string());
+
+    bearer.or_else(|| params.get("token").cloned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use serde_json::json;
+
+    fn temp_file(name: &str, contents: &str) -> String {
+        let path = std::env::temp_dir().join(format!("fibridge-auth-{}-{}", process::id(), name));
+        fs::write(&path, contents).expect("write temp file");
+        path.to_string_lossy().to_string()
+    }
+
+    #[test]
+    fn parses_api_keys_with_constraints() {
+        let path = temp_file("keys", "# comment\n\nkey-one\nkey-two id=my-files max-file-size=1024 expires=4102444800\n");
+        let authenticator = ApiKeyAuthenticator::from_file(&path).expect("load keys");
+
+        let grant = authenticator.authenticate("key-one").expect("key-one");
+        assert_eq!(grant.id, None);
+        assert_eq!(grant.max_file_size, None);
+
+        let grant = authenticator.authenticate("key-two").expect("key-two");
+        assert_eq!(grant.id, Some("my-files".to_string()));
+        assert_eq!(grant.max_file_size, Some(1024));
+        assert_eq!(grant.expires, Some(4102444800));
+
+        assert!(matches!(authenticator.authenticate("key-three"), Err(AuthError::Invalid(_))));
+        assert!(matches!(authenticator.authenticate("key-on"), Err(AuthError::Invalid(_))));
+    }
+
+    #[test]
+    fn rejects_expired_api_keys() {
+        let path = temp_file("expired-keys", "old-key expires=1000\n");
+        let authenticator = ApiKeyAuthenticator::from_file(&path).expect("load keys");
+
+        assert!(matches!(authenticator.authenticate("old-key"), Err(AuthError::Expired)));
+    }
+
+    #[test]
+    fn rejects_bad_api_key_constraints() {
+        for line in &["key id", "key max-file-size=big", "key colour=blue"] {
+            let path = temp_file("bad-keys", line);
+            assert!(ApiKeyAuthenticator::from_file(&path).is_err(), "{}", line);
+        }
+    }
+
+    #[test]
+    fn verifies_hs256_tokens() {
+        let path = temp_file("secret", "hunter2\n");
+        let authenticator = JwtAuthenticator::from_secret_file(&path).expect("load secret");
+
+        let claims = json!({ "hoster_id": "my-files", "max_file_size": 1024, "exp": 4102444800u64 });
+        let token = encode(&Header::default(), &claims, &EncodingKey::from_secret(b"hunter2")).expect("encode");
+
+        let grant = authenticator.authenticate(&token).expect("valid token");
+        assert_eq!(grant.id, Some("my-files".to_string()));
+        assert_eq!(grant.max_file_size, Some(1024));
+
+        let forged = encode(&Header::default(), &claims, &EncodingKey::from_secret(b"hunter3")).expect("encode");
+        assert!(matches!(authenticator.authenticate(&forged), Err(AuthError::Invalid(_))));
+    }
+
+    #[test]
+    fn rejects_expired_tokens() {
+        let path = temp_file("secret-expired", "hunter2");
+        let authenticator = JwtAuthenticator::from_secret_file(&path).expect("load secret");
+
+        let claims = json!({ "exp": 1000 });
+        let token = encode(&Header::default(), &claims, &EncodingKey::from_secret(b"hunter2")).expect("encode");
+
+        assert!(matches!(authenticator.authenticate(&token), Err(AuthError::Expired)));
+    }
+
+    #[test]
+    fn rejects_empty_secrets() {
+        for (name, contents) in &[("secret-empty", ""), ("secret-blank", " \n\t\n")] {
+            let path = temp_file(name, contents);
+            assert!(JwtAuthenticator::from_secret_file(&path).is_err());
+        }
+    }
+
+    #[test]
+    fn rejects_jwks_without_rsa_keys() {
+        let path = temp_file("jwks", r#"{"keys": [{"kty": "EC", "kid": "a"}]}"#);
+        assert!(JwtAuthenticator::from_jwks_file(&path).is_err());
+    }
+
+    #[test]
+    fn reads_bearer_tokens_and_query_tokens() {
+        let mut headers = HeaderMap::new();
+        let mut params = HashMap::new();
+        assert_eq!(request_token(&headers, &params), None);
+
+        params.insert("token".to_string(), "from-query".to_string());
+        assert_eq!(request_token(&headers, &params), Some("from-query".to_string()));
+
+        headers.insert("authorization", "Bearer from-header".parse().unwrap());
+        assert_eq!(request_token(&headers, &params), Some("from-header".to_string()));
+    }
+}
@@ -0,0 +1,186 @@
+use std::collections::HashMap;
+use serde::Deserialize;
+use percent_encoding::percent_decode_str;
+
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Credential {
+    pub username: String,
+    pub password: String,
+}
+
+// Credentials a hoster has attached to its files. Rules for a specific path
+// take precedence over the hoster-wide rule, and files without any rule are
+// public.
+#[derive(Default)]
+pub struct CredentialStore {
+    hoster_wide: Vec<Credential>,
+    paths: HashMap<String, Vec<Credential>>,
+}
+
+impl CredentialStore {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    // An empty list of credentials removes the rule.
+    pub fn set(&mut self, path: Option<String>, credentials: Vec<Credential>) {
+        match path {
+            Some(path) => {
+                let path = normalize_path(&path);
+                if credentials.is_empty() {
+                    self.paths.remove(&path);
+                }
+                else {
+                    self.paths.insert(path, credentials);
+                }
+            },
+            None => {
+                self.hoster_wide = credentials;
+            },
+        }
+    }
+
+    pub fn check(&self, path: &str, authorization: Option<&str>) -> bool {
+        let credentials = match self.paths.get(&normalize_path(path)) {
+            Some(credentials) => credentials,
+            None => &self.hoster_wide,
+        };
+
+        if credentials.is_empty() {
+            return true;
+        }
+
+        let (username, password) = match authorization.and_then(parse_authorization) {
+            Some(pair) => pair,
+            None => return false,
+        };
+
+        credentials.iter().any(|credential| {
+            constant_time_eq(credential.username.as_bytes(), username.as_bytes()) &&
+                constant_time_eq(credential.password.as_bytes(), password.as_bytes())
+        })
+    }
+}
+
+fn parse_authorization(header: &str) -> Option<(String, String)> {
+    if !header.starts_with("Basic ") {
+        return None;
+    }
+
+    let decoded = base64::decode(header["Basic ".len()..].trim()).ok()?;
+    let decoded = String::from_utf8(decoded).ok()?;
+
+    let parts: Vec<&str> = decoded.splitn(2, ':').collect();
+    if parts.len() != 2 {
+        return None;
+    }
+
+    Some((parts[0].to_string(), parts[1].to_string()))
+}
+
+// The one way of writing a path that rules are kept under, so that e.g.
+// /secret%2Etxt or /a/../secret.txt can't get around a rule for /secret.txt
+pub fn normalize_path(path: &str) -> String {
+    let decoded = percent_decode_str(path).decode_utf8_lossy();
+
+    let mut segments: Vec<&str> = Vec::new();
+
+    for segment in decoded.split('/') {
+        match segment {
+            "" | "." => (),
+            ".." => {
+                segments.pop();
+            },
+            segment => segments.push(segment),
+        }
+    }
+
+    format!("/{}", segments.join("/"))
+}
+
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter().zip(b.iter()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn basic(username: &str, password: &str) -> String {
+        format!("Basic {}", base64::encode(&format!("{}:{}", username, password)))
+    }
+
+    fn alice() -> Vec<Credential> {
+        vec![Credential { username: "alice".to_string(), password: "hunter2".to_string() }]
+    }
+
+    #[test]
+    fn files_without_rules_are_public() {
+        let store = CredentialStore::new();
+        assert!(store.check("/file.txt", None));
+    }
+
+    #[test]
+    fn path_rules_require_credentials() {
+        let mut store = CredentialStore::new();
+        store.set(Some("/secret.txt".to_string()), alice());
+
+        assert!(!store.check("/secret.txt", None));
+        assert!(!store.check("/secret.txt", Some(&basic("alice", "wrong"))));
+        assert!(!store.check("/secret.txt", Some("Bearer hunter2")));
+        assert!(store.check("/secret.txt", Some(&basic("alice", "hunter2"))));
+        assert!(store.check("/public.txt", None));
+    }
+
+    #[test]
+    fn path_rules_take_precedence() {
+        let mut store = CredentialStore::new();
+        store.set(None, alice());
+        store.set(Some("/other.txt".to_string()), vec![Credential { username: "bob".to_string(), password: "pw".to_string() }]);
+
+        assert!(!store.check("/file.txt", None));
+        assert!(store.check("/file.txt", Some(&basic("alice", "hunter2"))));
+        assert!(!store.check("/other.txt", Some(&basic("alice", "hunter2"))));
+        assert!(store.check("/other.txt", Some(&basic("bob", "pw"))));
+    }
+
+    #[test]
+    fn empty_credentials_remove_the_rule() {
+        let mut store = CredentialStore::new();
+        store.set(Some("/secret.txt".to_string()), alice());
+        store.set(Some("/secret.txt".to_string()), Vec::new());
+
+        assert!(store.check("/secret.txt", None));
+    }
+
+    #[test]
+    fn encoded_and_dotted_paths_cant_bypass_rules() {
+        let mut store = CredentialStore::new();
+        store.set(Some("/secret.txt".to_string()), alice());
+
+        for path in &["/secret%2Etxt", "/%73ecret.txt", "//secret.txt", "/./secret.txt", "/dir/../secret.txt", "/../secret.txt"] {
+            assert!(!store.check(path, None), "{}", path);
+        }
+    }
+
+    #[test]
+    fn rules_are_normalized_too() {
+        let mut store = CredentialStore::new();
+        store.set(Some("/dir/./my%20file.txt".to_string()), alice());
+
+        assert!(!store.check("/dir/my file.txt", None));
+    }
+
+    #[test]
+    fn normalizes_paths() {
+        assert_eq!(normalize_path(""), "/");
+        assert_eq!(normalize_path("file.txt"), "/file.txt");
+        assert_eq!(normalize_path("/a//b/./c/../d%20e"), "/a/b/d e");
+        assert_eq!(normalize_path("/../../etc/passwd"), "/etc/passwd");
+    }
+}
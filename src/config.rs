@@ -0,0 +1,1297 @@
+use std::fmt;
+use std::fs;
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+use std::path::Path;
+use std::net::{IpAddr, SocketAddr};
+use serde::Deserialize;
+use clap::ArgMatches;
+use tracing_subscriber::EnvFilter;
+use crate::forwarded::TrustedProxies;
+use crate::tls::CertFiles;
+use crate::acme;
+use crate::custom_domains;
+use crate::id_generator::IdConflictPolicy;
+use crate::access_log::LogFormat;
+
+
+const DEFAULT_MAX_CACHED_SIZE: usize = 20 * 1024 * 1024;
+
+// Which part of the config each flag overrides
+const FLAG_KEYS: &[(&str, &str)] = &[
+    ("host", "server.host"),
+    ("ip", "server.ip"),
+    ("port", "server.port"),
+    ("secure-port", "server.secure-port"),
+    ("hoster-domain", "server.hoster-domain"),
+    ("base-path", "server.base-path"),
+    ("websocket-path", "server.websocket-path"),
+    ("domain-verification", "domains.verification"),
+    ("allow-domain", "domains.allowlist"),
+    ("listen", "listener"),
+    ("listen-tls", "listener"),
+    ("listen-unix", "listener"),
+    ("key", "tls.key"),
+    ("cert", "tls.cert"),
+    ("sni-cert", "tls.certificate"),
+    ("tls-reload-interval", "tls.reload-interval"),
+    ("acme", "acme.enabled"),
+    ("acme-email", "acme.email"),
+    ("acme-directory-url", "acme.directory-url"),
+    ("acme-state-dir", "acme.state-dir"),
+    ("id-type", "ids.type"),
+    ("id-conflict", "ids.conflict"),
+    ("cache-max-file-size", "cache.max-file-size"),
+    ("approval-timeout", "timeouts.approval"),
+    ("id-request-timeout", "timeouts.id-request-ms"),
+    ("api-keys", "auth.api-keys"),
+    ("jwt-secret", "auth.jwt-secret"),
+    ("jwt-public-key", "auth.jwt-public-key"),
+    ("jwks", "auth.jwks"),
+    ("url-signing-key", "auth.url-signing-key"),
+    ("admin-address", "admin.address"),
+    ("admin-token-file", "admin.token-file"),
+    ("cors-allowed-origin", "cors.allowed-origins"),
+    ("static-dir", "gui.static-dir"),
+    ("index-page", "gui.index-page"),
+    ("static-cache-max-age", "gui.cache-max-age"),
+    ("redirect-port", "redirect.port"),
+    ("redirect-status", "redirect.status"),
+    ("plain-path", "redirect.plain-paths"),
+    ("hsts-max-age", "hsts.max-age"),
+    ("hsts-include-subdomains", "hsts.include-subdomains"),
+    ("hsts-preload", "hsts.preload"),
+    ("trusted-proxy", "proxy.trusted"),
+    ("log-level", "logging.level"),
+    ("access-log", "logging.access-log"),
+    ("access-log-format", "logging.access-log-format"),
+    ("access-log-max-size", "logging.access-log-max-size"),
+    ("access-log-max-files", "logging.access-log-max-files"),
+];
+
+// Everything that can be set in the --config file. Command line flags and
+// their environment variables take precedence over it. Keys are kebab-case
+// and match the flag names, e.g.
+//
+//   [server]
+//   ip = "0.0.0.0"
+//   port = 80
+//
+//   [cache]
+//   max-file-size = 52428800
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    #[serde(rename = "listener")]
+    pub listeners: Vec<ListenerConfig>,
+    pub tls: TlsConfig,
+    pub acme: AcmeConfig,
+    pub ids: IdConfig,
+    pub cache: CacheConfig,
+    pub timeouts: TimeoutConfig,
+    pub auth: AuthConfig,
+    pub admin: AdminConfig,
+    pub cors: CorsConfig,
+    pub redirect: RedirectConfig,
+    pub gui: GuiConfig,
+    pub hsts: HstsConfig,
+    pub domains: DomainsConfig,
+    pub proxy: ProxyConfig,
+    pub logging: LoggingConfig,
+    // The file's text, for pointing errors at the line a key was set on
+    #[serde(skip)]
+    source: String,
+    // Sections and section.keys set by flags, which the file's lines say
+    // nothing about
+    #[serde(skip)]
+    from_args: HashSet<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ServerConfig {
+    pub host: Option<String>,
+    pub ip: String,
+    pub port: u16,
+    pub secure_port: u16,
+    // Serve everything under this path instead of /, e.g. /fibridge
+    pub base_path: String,
+    // Where hosters connect, relative to base-path
+    pub websocket_path: String,
+    // Also serve each hoster at <id>.<hoster-domain>
+    pub hoster_domain: Option<String>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            host: None,
+            ip: "127.0.0.1".to_string(),
+            port: 9001,
+            secure_port: 9002,
+            base_path: "/".to_string(),
+            websocket_path: "/omnistreams".to_string(),
+            hoster_domain: None,
+        }
+    }
+}
+
+// One address to accept connections on, e.g.
+//
+//   [[listener]]
+//   address = "[::]:443"
+//   tls = true
+//
+// tls uses the [tls] key and cert unless the listener has its own. redirect
+// sends everything to the first TLS listener instead of serving it. A Unix
+// domain socket can be used instead of an address, for running behind a
+// reverse proxy on the same machine:
+//
+//   [[listener]]
+//   unix = "/run/fibridge/fibridge.sock"
+//   permissions = "660"
+//
+// proxy-protocol expects every connection to start with a HAProxy PROXY
+// header, as sent by load balancers that pass TCP straight through.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ListenerConfig {
+    pub address: String,
+    pub unix: Option<String>,
+    // Octal, as for chmod
+    pub permissions: Option<String>,
+    pub tls: bool,
+    pub key: Option<String>,
+    pub cert: Option<String>,
+    pub redirect: bool,
+    pub proxy_protocol: bool,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ListenerMode {
+    Plain,
+    Tls {
+        key: String,
+        cert: String,
+    },
+    Redirect,
+}
+
+#[derive(Clone, Debug)]
+pub enum ListenAddress {
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix {
+        path: String,
+        permissions: Option<u32>,
+    },
+}
+
+impl fmt::Display for ListenAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ListenAddress::Tcp(address) => write!(f, "{}", address),
+            #[cfg(unix)]
+            ListenAddress::Unix { path, .. } => write!(f, "unix:{}", path),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Listener {
+    pub address: ListenAddress,
+    pub mode: ListenerMode,
+    pub proxy_protocol: bool,
+}
+
+// key and cert are used for every TLS listener without its own. Additional
+// certificates are picked by the name the client asks for (SNI), e.g.
+//
+//   [[tls.certificate]]
+//   names = ["files.example.com", "*.example.com"]
+//   key = "example-key.pem"
+//   cert = "example-cert.pem"
+//
+// Certificates are reloaded on SIGHUP, and whenever their files change if
+// reload-interval (in seconds) isn't 0.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct TlsConfig {
+    pub key: Option<String>,
+    pub cert: Option<String>,
+    #[serde(rename = "certificate")]
+    pub certificates: Vec<CertificateConfig>,
+    pub reload_interval: u64,
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        Self {
+            key: None,
+            cert: None,
+            certificates: Vec::new(),
+            reload_interval: 60,
+        }
+    }
+}
+
+// Obtains the certificate for [server] host automatically instead of reading
+// it from [tls], answering HTTP-01 challenges on the redirect listener.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct AcmeConfig {
+    pub enabled: bool,
+    pub email: Option<String>,
+    pub directory_url: String,
+    pub state_dir: String,
+    // Renew once the certificate has fewer days left than this
+    pub renew_days: i64,
+}
+
+impl Default for AcmeConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            email: None,
+            directory_url: acme::LETS_ENCRYPT_URL.to_string(),
+            state_dir: "acme".to_string(),
+            renew_days: 30,
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CertificateConfig {
+    pub names: Vec<String>,
+    pub key: String,
+    pub cert: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IdConfig {
+    #[serde(rename = "type")]
+    pub id_type: String,
+    pub conflict: String,
+}
+
+impl Default for IdConfig {
+    fn default() -> Self {
+        Self {
+            id_type: "short-code".to_string(),
+            conflict: "fallback".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct CacheConfig {
+    // Files up to this many bytes are kept in memory once downloaded
+    pub max_file_size: usize,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            max_file_size: DEFAULT_MAX_CACHED_SIZE,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct TimeoutConfig {
+    // Seconds
+    pub approval: u64,
+    // Milliseconds a hoster has to send claimId, if it didn't ask for an id
+    // in the handshake. 0 doesn't wait.
+    pub id_request_ms: u64,
+}
+
+impl Default for TimeoutConfig {
+    fn default() -> Self {
+        Self {
+            approval: 60,
+            id_request_ms: 200,
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct AuthConfig {
+    pub api_keys: Option<String>,
+    pub jwt_secret: Option<String>,
+    pub jwt_public_key: Option<String>,
+    pub jwks: Option<String>,
+    pub url_signing_key: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct AdminConfig {
+    pub address: Option<String>,
+    pub token_file: Option<String>,
+}
+
+// How the redirect listener sends plain HTTP requests to HTTPS. port is
+// omit-default, which leaves :443 out of the URL, or keep, which always
+// includes the secure port. Requests under plain-paths are served over plain
+// HTTP instead of being redirected.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct RedirectConfig {
+    pub port: String,
+    pub status: u16,
+    pub plain_paths: Vec<String>,
+}
+
+impl Default for RedirectConfig {
+    fn default() -> Self {
+        Self {
+            port: "omit-default".to_string(),
+            status: 301,
+            plain_paths: Vec::new(),
+        }
+    }
+}
+
+// What's served at /. static-dir serves a whole asset tree, index-page just a
+// landing page. cache-max-age is how long browsers may keep assets other than
+// HTML.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct GuiConfig {
+    pub static_dir: Option<String>,
+    pub index_page: Option<String>,
+    pub cache_max_age: u64,
+}
+
+impl Default for GuiConfig {
+    fn default() -> Self {
+        Self {
+            static_dir: None,
+            index_page: None,
+            cache_max_age: 3600,
+        }
+    }
+}
+
+// Strict-Transport-Security on HTTPS responses, off unless max-age is set
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct HstsConfig {
+    pub max_age: Option<u64>,
+    pub include_subdomains: bool,
+    pub preload: bool,
+}
+
+impl HstsConfig {
+    pub fn header_value(&self) -> Option<String> {
+        self.max_age.map(|max_age| {
+            let mut value = format!("max-age={}", max_age);
+            if self.include_subdomains {
+                value.push_str("; includeSubDomains");
+            }
+            if self.preload {
+                value.push_str("; preload");
+            }
+            value
+        })
+    }
+}
+
+// Custom domains hosters can claim with claimDomain. verification is dns,
+// which wants a TXT record with the hash of the hoster's secret, or allowlist,
+// which only allows the domain to hoster id pairs in [domains.allowlist], for
+// hosters whose credentials are bound to the id. Unset turns custom domains
+// off.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct DomainsConfig {
+    pub verification: Option<String>,
+    pub allowlist: HashMap<String, String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct CorsConfig {
+    // Origins allowed to fetch downloads from scripts. Empty disables CORS.
+    pub allowed_origins: Vec<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProxyConfig {
+    // Load balancers and reverse proxies whose X-Forwarded-* and Forwarded
+    // headers are believed, as addresses or CIDRs
+    pub trusted: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct LoggingConfig {
+    pub level: Option<String>,
+    pub access_log: String,
+    pub access_log_format: String,
+    // Megabytes
+    pub access_log_max_size: u64,
+    pub access_log_max_files: usize,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            level: None,
+            access_log: "stdout".to_string(),
+            access_log_format: "combined".to_string(),
+            access_log_max_size: 100,
+            access_log_max_files: 5,
+        }
+    }
+}
+
+impl LoggingConfig {
+    // The tracing filter to log with. --log-level and $FIBRIDGE_LOG have
+    // already been applied to level, so $RUST_LOG only counts when neither
+    // they nor the config file set one.
+    pub fn filter(&self, rust_log: Option<String>) -> String {
+        self.level.clone()
+            .or(rust_log)
+            .unwrap_or_else(|| "info".to_string())
+    }
+}
+
+#[derive(Debug)]
+pub struct ConfigError {
+    line: Option<usize>,
+    message: String,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "line {}: {}", line, self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+impl Config {
+    // Parses a config file. validate() should be called once flags have
+    // been applied.
+    pub fn from_file(path: &str) -> Result<Self, Vec<ConfigError>> {
+        let source = fs::read_to_string(path).map_err(|e| {
+            vec![ConfigError { line: None, message: format!("Failed to read {}: {}", path, e) }]
+        })?;
+
+        Self::parse(&source)
+    }
+
+    fn parse(source: &str) -> Result<Self, Vec<ConfigError>> {
+        let mut config: Config = toml::from_str(source).map_err(|e| {
+            vec![ConfigError {
+                line: e.line_col().map(|(line, _)| line + 1),
+                message: e.to_string(),
+            }]
+        })?;
+
+        config.source = source.to_string();
+
+        Ok(config)
+    }
+
+    // Checks the file and flags together, reporting every problem found
+    // rather than stopping at the first.
+    pub fn validate(&self) -> Result<(), Vec<ConfigError>> {
+        let mut listener_errors = Vec::new();
+
+        for (i, listener) in self.listeners.iter().enumerate() {
+            let mut error = |key: &str, message: String| {
+                listener_errors.push(ConfigError { line: self.line_of_nth("listener", key, i), message });
+            };
+
+            match &listener.unix {
+                Some(_) => {
+                    if cfg!(not(unix)) {
+                        error("unix", "Unix sockets are only supported on Unix-like systems".to_string());
+                    }
+                    if !listener.address.is_empty() {
+                        error("unix", "A listener can't have both an address and a unix socket".to_string());
+                    }
+                    if listener.tls || listener.key.is_some() || listener.cert.is_some() {
+                        error("unix", "TLS isn't supported on unix sockets".to_string());
+                    }
+                },
+                None => {
+                    if listener.address.parse::<SocketAddr>().is_err() {
+                        error("address", format!("Invalid address '{}', e.g. 0.0.0.0:80 or [::]:80", listener.address));
+                    }
+                    if listener.permissions.is_some() {
+                        error("permissions", "permissions only apply to unix sockets".to_string());
+                    }
+                },
+            }
+
+            if let Some(permissions) = &listener.permissions {
+                match u32::from_str_radix(permissions, 8) {
+                    Ok(mode) if mode <= 0o777 => (),
+                    _ => error("permissions", format!("Invalid permissions '{}', e.g. 660", permissions)),
+                }
+            }
+
+            match (&listener.key, &listener.cert) {
+                (Some(_), None) => error("key", "key requires cert".to_string()),
+                (None, Some(_)) => error("cert", "cert requires key".to_string()),
+                _ => (),
+            }
+
+            let has_own_cert = listener.key.is_some() && listener.cert.is_some();
+
+            if listener.tls && !has_own_cert && (self.tls.key.is_none() || self.tls.cert.is_none()) && !self.acme.enabled {
+                error("tls", "tls requires a key and cert, either on the listener or in [tls], or [acme] enabled".to_string());
+            }
+
+            if listener.redirect && (listener.tls || has_own_cert) {
+                error("redirect", "A listener can't both redirect and serve TLS".to_string());
+            }
+
+            if listener.proxy_protocol && (listener.tls || has_own_cert || listener.unix.is_some()) {
+                error("proxy-protocol", "proxy-protocol is only supported on plain TCP listeners".to_string());
+            }
+        }
+
+        for (i, certificate) in self.tls.certificates.iter().enumerate() {
+            let mut error = |key: &str, message: String| {
+                listener_errors.push(ConfigError { line: self.line_of_nth("tls.certificate", key, i), message });
+            };
+
+            if certificate.names.is_empty() {
+                error("names", "A certificate needs at least one name".to_string());
+            }
+
+            for name in &certificate.names {
+                // Only a single leading wildcard label is allowed
+                let domain = if name.starts_with("*.") { &name[2..] } else { name.as_str() };
+
+                if domain.is_empty() || domain.contains('*') {
+                    error("names", format!("Invalid name '{}', e.g. example.com or *.example.com", name));
+                }
+            }
+
+            for (key, path) in [("key", &certificate.key), ("cert", &certificate.cert)].iter() {
+                if !Path::new(path).is_file() {
+                    error(key, format!("No such file '{}'", path));
+                }
+            }
+        }
+
+        let mut errors = listener_errors;
+
+        let mut error = |section: &str, key: &str, message: String| {
+            errors.push(ConfigError { line: self.line_of_nth(section, key, 0), message });
+        };
+
+        if self.server.ip.parse::<IpAddr>().is_err() {
+            error("server", "ip", format!("Invalid ip '{}'", self.server.ip));
+        }
+
+        if let Some(domain) = &self.server.hoster_domain {
+            let domain = domain.trim_start_matches("*.");
+            if domain.is_empty() || domain.contains(|c: char| !(c.is_ascii_alphanumeric() || c == '-' || c == '.')) {
+                error("server", "hoster-domain", format!("Invalid domain '{}', e.g. fbrg.xyz", domain));
+            }
+        }
+
+        if !is_valid_path(&self.server.base_path) {
+            error("server", "base-path", format!("Invalid path '{}', e.g. /fibridge", self.server.base_path));
+        }
+
+        if !is_valid_path(&self.server.websocket_path) || self.server.websocket_path.trim_matches('/').is_empty() {
+            error("server", "websocket-path", format!("Invalid path '{}', e.g. /omnistreams", self.server.websocket_path));
+        }
+
+        match (&self.tls.key, &self.tls.cert) {
+            (Some(_), None) => error("tls", "key", "key requires cert".to_string()),
+            (None, Some(_)) => error("tls", "cert", "cert requires key".to_string()),
+            _ => (),
+        }
+
+        if self.acme.enabled {
+            if self.server.host.is_none() {
+                error("acme", "enabled", "acme requires [server] host".to_string());
+            }
+            if self.acme.email.is_none() {
+                error("acme", "enabled", "acme requires an email".to_string());
+            }
+            if self.tls.key.is_some() || self.tls.cert.is_some() {
+                error("acme", "enabled", "acme can't be used with a [tls] key and cert".to_string());
+            }
+            // HTTP-01 challenges are only answered on plain HTTP redirect
+            // listeners. Without [[listener]]s there's always one.
+            if !self.listeners.is_empty() && !self.listeners.iter().any(|listener| listener.redirect) {
+                error("acme", "enabled", "acme requires a redirect listener to answer challenges on".to_string());
+            }
+            if !self.acme.directory_url.starts_with("https://") {
+                error("acme", "directory-url", format!("Invalid directory url '{}'", self.acme.directory_url));
+            }
+            // Let's Encrypt certificates last 90 days, and asking for a new
+            // one at every check would soon hit its rate limits
+            if self.acme.renew_days < 1 || self.acme.renew_days > 60 {
+                error("acme", "renew-days", format!("renew-days must be between 1 and 60, not {}", self.acme.renew_days));
+            }
+        }
+
+        let files = [
+            ("tls", "key", &self.tls.key),
+            ("tls", "cert", &self.tls.cert),
+            ("auth", "api-keys", &self.auth.api_keys),
+            ("auth", "jwt-secret", &self.auth.jwt_secret),
+            ("auth", "jwt-public-key", &self.auth.jwt_public_key),
+            ("auth", "jwks", &self.auth.jwks),
+            ("auth", "url-signing-key", &self.auth.url_signing_key),
+            ("admin", "token-file", &self.admin.token_file),
+            ("gui", "index-page", &self.gui.index_page),
+        ];
+
+        for (section, key, path) in files.iter() {
+            if let Some(path) = path {
+                if !Path::new(path).is_file() {
+                    error(section, key, format!("No such file '{}'", path));
+                }
+            }
+        }
+
+        if let Some(static_dir) = &self.gui.static_dir {
+            if !Path::new(static_dir).is_dir() {
+                error("gui", "static-dir", format!("No such directory '{}'", static_dir));
+            }
+        }
+
+        if self.ids.id_type != "short-code" && self.ids.id_type != "uuid" {
+            error("ids", "type", format!("Unknown id type '{}', expected short-code or uuid", self.ids.id_type));
+        }
+
+        if let Err(e) = self.ids.conflict.parse::<IdConflictPolicy>() {
+            error("ids", "conflict", e);
+        }
+
+        if self.timeouts.approval == 0 {
+            error("timeouts", "approval", "approval must be at least 1 second".to_string());
+        }
+
+        if let Some(address) = &self.admin.address {
+            if address.parse::<SocketAddr>().is_err() {
+                error("admin", "address", format!("Invalid address '{}'", address));
+            }
+            if self.admin.token_file.is_none() {
+                error("admin", "address", "address requires token-file".to_string());
+            }
+        }
+
+        for origin in &self.cors.allowed_origins {
+            if !origin.starts_with("http://") && !origin.starts_with("https://") {
+                error("cors", "allowed-origins", format!("Invalid origin '{}'", origin));
+            }
+        }
+
+        if self.redirect.port != "omit-default" && self.redirect.port != "keep" {
+            error("redirect", "port", format!("Unknown port handling '{}', expected omit-default or keep", self.redirect.port));
+        }
+
+        if self.redirect.status != 301 && self.redirect.status != 308 {
+            error("redirect", "status", format!("Unsupported status {}, expected 301 or 308", self.redirect.status));
+        }
+
+        for path in &self.redirect.plain_paths {
+            if !path.starts_with('/') {
+                error("redirect", "plain-paths", format!("Invalid path '{}', paths start with /", path));
+            }
+        }
+
+        if self.hsts.max_age.is_none() && (self.hsts.include_subdomains || self.hsts.preload) {
+            error("hsts", "max-age", "include-subdomains and preload require max-age".to_string());
+        }
+
+        match self.domains.verification.as_ref().map(|verification| verification.as_str()) {
+            None | Some("dns") => {
+                if !self.domains.allowlist.is_empty() {
+                    error("domains", "verification", "allowlist requires verification = \"allowlist\"".to_string());
+                }
+            },
+            Some("allowlist") => (),
+            Some(verification) => {
+                error("domains", "verification", format!("Unknown verification '{}', expected dns or allowlist", verification));
+            },
+        }
+
+        for domain in self.domains.allowlist.keys() {
+            if !custom_domains::is_valid_domain(&custom_domains::normalize(domain)) {
+                error("domains.allowlist", domain, format!("Invalid domain '{}', e.g. files.example.com", domain));
+            }
+        }
+
+        if let Err(e) = TrustedProxies::parse(&self.proxy.trusted) {
+            error("proxy", "trusted", e);
+        }
+
+        if let Some(level) = &self.logging.level {
+            if let Err(e) = EnvFilter::try_new(level) {
+                error("logging", "level", format!("Invalid level '{}': {}", level, e));
+            }
+        }
+
+        if let Err(e) = self.logging.access_log_format.parse::<LogFormat>() {
+            error("logging", "access-log-format", e);
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        }
+        else {
+            Err(errors)
+        }
+    }
+
+    // Where a key was set in the file, unless a flag has replaced it
+    fn line_of_nth(&self, section: &str, key: &str, index: usize) -> Option<usize> {
+        if self.from_args.contains(section) || self.from_args.contains(&format!("{}.{}", section, key)) {
+            None
+        }
+        else {
+            line_of_nth(&self.source, section, key, index)
+        }
+    }
+
+    // Without any listeners configured, serve on ip:port, or with TLS on
+    // ip:secure-port and redirect ip:port to it.
+    pub fn listeners(&self) -> Vec<Listener> {
+        let tls = match (&self.tls.key, &self.tls.cert) {
+            (Some(key), Some(cert)) => Some(ListenerMode::Tls { key: key.clone(), cert: cert.clone() }),
+            _ => self.acme_cert_files().map(|files| ListenerMode::Tls { key: files.key, cert: files.cert }),
+        };
+
+        if self.listeners.is_empty() {
+            let ip: IpAddr = self.server.ip.parse().expect("parse ip");
+
+            return match tls {
+                Some(tls) => vec![
+                    Listener { address: ListenAddress::Tcp(SocketAddr::new(ip, self.server.port)), mode: ListenerMode::Redirect, proxy_protocol: false },
+                    Listener { address: ListenAddress::Tcp(SocketAddr::new(ip, self.server.secure_port)), mode: tls, proxy_protocol: false },
+                ],
+                None => vec![
+                    Listener { address: ListenAddress::Tcp(SocketAddr::new(ip, self.server.port)), mode: ListenerMode::Plain, proxy_protocol: false },
+                ],
+            };
+        }
+
+        self.listeners.iter().map(|listener| {
+            let mode = match (&listener.key, &listener.cert) {
+                (Some(key), Some(cert)) => ListenerMode::Tls { key: key.clone(), cert: cert.clone() },
+                _ if listener.tls => tls.clone().expect("listener tls key and cert"),
+                _ if listener.redirect => ListenerMode::Redirect,
+                _ => ListenerMode::Plain,
+            };
+
+            let address = match &listener.unix {
+                #[cfg(unix)]
+                Some(path) => {
+                    ListenAddress::Unix {
+                        path: path.clone(),
+                        permissions: listener.permissions.as_ref().map(|permissions| {
+                            u32::from_str_radix(permissions, 8).expect("parse unix socket permissions")
+                        }),
+                    }
+                },
+                // Turned away by validate
+                #[cfg(not(unix))]
+                Some(_) => panic!("unix sockets aren't supported on this platform"),
+                None => ListenAddress::Tcp(listener.address.parse().expect("parse listen address")),
+            };
+
+            Listener {
+                address,
+                mode,
+                proxy_protocol: listener.proxy_protocol,
+            }
+        })
+        .collect()
+    }
+
+    // Where the ACME certificate is kept, if ACME is enabled
+    pub fn acme_cert_files(&self) -> Option<CertFiles> {
+        match (self.acme.enabled, &self.server.host) {
+            (true, Some(host)) => Some(acme::cert_files(&self.acme.state_dir, host)),
+            _ => None,
+        }
+    }
+
+    // Flags, or the environment variables behind them, win over the file
+    pub fn apply_args(&mut self, matches: &ArgMatches) -> Result<(), Vec<ConfigError>> {
+        let string = |name| matches.value_of(name).map(|value| value.to_string());
+        let mut errors = Vec::new();
+
+        for (flag, key) in FLAG_KEYS.iter() {
+            if matches.is_present(flag) {
+                self.from_args.insert(key.to_string());
+            }
+        }
+
+        if let Some(host) = string("host") {
+            self.server.host = Some(host);
+        }
+        if let Some(ip) = string("ip") {
+            self.server.ip = ip;
+        }
+        if let Some(domain) = string("hoster-domain") {
+            self.server.hoster_domain = Some(domain);
+        }
+        if let Some(verification) = string("domain-verification") {
+            self.domains.verification = Some(verification);
+        }
+        if let Some(allowed) = matches.values_of("allow-domain") {
+            self.domains.allowlist = allowed.filter_map(|allowed| {
+                // DOMAIN=ID
+                let mut parts = allowed.splitn(2, '=');
+                match (parts.next(), parts.next()) {
+                    (Some(domain), Some(id)) => Some((domain.to_string(), id.to_string())),
+                    _ => {
+                        errors.push(ConfigError { line: None, message: format!("Invalid --allow-domain '{}', expected DOMAIN=ID", allowed) });
+                        None
+                    },
+                }
+            })
+            .collect();
+        }
+        if let Some(base_path) = string("base-path") {
+            self.server.base_path = base_path;
+        }
+        if let Some(websocket_path) = string("websocket-path") {
+            self.server.websocket_path = websocket_path;
+        }
+        let proxy_protocol = matches.is_present("proxy-protocol");
+        let plain = matches.values_of("listen").into_iter().flatten()
+            .map(|address| (address, false));
+        let tls = matches.values_of("listen-tls").into_iter().flatten()
+            .map(|address| (address, true));
+        let unix = matches.values_of("listen-unix").into_iter().flatten()
+            .map(|path| {
+                ListenerConfig {
+                    unix: Some(path.to_string()),
+                    permissions: matches.value_of("unix-socket-permissions").map(|permissions| permissions.to_string()),
+                    ..Default::default()
+                }
+            });
+        let listeners: Vec<ListenerConfig> = plain.chain(tls)
+            .map(|(address, tls)| {
+                ListenerConfig {
+                    address: address.to_string(),
+                    tls,
+                    proxy_protocol: proxy_protocol && !tls,
+                    ..Default::default()
+                }
+            })
+            .chain(unix)
+            .collect();
+
+        if !listeners.is_empty() {
+            self.listeners = listeners;
+        }
+
+        if let Some(port) = parse_arg(matches, "port", &mut errors) {
+            self.server.port = port;
+        }
+        if let Some(secure_port) = parse_arg(matches, "secure-port", &mut errors) {
+            self.server.secure_port = secure_port;
+        }
+
+        if let Some(key) = string("key") {
+            self.tls.key = Some(key);
+        }
+        if let Some(cert) = string("cert") {
+            self.tls.cert = Some(cert);
+        }
+        if let Some(certificates) = matches.values_of("sni-cert") {
+            self.tls.certificates = certificates.filter_map(|certificate| {
+                // NAMES:CERT:KEY, with the names comma separated
+                let mut parts = certificate.splitn(3, ':');
+                match (parts.next(), parts.next(), parts.next()) {
+                    (Some(names), Some(cert), Some(key)) => {
+                        Some(CertificateConfig {
+                            names: names.split(',').map(|name| name.to_string()).collect(),
+                            key: key.to_string(),
+                            cert: cert.to_string(),
+                        })
+                    },
+                    _ => {
+                        errors.push(ConfigError { line: None, message: format!("Invalid --sni-cert '{}', expected NAMES:CERT:KEY", certificate) });
+                        None
+                    },
+                }
+            })
+            .collect();
+        }
+        if let Some(reload_interval) = parse_arg(matches, "tls-reload-interval", &mut errors) {
+            self.tls.reload_interval = reload_interval;
+        }
+
+        if matches.is_present("acme") {
+            self.acme.enabled = true;
+        }
+        if let Some(email) = string("acme-email") {
+            self.acme.email = Some(email);
+        }
+        if let Some(directory_url) = string("acme-directory-url") {
+            self.acme.directory_url = directory_url;
+        }
+        if let Some(state_dir) = string("acme-state-dir") {
+            self.acme.state_dir = state_dir;
+        }
+
+        if let Some(id_type) = string("id-type") {
+            self.ids.id_type = id_type;
+        }
+        if let Some(conflict) = string("id-conflict") {
+            self.ids.conflict = conflict;
+        }
+
+        if let Some(max_file_size) = parse_arg(matches, "cache-max-file-size", &mut errors) {
+            self.cache.max_file_size = max_file_size;
+        }
+
+        if let Some(approval) = parse_arg(matches, "approval-timeout", &mut errors) {
+            self.timeouts.approval = approval;
+        }
+        if let Some(id_request_ms) = parse_arg(matches, "id-request-timeout", &mut errors) {
+            self.timeouts.id_request_ms = id_request_ms;
+        }
+
+        if let Some(api_keys) = string("api-keys") {
+            self.auth.api_keys = Some(api_keys);
+        }
+        if let Some(jwt_secret) = string("jwt-secret") {
+            self.auth.jwt_secret = Some(jwt_secret);
+        }
+        if let Some(jwt_public_key) = string("jwt-public-key") {
+            self.auth.jwt_public_key = Some(jwt_public_key);
+        }
+        if let Some(jwks) = string("jwks") {
+            self.auth.jwks = Some(jwks);
+        }
+        if let Some(url_signing_key) = string("url-signing-key") {
+            self.auth.url_signing_key = Some(url_signing_key);
+        }
+
+        if let Some(address) = string("admin-address") {
+            self.admin.address = Some(address);
+        }
+        if let Some(token_file) = string("admin-token-file") {
+            self.admin.token_file = Some(token_file);
+        }
+
+        if let Some(origins) = matches.values_of("cors-allowed-origin") {
+            self.cors.allowed_origins = origins.map(|origin| origin.to_string()).collect();
+        }
+
+        if let Some(static_dir) = string("static-dir") {
+            self.gui.static_dir = Some(static_dir);
+        }
+        if let Some(index_page) = string("index-page") {
+            self.gui.index_page = Some(index_page);
+        }
+        if let Some(cache_max_age) = parse_arg(matches, "static-cache-max-age", &mut errors) {
+            self.gui.cache_max_age = cache_max_age;
+        }
+
+        if let Some(port) = string("redirect-port") {
+            self.redirect.port = port;
+        }
+        if let Some(status) = parse_arg(matches, "redirect-status", &mut errors) {
+            self.redirect.status = status;
+        }
+        if let Some(paths) = matches.values_of("plain-path") {
+            self.redirect.plain_paths = paths.map(|path| path.to_string()).collect();
+        }
+
+        if let Some(max_age) = parse_arg(matches, "hsts-max-age", &mut errors) {
+            self.hsts.max_age = Some(max_age);
+        }
+        if matches.is_present("hsts-include-subdomains") {
+            self.hsts.include_subdomains = true;
+        }
+        if matches.is_present("hsts-preload") {
+            self.hsts.preload = true;
+        }
+
+        if let Some(trusted) = matches.values_of("trusted-proxy") {
+            self.proxy.trusted = trusted.map(|proxy| proxy.to_string()).collect();
+        }
+
+        if let Some(level) = string("log-level") {
+            self.logging.level = Some(level);
+        }
+        if let Some(access_log) = string("access-log") {
+            self.logging.access_log = access_log;
+        }
+        if let Some(format) = string("access-log-format") {
+            self.logging.access_log_format = format;
+        }
+        if let Some(max_size) = parse_arg(matches, "access-log-max-size", &mut errors) {
+            self.logging.access_log_max_size = max_size;
+        }
+        if let Some(max_files) = parse_arg(matches, "access-log-max-files", &mut errors) {
+            self.logging.access_log_max_files = max_files;
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        }
+        else {
+            Err(errors)
+        }
+    }
+}
+
+fn parse_arg<T>(matches: &ArgMatches, name: &str, errors: &mut Vec<ConfigError>) -> Option<T>
+    where T: FromStr,
+          T::Err: fmt::Display,
+{
+    let value = matches.value_of(name)?;
+
+    match value.parse() {
+        Ok(value) => Some(value),
+        Err(e) => {
+            errors.push(ConfigError { line: None, message: format!("Invalid --{} '{}': {}", name, value, e) });
+            None
+        },
+    }
+}
+
+// Paths are sent back to browsers and matched segment by segment, so anything
+// that would need escaping is out.
+fn is_valid_path(path: &str) -> bool {
+    path.starts_with('/') && path.split('/').all(|segment| {
+        segment != "." && segment != ".." &&
+            segment.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+    })
+}
+
+// Finds the line a key was set on within the index'th [section] or
+// [[section]], for error messages. The toml crate doesn't keep positions once
+// a value has been deserialized.
+fn line_of_nth(source: &str, section: &str, key: &str, index: usize) -> Option<usize> {
+    let mut current_section = "";
+    let mut seen = 0;
+
+    for (i, line) in source.lines().enumerate() {
+        let line = line.trim();
+
+        if line.starts_with('[') {
+            current_section = line.trim_matches(|c| c == '[' || c == ']').trim();
+            if current_section == section {
+                seen += 1;
+            }
+        }
+        else if current_section == section && seen == index + 1 {
+            let name = line.split('=').next().unwrap_or("").trim().trim_matches('"');
+            if name == key {
+                return Some(i + 1);
+            }
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use clap::{App, Arg};
+
+    fn matches(args: &[&str]) -> ArgMatches<'static> {
+        App::new("test")
+            .arg(Arg::with_name("ip").long("ip-address").takes_value(true))
+            .arg(Arg::with_name("port").long("port").takes_value(true))
+            .arg(Arg::with_name("websocket-path").long("websocket-path").takes_value(true))
+            .arg(Arg::with_name("listen").long("listen").takes_value(true).multiple(true))
+            .arg(Arg::with_name("listen-tls").long("listen-tls").takes_value(true).multiple(true))
+            .arg(Arg::with_name("allow-domain").long("allow-domain").takes_value(true).multiple(true))
+            .arg(Arg::with_name("sni-cert").long("sni-cert").takes_value(true).multiple(true))
+            .arg(Arg::with_name("id-request-timeout").long("id-request-timeout").takes_value(true))
+            .arg(Arg::with_name("log-level").long("log-level").env("FIBRIDGE_TEST_LOG").takes_value(true))
+            .get_matches_from(std::iter::once("test").chain(args.iter().cloned()))
+    }
+
+    fn lines(errors: &[ConfigError]) -> Vec<Option<usize>> {
+        errors.iter().map(|e| e.line).collect()
+    }
+
+    #[test]
+    fn default_is_valid() {
+        assert!(Config::default().validate().is_ok());
+    }
+
+    #[test]
+    fn reports_every_error_with_its_line() {
+        let config = Config::parse("[server]\nip = \"nope\"\n\n[ids]\ntype = \"words\"\nconflict = \"maybe\"\n").unwrap();
+        let errors = config.validate().unwrap_err();
+        assert_eq!(lines(&errors), vec![Some(2), Some(5), Some(6)]);
+    }
+
+    #[test]
+    fn listener_errors_point_at_their_table() {
+        let source = "[[listener]]\naddress = \"0.0.0.0:80\"\n\n[[listener]]\naddress = \"bogus\"\n";
+        let errors = Config::parse(source).unwrap().validate().unwrap_err();
+        assert_eq!(lines(&errors), vec![Some(5)]);
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected() {
+        let errors = Config::parse("[server]\nprot = 80\n").unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].message.contains("prot"));
+    }
+
+    #[test]
+    fn flags_are_validated() {
+        let mut config = Config::default();
+        config.apply_args(&matches(&["--ip-address", "nope", "--websocket-path", "/a b"])).unwrap();
+        let errors = config.validate().unwrap_err();
+        assert_eq!(errors.len(), 2);
+    }
+
+    #[test]
+    fn flag_errors_have_no_line() {
+        let mut config = Config::parse("[server]\nip = \"127.0.0.1\"\n").unwrap();
+        config.apply_args(&matches(&["--ip-address", "nope"])).unwrap();
+        assert_eq!(lines(&config.validate().unwrap_err()), vec![None]);
+    }
+
+    #[test]
+    fn flags_override_the_file() {
+        let mut config = Config::parse("[server]\nip = \"nope\"\nport = 80\n").unwrap();
+        config.apply_args(&matches(&["--ip-address", "0.0.0.0"])).unwrap();
+        assert!(config.validate().is_ok());
+        assert_eq!(config.server.ip, "0.0.0.0");
+        assert_eq!(config.server.port, 80);
+    }
+
+    #[test]
+    fn listen_flags_are_validated() {
+        let mut config = Config::default();
+        config.apply_args(&matches(&["--listen", "bogus", "--listen-tls", "0.0.0.0:443"])).unwrap();
+        let errors = config.validate().unwrap_err();
+        assert_eq!(lines(&errors), vec![None, None]);
+        assert!(errors[0].message.contains("bogus"));
+        assert!(errors[1].message.contains("tls requires a key and cert"));
+    }
+
+    #[test]
+    fn listen_flags_replace_file_listeners() {
+        let mut config = Config::parse("[[listener]]\naddress = \"bogus\"\n").unwrap();
+        config.apply_args(&matches(&["--listen", "127.0.0.1:8080"])).unwrap();
+        assert!(config.validate().is_ok());
+        assert_eq!(config.listeners().len(), 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unix_socket_permissions_are_octal_modes() {
+        for (permissions, valid) in &[("660", true), ("0600", true), ("777", true), ("1777", false), ("rw", false), ("-1", false)] {
+            let source = format!("[[listener]]\nunix = \"/tmp/fibridge.sock\"\npermissions = \"{}\"\n", permissions);
+            let config = Config::parse(&source).unwrap();
+            assert_eq!(config.validate().is_ok(), *valid, "{}", permissions);
+        }
+    }
+
+    #[cfg(not(unix))]
+    #[test]
+    fn unix_sockets_need_unix() {
+        let config = Config::parse("[[listener]]\nunix = \"fibridge.sock\"\n").unwrap();
+        assert_eq!(lines(&config.validate().unwrap_err()), vec![Some(2)]);
+    }
+
+    #[test]
+    fn sni_certs() {
+        let mut config = Config::default();
+        let errors = config.apply_args(&matches(&["--sni-cert", "example.com", "--sni-cert", "example.com:cert.pem"])).unwrap_err();
+        assert_eq!(errors.len(), 2);
+
+        let mut config = Config::default();
+        config.apply_args(&matches(&["--sni-cert", "*.*.example.com,:missing-cert.pem:missing-key.pem"])).unwrap();
+        // Both names, and both files
+        assert_eq!(config.validate().unwrap_err().len(), 4);
+    }
+
+    #[test]
+    fn acme_renew_days_are_range_checked() {
+        for (renew_days, valid) in &[(1, true), (30, true), (60, true), (0, false), (-5, false), (90, false)] {
+            let source = format!("[server]\nhost = \"example.com\"\n\n[acme]\nenabled = true\nemail = \"admin@example.com\"\nrenew-days = {}\n", renew_days);
+            let errors = Config::parse(&source).unwrap().validate().err().unwrap_or_default();
+            assert_eq!(errors.is_empty(), *valid, "{}", renew_days);
+            if !valid {
+                assert_eq!(lines(&errors), vec![Some(7)]);
+            }
+        }
+    }
+
+    #[test]
+    fn acme_requires_a_redirect_listener() {
+        let acme = "[server]\nhost = \"example.com\"\n\n[acme]\nenabled = true\nemail = \"admin@example.com\"\n";
+
+        let source = format!("{}\n[[listener]]\naddress = \"0.0.0.0:443\"\ntls = true\n", acme);
+        let errors = Config::parse(&source).unwrap().validate().unwrap_err();
+        assert_eq!(lines(&errors), vec![Some(5)]);
+
+        let source = format!("{}\n[[listener]]\naddress = \"0.0.0.0:443\"\ntls = true\n\n[[listener]]\naddress = \"0.0.0.0:80\"\nredirect = true\n", acme);
+        assert!(Config::parse(&source).unwrap().validate().is_ok());
+
+        assert!(Config::parse(acme).unwrap().validate().is_ok());
+    }
+
+    #[test]
+    fn id_request_timeout() {
+        assert_eq!(Config::default().timeouts.id_request_ms, 200);
+
+        let mut config = Config::parse("[timeouts]\nid-request-ms = 50\n").unwrap();
+        assert_eq!(config.timeouts.id_request_ms, 50);
+
+        config.apply_args(&matches(&["--id-request-timeout", "0"])).unwrap();
+        assert_eq!(config.timeouts.id_request_ms, 0);
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn log_level_precedence() {
+        let rust_log = || Some("warn".to_string());
+
+        let mut config = Config::default();
+        assert_eq!(config.logging.filter(None), "info");
+        assert_eq!(config.logging.filter(rust_log()), "warn");
+
+        let mut config_file = Config::parse("[logging]\nlevel = \"debug\"\n").unwrap();
+        assert_eq!(config_file.logging.filter(rust_log()), "debug");
+
+        config_file.apply_args(&matches(&["--log-level", "fibridge_proxy_rs=trace"])).unwrap();
+        assert_eq!(config_file.logging.filter(rust_log()), "fibridge_proxy_rs=trace");
+
+        // Only this test sets the variable, standing in for $FIBRIDGE_LOG
+        env::set_var("FIBRIDGE_TEST_LOG", "error");
+        config.apply_args(&matches(&[])).unwrap();
+        env::remove_var("FIBRIDGE_TEST_LOG");
+        assert_eq!(config.logging.filter(rust_log()), "error");
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn malformed_flags_are_errors() {
+        let mut config = Config::default();
+        let errors = config.apply_args(&matches(&["--port", "eighty", "--allow-domain", "example.com"])).unwrap_err();
+        assert_eq!(errors.len(), 2);
+        assert_eq!(config.server.port, 9001);
+    }
+}
@@ -0,0 +1,153 @@
+use std::io;
+use std::sync::Arc;
+use std::error::Error as StdError;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use futures::{Async, Future, Poll, Stream};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::timer::Delay;
+#[cfg(unix)]
+use tokio_uds::{UnixListener, UnixStream};
+use hyper::{Body, Request};
+use hyper::server::conn::Http;
+use hyper::service::{Service, service_fn};
+use tracing::{debug, warn};
+use crate::forwarded::Peer;
+
+
+// How long to stop accepting after an error such as running out of file
+// descriptors, which would otherwise recur immediately
+const ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
+// Listeners Incoming can accept from
+pub trait Accept {
+    type Connection;
+
+    fn poll_accept(&mut self) -> Poll<Self::Connection, io::Error>;
+}
+
+impl Accept for TcpListener {
+    type Connection = (TcpStream, SocketAddr);
+
+    fn poll_accept(&mut self) -> Poll<Self::Connection, io::Error> {
+        TcpListener::poll_accept(self)
+    }
+}
+
+#[cfg(unix)]
+impl Accept for UnixListener {
+    type Connection = UnixStream;
+
+    fn poll_accept(&mut self) -> Poll<Self::Connection, io::Error> {
+        UnixListener::poll_accept(self).map(|accepted| accepted.map(|(stream, _)| stream))
+    }
+}
+
+// Accepts connections for as long as the listener is open. Unlike
+// TcpListener::incoming, errors don't end the stream: they're logged and,
+// unless they only affect the one connection, accepting pauses for a moment,
+// as hyper's AddrIncoming does.
+pub struct Incoming<L> {
+    listener: L,
+    backoff: Option<Delay>,
+}
+
+impl<L: Accept> Incoming<L> {
+    pub fn new(listener: L) -> Self {
+        Self {
+            listener,
+            backoff: None,
+        }
+    }
+}
+
+impl<L: Accept> Stream for Incoming<L> {
+    type Item = L::Connection;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            if let Some(backoff) = self.backoff.as_mut() {
+                match backoff.poll() {
+                    Ok(Async::NotReady) => return Ok(Async::NotReady),
+                    // A timer error just means we retry sooner
+                    Ok(Async::Ready(())) | Err(_) => self.backoff = None,
+                }
+            }
+
+            match self.listener.poll_accept() {
+                Ok(Async::Ready(accepted)) => return Ok(Async::Ready(Some(accepted))),
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Err(ref e) if is_connection_error(e) => {
+                    debug!("Accepted connection already closed: {}", e);
+                },
+                Err(e) => {
+                    warn!("Failed to accept connection: {}", e);
+                    self.backoff = Some(Delay::new(Instant::now() + ACCEPT_BACKOFF));
+                },
+            }
+        }
+    }
+}
+
+// Errors about a connection that went away before we accepted it
+fn is_connection_error(e: &io::Error) -> bool {
+    match e.kind() {
+        io::ErrorKind::ConnectionRefused |
+        io::ErrorKind::ConnectionAborted |
+        io::ErrorKind::ConnectionReset => true,
+        _ => false,
+    }
+}
+
+// Serves HTTP on a plain TCP listener, like warp::serve(..).bind(..) but
+// returning an error if the address can't be bound rather than panicking.
+pub fn listen<F, S>(address: SocketAddr, new_service: F) -> io::Result<impl Future<Item = (), Error = ()> + Send>
+where
+    F: Fn() -> S + Send + Sync + 'static,
+    S: Service<ReqBody = Body, ResBody = Body> + 'static,
+    S::Future: Send + 'static,
+    S::Error: Into<Box<dyn StdError + Send + Sync>>,
+{
+    let listener = TcpListener::bind(&address)?;
+    let new_service = Arc::new(new_service);
+
+    let server = Incoming::new(listener)
+        .for_each(move |(stream, peer): (TcpStream, SocketAddr)| {
+            hyper::rt::spawn(serve(stream, Some(Peer::Addr(peer)), new_service.clone()));
+            Ok(())
+        });
+
+    Ok(server)
+}
+
+// Serves HTTP on a connection we accepted ourselves rather than through
+// warp::serve, e.g. after a PROXY header or TLS handshake. warp can't see the
+// peer address of such connections, so it's attached to every request as a
+// Peer extension, along with the HTTP version for the access log.
+// new_service is called for each request, e.g. with
+// || warp::service(routes.clone()).
+pub fn serve<I, F, S>(io: I, peer: Option<Peer>, new_service: Arc<F>) -> impl Future<Item = (), Error = ()>
+where
+    I: AsyncRead + AsyncWrite + Send + 'static,
+    F: Fn() -> S + Send + Sync + 'static,
+    S: Service<ReqBody = Body, ResBody = Body> + 'static,
+    S::Future: Send + 'static,
+    S::Error: Into<Box<dyn StdError + Send + Sync>>,
+{
+    let service = service_fn(move |mut request: Request<Body>| {
+        if let Some(peer) = peer {
+            request.extensions_mut().insert(peer);
+        }
+        let version = request.version();
+        request.extensions_mut().insert(version);
+        new_service().call(request)
+    });
+
+    Http::new()
+        .serve_connection(io, service)
+        // WebSocket upgrades for /omnistreams
+        .with_upgrades()
+        .map_err(|e| warn!("Connection error: {}", e))
+}
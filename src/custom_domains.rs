@@ -0,0 +1,308 @@
+use std::fmt;
+use std::collections::HashMap;
+use sha2::{Digest, Sha256};
+use trust_dns_resolver::Resolver;
+
+
+// Where a domain's owner publishes the SHA-256 of a secret only the hoster
+// they picked knows, e.g.
+//
+//   _fibridge.files.example.com. TXT "fibridge-verification=2c26b46b68ffc68f..."
+//
+// Hoster ids aren't secret, and without credentials bound to them anyone
+// can connect with any free id, so naming the id would prove nothing.
+pub const TXT_PREFIX: &str = "_fibridge";
+const TXT_KEY: &str = "fibridge-verification=";
+
+// Anything shorter could be guessed from its published hash
+pub const MIN_SECRET_LENGTH: usize = 16;
+
+#[derive(Debug)]
+pub enum DomainError {
+    Invalid,
+    Unverified(String),
+    Lookup(String),
+}
+
+impl fmt::Display for DomainError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DomainError::Invalid => write!(f, "Invalid domain"),
+            DomainError::Unverified(reason) => write!(f, "Domain not verified: {}", reason),
+            DomainError::Lookup(reason) => write!(f, "Failed to verify domain: {}", reason),
+        }
+    }
+}
+
+// A hoster asking for a domain with claimDomain
+pub struct DomainClaim<'a> {
+    pub domain: &'a str,
+    pub hoster_id: &'a str,
+    // Whether the hoster's credentials only allow it this id. Otherwise the
+    // id is whatever the hoster asked for.
+    pub id_bound: bool,
+    // Sent along with the claim, for verifiers that check it against
+    // something the domain's owner published
+    pub secret: Option<&'a str>,
+}
+
+// Decides whether a hoster may serve its files on a domain. Verifiers may
+// block, e.g. on DNS, so they're never called on the event loop.
+pub trait DomainVerifier {
+    fn verify(&self, claim: &DomainClaim) -> Result<(), DomainError>;
+}
+
+// Fixed domain to hoster id pairs, set up by whoever runs the proxy. Handy for
+// tests, and for domains whose DNS can't be changed. Only hosters whose
+// credentials are bound to the id can claim its domains.
+pub struct AllowlistVerifier {
+    allowed: HashMap<String, String>,
+}
+
+impl AllowlistVerifier {
+    pub fn new(allowed: HashMap<String, String>) -> Self {
+        Self {
+            allowed: allowed.into_iter()
+                .map(|(domain, id)| (normalize(&domain), id))
+                .collect(),
+        }
+    }
+}
+
+impl DomainVerifier for AllowlistVerifier {
+    fn verify(&self, claim: &DomainClaim) -> Result<(), DomainError> {
+        match self.allowed.get(claim.domain) {
+            Some(id) if id == claim.hoster_id && claim.id_bound => Ok(()),
+            Some(id) if id == claim.hoster_id => {
+                Err(DomainError::Unverified("the hoster's credentials aren't bound to its id".to_string()))
+            },
+            Some(_) => Err(DomainError::Unverified("allowed for another hoster".to_string())),
+            None => Err(DomainError::Unverified("not in the allowlist".to_string())),
+        }
+    }
+}
+
+// Looks up the TXT records for a name, so DnsTxtVerifier can be pointed at
+// something other than the system's DNS.
+pub trait TxtResolver {
+    fn txt_records(&self, name: &str) -> Result<Vec<String>, String>;
+}
+
+// The resolvers in /etc/resolv.conf
+pub struct SystemTxtResolver {
+    resolver: Resolver,
+}
+
+impl SystemTxtResolver {
+    pub fn new() -> Result<Self, String> {
+        Ok(Self {
+            resolver: Resolver::from_system_conf().map_err(|e| e.to_string())?,
+        })
+    }
+}
+
+impl TxtResolver for SystemTxtResolver {
+    fn txt_records(&self, name: &str) -> Result<Vec<String>, String> {
+        let lookup = self.resolver.txt_lookup(name).map_err(|e| e.to_string())?;
+
+        // A record can be split into several strings, which are meant to be
+        // read as one
+        Ok(lookup.iter()
+            .map(|txt| {
+                txt.txt_data().iter()
+                    .map(|data| String::from_utf8_lossy(data).to_string())
+                    .collect()
+            })
+            .collect())
+    }
+}
+
+// Proves control of a domain the same way ACME's DNS-01 does: the hash of the
+// secret the hoster sends must be published in a TXT record at
+// _fibridge.<domain>.
+pub struct DnsTxtVerifier {
+    resolver: Box<dyn TxtResolver + Send + Sync>,
+}
+
+impl DnsTxtVerifier {
+    pub fn new(resolver: Box<dyn TxtResolver + Send + Sync>) -> Self {
+        Self {
+            resolver,
+        }
+    }
+
+    pub fn system() -> Result<Self, String> {
+        Ok(Self::new(Box::new(SystemTxtResolver::new()?)))
+    }
+}
+
+impl DomainVerifier for DnsTxtVerifier {
+    fn verify(&self, claim: &DomainClaim) -> Result<(), DomainError> {
+        let secret = match claim.secret {
+            Some(secret) if secret.len() >= MIN_SECRET_LENGTH => secret,
+            Some(_) => {
+                return Err(DomainError::Unverified(format!("the secret must be at least {} characters", MIN_SECRET_LENGTH)));
+            },
+            None => return Err(DomainError::Unverified("no secret given".to_string())),
+        };
+
+        let name = format!("{}.{}.", TXT_PREFIX, claim.domain);
+        let records = self.resolver.txt_records(&name).map_err(DomainError::Lookup)?;
+
+        let expected = secret_hash(secret);
+        let found = records.iter()
+            .filter_map(|record| {
+                let record = record.trim();
+                if record.starts_with(TXT_KEY) {
+                    Some(record[TXT_KEY.len()..].to_lowercase())
+                }
+                else {
+                    None
+                }
+            })
+            .any(|hash| hash == expected);
+
+        if found {
+            Ok(())
+        }
+        else {
+            Err(DomainError::Unverified(format!("no {}<sha256 of the secret> TXT record at {}", TXT_KEY, name)))
+        }
+    }
+}
+
+// What goes in the TXT record for a secret: its SHA-256, as lowercase hex
+pub fn secret_hash(secret: &str) -> String {
+    hex::encode(Sha256::digest(secret.as_bytes()))
+}
+
+// The proxy's own host, and the hoster domain with every subdomain of it,
+// which no hoster can take over
+pub fn is_reserved_domain(domain: &str, host: &str, hoster_domain: Option<&str>) -> bool {
+    domain == host ||
+        hoster_domain.map(|hoster_domain| {
+            domain == hoster_domain || domain.ends_with(&format!(".{}", hoster_domain))
+        }).unwrap_or(false)
+}
+
+// Lowercase, without a trailing dot or port, as it's compared to Host headers
+pub fn normalize(domain: &str) -> String {
+    domain.split(':').next().unwrap_or("")
+        .trim_end_matches('.')
+        .to_lowercase()
+}
+
+// At least two labels of letters, digits and hyphens. IP addresses and
+// wildcards can't be claimed.
+pub fn is_valid_domain(domain: &str) -> bool {
+    let labels: Vec<&str> = domain.split('.').collect();
+
+    labels.len() >= 2 &&
+        labels.iter().all(|label| {
+            !label.is_empty() && label.len() <= 63 &&
+                !label.starts_with('-') && !label.ends_with('-') &&
+                label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        }) &&
+        !labels[labels.len() - 1].chars().all(|c| c.is_ascii_digit())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "correct horse battery staple";
+
+    struct FakeResolver(Vec<String>);
+
+    impl TxtResolver for FakeResolver {
+        fn txt_records(&self, name: &str) -> Result<Vec<String>, String> {
+            if name == "_fibridge.files.example.com." {
+                Ok(self.0.clone())
+            }
+            else {
+                Err("no records".to_string())
+            }
+        }
+    }
+
+    fn claim<'a>(domain: &'a str, hoster_id: &'a str, id_bound: bool, secret: Option<&'a str>) -> DomainClaim<'a> {
+        DomainClaim {
+            domain,
+            hoster_id,
+            id_bound,
+            secret,
+        }
+    }
+
+    fn dns_verifier(records: &[&str]) -> DnsTxtVerifier {
+        let records = records.iter().map(|record| record.to_string()).collect();
+        DnsTxtVerifier::new(Box::new(FakeResolver(records)))
+    }
+
+    #[test]
+    fn hashes_secrets() {
+        assert_eq!(secret_hash("foo"), "2c26b46b68ffc68ff99b453c1d30413413422d706483bfa0f98a5e886266e7ae");
+    }
+
+    #[test]
+    fn dns_wants_the_hash_of_the_secret() {
+        let record = format!("fibridge-verification={}", secret_hash(SECRET));
+        let verifier = dns_verifier(&["v=spf1 -all", &record]);
+
+        assert!(verifier.verify(&claim("files.example.com", "my-files", false, Some(SECRET))).is_ok());
+        assert!(matches!(
+            verifier.verify(&claim("files.example.com", "my-files", false, Some("another long secret"))),
+            Err(DomainError::Unverified(_))));
+        assert!(matches!(
+            verifier.verify(&claim("other.example.com", "my-files", false, Some(SECRET))),
+            Err(DomainError::Lookup(_))));
+    }
+
+    #[test]
+    fn dns_ignores_hoster_ids() {
+        let verifier = dns_verifier(&["fibridge-hoster=my-files", "fibridge-verification=my-files"]);
+
+        assert!(verifier.verify(&claim("files.example.com", "my-files", true, Some("my-files"))).is_err());
+        assert!(verifier.verify(&claim("files.example.com", "my-files", true, None)).is_err());
+    }
+
+    #[test]
+    fn dns_rejects_short_secrets() {
+        let record = format!("fibridge-verification={}", secret_hash("short"));
+        let verifier = dns_verifier(&[&record]);
+
+        assert!(matches!(
+            verifier.verify(&claim("files.example.com", "my-files", false, Some("short"))),
+            Err(DomainError::Unverified(_))));
+    }
+
+    #[test]
+    fn allowlist_wants_a_bound_id() {
+        let mut allowed = HashMap::new();
+        allowed.insert("Files.Example.com.".to_string(), "my-files".to_string());
+        let verifier = AllowlistVerifier::new(allowed);
+
+        assert!(verifier.verify(&claim("files.example.com", "my-files", true, None)).is_ok());
+        assert!(verifier.verify(&claim("files.example.com", "my-files", false, None)).is_err());
+        assert!(verifier.verify(&claim("files.example.com", "other", true, None)).is_err());
+        assert!(verifier.verify(&claim("other.example.com", "my-files", true, None)).is_err());
+    }
+
+    #[test]
+    fn reserves_the_proxys_domains() {
+        assert!(is_reserved_domain("fbrg.xyz", "fbrg.xyz", None));
+        assert!(is_reserved_domain("fbrg.xyz", "proxy.example.com", Some("fbrg.xyz")));
+        assert!(is_reserved_domain("my-files.fbrg.xyz", "proxy.example.com", Some("fbrg.xyz")));
+        assert!(!is_reserved_domain("files.example.com", "proxy.example.com", Some("fbrg.xyz")));
+        assert!(!is_reserved_domain("notfbrg.xyz", "proxy.example.com", Some("fbrg.xyz")));
+    }
+
+    #[test]
+    fn validates_domains() {
+        assert!(is_valid_domain("files.example.com"));
+        assert!(!is_valid_domain("localhost"));
+        assert!(!is_valid_domain("*.example.com"));
+        assert!(!is_valid_domain("192.168.0.1"));
+        assert!(!is_valid_domain("-files.example.com"));
+    }
+}
@@ -0,0 +1,300 @@
+use std::sync::Arc;
+use std::net::{IpAddr, SocketAddr};
+use ipnet::IpNet;
+use warp::{self, Filter};
+use warp::http::HeaderMap;
+
+
+// Who's on the other end of a connection served outside warp::serve,
+// attached to every request on it.
+#[derive(Clone, Copy, Debug)]
+pub enum Peer {
+    // The peer address, or the client address from a PROXY protocol header
+    Addr(SocketAddr),
+    // A Unix socket, which only the local reverse proxy can reach
+    Unix,
+}
+
+// What a trusted load balancer or reverse proxy told us about the original
+// request.
+#[derive(Clone, Debug, Default)]
+pub struct ForwardedInfo {
+    pub client_addr: Option<SocketAddr>,
+    pub proto: Option<String>,
+    pub host: Option<String>,
+}
+
+// Addresses whose X-Forwarded-* and Forwarded headers are believed. Requests
+// over a Unix socket are trusted too, and requests whose peer isn't known
+// never are.
+pub struct TrustedProxies {
+    nets: Vec<IpNet>,
+}
+
+impl TrustedProxies {
+    // Accepts CIDRs like 10.0.0.0/8 or plain addresses
+    pub fn parse(proxies: &[String]) -> Result<Self, String> {
+        let nets = proxies.iter()
+            .map(|proxy| {
+                proxy.parse::<IpNet>()
+                    .or_else(|_| proxy.parse::<IpAddr>().map(IpNet::from))
+                    .map_err(|_| format!("Invalid trusted proxy '{}'", proxy))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Self {
+            nets,
+        })
+    }
+
+    fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.nets.iter().any(|net| net.contains(&ip))
+    }
+
+    pub fn resolve(&self, peer: Option<Peer>, headers: &HeaderMap) -> ForwardedInfo {
+        let (peer, trusted) = match peer {
+            Some(Peer::Addr(addr)) => (Some(addr), self.is_trusted(addr.ip())),
+            Some(Peer::Unix) => (None, true),
+            None => (None, false),
+        };
+
+        let untouched = ForwardedInfo {
+            client_addr: peer,
+            ..Default::default()
+        };
+
+        if !trusted {
+            return untouched;
+        }
+
+        let hops = match forwarded_header(headers) {
+            Some(hops) => hops,
+            None => match x_forwarded_headers(headers) {
+                Some(hops) => hops,
+                None => return untouched,
+            },
+        };
+
+        // Each proxy appends whoever connected to it, so walk back from the
+        // nearest until reaching an address we don't trust.
+        let client_addr = hops.iter().rev()
+            .filter_map(|hop| hop.client_addr)
+            .find(|addr| !self.is_trusted(addr.ip()))
+            .or_else(|| hops.iter().filter_map(|hop| hop.client_addr).next())
+            .or(peer);
+
+        // Only the nearest proxy's view of the scheme and host can be trusted
+        let nearest = hops.last();
+
+        ForwardedInfo {
+            client_addr,
+            proto: nearest.and_then(|hop| hop.proto.clone()),
+            host: nearest.and_then(|hop| hop.host.clone()),
+        }
+    }
+}
+
+fn peer() -> impl Filter<Extract = (Option<Peer>,), Error = warp::Rejection> + Clone {
+    warp::ext::get::<Peer>()
+        .map(Some)
+        .or(warp::addr::remote().map(|addr: Option<SocketAddr>| addr.map(Peer::Addr)))
+        .unify()
+}
+
+pub fn forwarded(trusted: Arc<TrustedProxies>) -> impl Filter<Extract = (ForwardedInfo,), Error = warp::Rejection> + Clone {
+    peer()
+        .and(warp::header::headers_cloned())
+        .map(move |peer, headers: HeaderMap| trusted.resolve(peer, &headers))
+}
+
+// The real client address, for logs and anything else that cares who asked
+pub fn client_addr(trusted: Arc<TrustedProxies>) -> impl Filter<Extract = (Option<SocketAddr>,), Error = warp::Rejection> + Clone {
+    forwarded(trusted).map(|info: ForwardedInfo| info.client_addr)
+}
+
+// Forwarded: for=192.0.2.60;proto=https;host=example.com, for="[2001:db8::1]:4711"
+fn forwarded_header(headers: &HeaderMap) -> Option<Vec<ForwardedInfo>> {
+    let values = headers.get_all("forwarded").iter()
+        .filter_map(|value| value.to_str().ok())
+        .collect::<Vec<_>>();
+
+    if values.is_empty() {
+        return None;
+    }
+
+    let hops = values.iter()
+        .flat_map(|value| value.split(','))
+        .map(|element| {
+            let mut hop = ForwardedInfo::default();
+
+            for pair in element.split(';') {
+                let mut parts = pair.splitn(2, '=');
+                let key = parts.next().unwrap_or("").trim().to_lowercase();
+                let value = parts.next().unwrap_or("").trim().trim_matches('"');
+
+                match key.as_str() {
+                    "for" => hop.client_addr = parse_node(value),
+                    "proto" => hop.proto = Some(value.to_lowercase()),
+                    "host" => hop.host = Some(value.to_string()),
+                    _ => (),
+                }
+            }
+
+            hop
+        })
+        .collect();
+
+    Some(hops)
+}
+
+fn x_forwarded_headers(headers: &HeaderMap) -> Option<Vec<ForwardedInfo>> {
+    let header = |name: &str| {
+        headers.get(name)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split(',').last())
+            .map(|value| value.trim().to_string())
+    };
+
+    let mut hops = headers.get_all("x-forwarded-for").iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|node| {
+            ForwardedInfo {
+                client_addr: parse_node(node.trim()),
+                ..Default::default()
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let proto = header("x-forwarded-proto").map(|proto| proto.to_lowercase());
+    let host = header("x-forwarded-host");
+
+    if hops.is_empty() {
+        if proto.is_none() && host.is_none() {
+            return None;
+        }
+        hops.push(ForwardedInfo::default());
+    }
+
+    if let Some(nearest) = hops.last_mut() {
+        nearest.proto = proto;
+        nearest.host = host;
+    }
+
+    Some(hops)
+}
+
+// Accepts 192.0.2.60, 192.0.2.60:4711, 2001:db8::1 and [2001:db8::1]:4711.
+// Obfuscated and "unknown" nodes have no usable address.
+fn parse_node(node: &str) -> Option<SocketAddr> {
+    node.parse::<SocketAddr>().ok()
+        .or_else(|| {
+            node.trim_start_matches('[').trim_end_matches(']')
+                .parse::<IpAddr>().ok()
+                .map(|ip| SocketAddr::new(ip, 0))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use warp::http::HeaderValue;
+
+    fn trusted() -> TrustedProxies {
+        TrustedProxies::parse(&["10.0.0.0/8".to_string(), "192.0.2.1".to_string()]).unwrap()
+    }
+
+    fn peer(addr: &str) -> Option<Peer> {
+        Some(Peer::Addr(addr.parse().unwrap()))
+    }
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for &(name, value) in pairs {
+            headers.append(name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    #[test]
+    fn parses_trusted_proxies() {
+        assert!(TrustedProxies::parse(&["2001:db8::/32".to_string(), "::1".to_string()]).is_ok());
+        assert!(TrustedProxies::parse(&["10.0.0.0/33".to_string()]).is_err());
+        assert!(TrustedProxies::parse(&["proxy.example.com".to_string()]).is_err());
+    }
+
+    #[test]
+    fn forwarded_header() {
+        let headers = headers(&[("forwarded", "for=198.51.100.7;proto=https;host=example.com")]);
+        let info = trusted().resolve(peer("10.1.2.3:5000"), &headers);
+
+        assert_eq!(info.client_addr, "198.51.100.7:0".parse().ok());
+        assert_eq!(info.proto.as_ref().map(|proto| proto.as_str()), Some("https"));
+        assert_eq!(info.host.as_ref().map(|host| host.as_str()), Some("example.com"));
+    }
+
+    #[test]
+    fn forwarded_ipv6_with_port() {
+        let headers = headers(&[("forwarded", "for=\"[2001:db8::1]:4711\"")]);
+        let info = trusted().resolve(peer("10.1.2.3:5000"), &headers);
+        assert_eq!(info.client_addr, "[2001:db8::1]:4711".parse().ok());
+    }
+
+    #[test]
+    fn x_forwarded_headers() {
+        let headers = headers(&[
+            ("x-forwarded-for", "198.51.100.7, 10.0.0.2"),
+            ("x-forwarded-proto", "HTTPS"),
+            ("x-forwarded-host", "example.com"),
+        ]);
+        let info = trusted().resolve(peer("10.1.2.3:5000"), &headers);
+
+        // The nearest untrusted hop is the client
+        assert_eq!(info.client_addr, "198.51.100.7:0".parse().ok());
+        assert_eq!(info.proto.as_ref().map(|proto| proto.as_str()), Some("https"));
+        assert_eq!(info.host.as_ref().map(|host| host.as_str()), Some("example.com"));
+    }
+
+    #[test]
+    fn spoofed_hops_before_an_untrusted_one_are_ignored() {
+        let headers = headers(&[("x-forwarded-for", "203.0.113.9, 198.51.100.7")]);
+        let info = trusted().resolve(peer("10.1.2.3:5000"), &headers);
+        assert_eq!(info.client_addr, "198.51.100.7:0".parse().ok());
+    }
+
+    #[test]
+    fn untrusted_peers_are_taken_at_their_word() {
+        let headers = headers(&[("forwarded", "for=198.51.100.7;proto=https;host=example.com")]);
+        let info = trusted().resolve(peer("203.0.113.9:5000"), &headers);
+
+        assert_eq!(info.client_addr, "203.0.113.9:5000".parse().ok());
+        assert!(info.proto.is_none());
+        assert!(info.host.is_none());
+    }
+
+    #[test]
+    fn unknown_peers_are_untrusted() {
+        let headers = headers(&[("x-forwarded-proto", "https")]);
+        let info = trusted().resolve(None, &headers);
+        assert!(info.client_addr.is_none());
+        assert!(info.proto.is_none());
+    }
+
+    #[test]
+    fn unix_socket_peers_are_trusted() {
+        let headers = headers(&[("x-forwarded-for", "198.51.100.7"), ("x-forwarded-proto", "https")]);
+        let info = trusted().resolve(Some(Peer::Unix), &headers);
+        assert_eq!(info.client_addr, "198.51.100.7:0".parse().ok());
+        assert_eq!(info.proto.as_ref().map(|proto| proto.as_str()), Some("https"));
+    }
+
+    #[test]
+    fn nodes() {
+        assert_eq!(parse_node("192.0.2.60"), "192.0.2.60:0".parse().ok());
+        assert_eq!(parse_node("192.0.2.60:4711"), "192.0.2.60:4711".parse().ok());
+        assert_eq!(parse_node("2001:db8::1"), "[2001:db8::1]:0".parse().ok());
+        assert_eq!(parse_node("[2001:db8::1]"), "[2001:db8::1]:0".parse().ok());
+        assert_eq!(parse_node("unknown"), None);
+        assert_eq!(parse_node("_hidden"), None);
+    }
+}
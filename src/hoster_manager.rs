@@ -1,46 +1,321 @@
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{SystemTime, UNIX_EPOCH, Duration, Instant};
+use std::thread;
 use futures::sync::{mpsc, oneshot};
-use futures::{Stream};
+use futures::{stream, Future, Stream};
+use futures::future::{self, Either};
+use tokio::timer::{timeout, Delay, Timeout};
 use serde_json::{json, Value};
 use omnistreams::{
     Multiplexer, MultiplexerEvent, EventEmitter, Producer, SinkAdapter,
-    MapConduit, Message,
+    MapConduit, Message, CancelReason, Streamer,
 };
-use super::transport::WebSocketTransport;
+use serde::Serialize;
+use uuid::Uuid;
+use super::transport::{WebSocketTransport, CloseHandle};
 use warp::http::{Response};
 use hyper::Body;
 use warp::filters::ws::{WebSocket};
-use crate::stats_conduit::StatsConduit;
+use crate::stats_conduit::{StatsConduit, HosterStats, TransferHandle, CancelOnDrop};
+use crate::transfer_stats::{TransferInfo, TransferOutcome, TransferEvent, TransferAggregator};
+use crate::auth::HosterGrant;
+use crate::signed_url::{UrlSigner, MAX_EXPIRES_IN};
+use crate::basic_auth::{CredentialStore, Credential, constant_time_eq, normalize_path};
+use crate::approval::ApprovalRules;
+use crate::metrics::Metrics;
+use crate::access_log::AccessLog;
+use crate::request_info::RequestInfo;
+use crate::custom_domains::{self, DomainClaim, DomainVerifier};
+use crate::{HosterDomains, now};
+use tracing::{info, debug, trace, Span};
 
 
 type ResponseManagers = Arc<Mutex<HashMap<usize, ResponseManager>>>;
 type Cache = Arc<Mutex<HashMap<String, Vec<u8>>>>;
+type SharedMultiplexer = Arc<Mutex<Multiplexer>>;
+type Transfers = Arc<Mutex<HashMap<usize, TransferHandle>>>;
+type Approvals = Arc<Mutex<HashMap<usize, oneshot::Sender<bool>>>>;
 
-const MAX_CACHED_SIZE: usize = 20 * 1024 * 1024;
+pub type ResponseFuture = Box<dyn Future<Item = Response<Body>, Error = oneshot::Canceled> + Send>;
 
+// Sent when a hoster's connection closes: its id, and the session token that
+// tells it apart from a later hoster with the same id
+pub type HosterDone = (String, String);
+
+// Proxy wide services handed to every hoster
+#[derive(Clone)]
+pub struct HosterServices {
+    pub url_signer: Option<Arc<UrlSigner>>,
+    pub metrics: Arc<Metrics>,
+    pub access_log: Arc<AccessLog>,
+    pub transfer_stats: Arc<TransferAggregator>,
+    pub approval_timeout: Duration,
+    pub max_cached_size: usize,
+    // Prefixed to generated links, e.g. /fibridge, or empty
+    pub base_path: String,
+    pub domains: HosterDomains,
+    // None turns custom domains off
+    pub domain_verifier: Option<Arc<dyn DomainVerifier + Send + Sync>>,
+    // The proxy's own host and hoster domain, which can't be claimed
+    pub host: String,
+    pub hoster_domain: Option<String>,
+}
 
 pub struct HosterManager {
     id: String,
     next_request_id: usize,
-    mux: Multiplexer,
+    sender: RequestSender,
+    credentials: Arc<Mutex<CredentialStore>>,
+    approval_rules: Arc<Mutex<ApprovalRules>>,
+    approvals: Approvals,
+    approval_timeout: Duration,
+    session_token: String,
+    connected_at: SystemTime,
+    remote_addr: Option<SocketAddr>,
+    close_handle: CloseHandle,
+    close_watchers: CloseWatchers,
+    holdings: Holdings,
+}
+
+// Dropped when the hoster disconnects, which ends anything waiting on them.
+// None once that's happened.
+type CloseWatchers = Arc<Mutex<Option<Vec<oneshot::Sender<()>>>>>;
+
+// What a hoster holds on the proxy, let go of as soon as it's gone, whether
+// it closed the connection itself or was disconnected
+#[derive(Clone)]
+struct Holdings {
+    id: String,
+    transfers: Transfers,
+    approvals: Approvals,
+    domains: HosterDomains,
+    // Also stops a domain verified after the hoster left from being claimed
+    closed: Arc<AtomicBool>,
+    close_watchers: CloseWatchers,
+}
+
+impl Holdings {
+    // Only the first call does anything
+    fn release(&self) {
+        {
+            let mut domains = self.domains.lock().expect("get lock");
+            if self.closed.swap(true, Ordering::SeqCst) {
+                return;
+            }
+            domains.retain(|_, owner| *owner != self.id);
+        }
+
+        // Anything still streaming will never get the rest of its data.
+        for (_, transfer) in self.transfers.lock().expect("get lock").drain() {
+            transfer.fail();
+        }
+
+        // Dropping the senders fails any pending approvals
+        self.approvals.lock().expect("get lock").clear();
+
+        self.close_watchers.lock().expect("get lock").take();
+    }
+}
+
+// Everything needed to ask the hoster for a file, shared so requests can be
+// sent after the hoster approves them.
+#[derive(Clone)]
+struct RequestSender {
+    id: String,
+    mux: SharedMultiplexer,
     response_managers: ResponseManagers,
     cache: Cache,
+    stats: Arc<HosterStats>,
+    access_log: Arc<AccessLog>,
+}
+
+// Snapshot of a hoster for the admin API
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HosterInfo {
+    pub id: String,
+    pub connected_at: u64,
+    pub remote_address: Option<String>,
+    pub active_transfers: usize,
+    pub bytes_served: usize,
+    pub cached_files: usize,
+    pub cache_size: usize,
 }
 
 struct ResponseManager {
     cache_key: String,
+    request: RequestInfo,
+    span: Span,
     tx: oneshot::Sender<Response<Body>>,
 }
 
+// A hoster's WebSocket, before it has been given an id
+pub struct HosterConnection {
+    mux: Multiplexer,
+    events: Box<dyn Stream<Item = MultiplexerEvent, Error = ()> + Send>,
+    close_handle: CloseHandle,
+}
+
+// A hoster asking for an id in its first control message:
+//
+//   {"jsonrpc": "2.0", "method": "claimId", "params": {"id": "my-files"}, "id": 1}
+pub struct IdRequest {
+    pub id: String,
+    rpc_id: Value,
+}
+
+impl HosterConnection {
+    pub fn new(ws: WebSocket) -> Self {
+        let transport = WebSocketTransport::new(ws);
+        let close_handle = transport.close_handle();
+        let mut mux = Multiplexer::new(transport);
+        let events = mux.events().expect("no events").map_err(|_| ());
+
+        Self {
+            mux,
+            events: Box::new(events),
+            close_handle,
+        }
+    }
+
+    // Waits up to timeout for a claimId as the hoster's first message.
+    // Anything else it sends first is handled as usual once it has an id.
+    // Fails if the hoster goes away in the meantime.
+    pub fn id_request(self, timeout: Duration) -> impl Future<Item = (Option<IdRequest>, Self), Error = ()> + Send {
+        let HosterConnection { mux, events, close_handle } = self;
+
+        events.into_future()
+            .select2(Delay::new(Instant::now() + timeout))
+            .then(move |result| {
+                let (first, events) = match result {
+                    Ok(Either::A(((Some(first), events), _))) => (Some(first), events),
+                    // The hoster left before it was given an id
+                    Ok(Either::A(((None, _), _))) | Err(Either::A(_)) => return Err(()),
+                    // Nothing yet, so the hoster gets a generated id
+                    Ok(Either::B((_, pending))) | Err(Either::B((_, pending))) => {
+                        match pending.into_inner() {
+                            Some(events) => (None, events),
+                            None => return Err(()),
+                        }
+                    },
+                };
+
+                let request = match &first {
+                    Some(MultiplexerEvent::ControlMessage(control_message)) => {
+                        serde_json::from_slice::<Value>(control_message).ok()
+                            .filter(|message| message["method"] == "claimId")
+                            .map(|message| {
+                                IdRequest {
+                                    id: message["params"]["id"].as_str().unwrap_or("").to_string(),
+                                    rpc_id: message["id"].clone(),
+                                }
+                            })
+                    },
+                    _ => None,
+                };
+
+                let events: Box<dyn Stream<Item = MultiplexerEvent, Error = ()> + Send> = match first {
+                    Some(first) if request.is_none() => Box::new(stream::once(Ok(first)).chain(events)),
+                    _ => events,
+                };
+
+                Ok((request, HosterConnection { mux, events, close_handle }))
+            })
+    }
+
+    // Answers an IdRequest with the id the hoster ended up with, or why it
+    // didn't get one
+    pub fn respond(&mut self, request: &IdRequest, result: Result<&str, String>) {
+        let response = match result {
+            Ok(id) => {
+                json!({
+                    "jsonrpc": "2.0",
+                    "result": {
+                        "id": id,
+                    },
+                    "id": request.rpc_id,
+                })
+            },
+            Err(message) => {
+                json!({
+                    "jsonrpc": "2.0",
+                    "error": {
+                        "code": -32000,
+                        "message": message,
+                    },
+                    "id": request.rpc_id,
+                })
+            },
+        };
+
+        self.mux.send_control_message(response.to_string().as_bytes().to_vec());
+    }
+
+    pub fn close(&self) {
+        self.close_handle.close();
+    }
+}
+
 impl HosterManager {
-    pub fn new(id: String, ws: WebSocket, done_tx: mpsc::UnboundedSender<String>) -> Self {
+    pub fn new(
+        id: String,
+        connection: HosterConnection,
+        done_tx: mpsc::UnboundedSender<HosterDone>,
+        grant: HosterGrant,
+        remote_addr: Option<SocketAddr>,
+        services: HosterServices,
+    ) -> Self {
+
+        let url_signer = services.url_signer;
+        let base_path = services.base_path;
+        let domains = services.domains;
+        let closed = Arc::new(AtomicBool::new(false));
+        let domain_claims = DomainClaims {
+            domains: domains.clone(),
+            verifier: services.domain_verifier,
+            host: services.host,
+            hoster_domain: services.hoster_domain,
+            id_bound: grant.id.is_some(),
+            closed: closed.clone(),
+            pending: Arc::new(AtomicBool::new(false)),
+        };
+        let close_watchers: CloseWatchers = Arc::new(Mutex::new(Some(Vec::new())));
+        let access_log = services.access_log;
+        let access_log_clone = access_log.clone();
 
         let cache = Arc::new(Mutex::new(HashMap::new()));
         let cache_clone = cache.clone();
 
-        let transport = WebSocketTransport::new(ws);
-        let mut mux = Multiplexer::new(transport);
+        let stats = Arc::new(HosterStats::new(services.metrics));
+        let stats_clone = stats.clone();
+
+        let transfer_stats = services.transfer_stats;
+        let max_cached_size = services.max_cached_size;
+
+        let transfers_clone: Transfers = Arc::new(Mutex::new(HashMap::new()));
+
+        let HosterConnection { mut mux, events, close_handle } = connection;
+
+        // Credentials that expire end the connection they were used for,
+        // rather than only keeping new ones out
+        if let Some(expires) = grant.expires {
+            let (closed_tx, closed_rx) = oneshot::channel();
+            if let Some(watchers) = close_watchers.lock().expect("get lock").as_mut() {
+                watchers.push(closed_tx);
+            }
+
+            let expiry_id = id.clone();
+            let expiry_close_handle = close_handle.clone();
+            warp::spawn(expiry(expires, closed_rx).map(move |expired| {
+                if expired {
+                    info!(hoster_id = %expiry_id, "credentials expired");
+                    expiry_close_handle.close();
+                }
+            }));
+        }
 
         let rpc_set_id = json!({
             "jsonrpc": "2.0",
@@ -50,12 +325,69 @@ impl HosterManager {
 
         mux.send_control_message(rpc_set_id.as_bytes().to_vec());
 
-        let events = mux.events().expect("no events");
+        // Lets the hoster's GUI prove it belongs to this hoster, e.g. when
+        // subscribing to its transfer feed.
+        let session_token = Uuid::new_v4().to_simple().to_string();
+
+        let rpc_set_session_token = json!({
+            "jsonrpc": "2.0",
+            "method": "setSessionToken",
+            "params": session_token,
+        }).to_string();
+
+        mux.send_control_message(rpc_set_session_token.as_bytes().to_vec());
+
+        let done_session_token = session_token.clone();
+
+        let mux = Arc::new(Mutex::new(mux));
+        let mux_clone = mux.clone();
+
+        let credentials = Arc::new(Mutex::new(CredentialStore::new()));
+        let credentials_clone = credentials.clone();
+
+        let approval_rules = Arc::new(Mutex::new(ApprovalRules::new()));
+        let approval_rules_clone = approval_rules.clone();
+
+        let approvals: Approvals = Arc::new(Mutex::new(HashMap::new()));
+        let approvals_clone = approvals.clone();
+
+        let holdings = Holdings {
+            id: id.clone(),
+            transfers: transfers_clone.clone(),
+            approvals: approvals.clone(),
+            domains: domains.clone(),
+            closed: closed.clone(),
+            close_watchers: close_watchers.clone(),
+        };
+        let holdings_clone = holdings.clone();
 
         let response_managers: ResponseManagers = Arc::new(Mutex::new(HashMap::new()));
         let response_managers_clone = response_managers.clone();
 
+        // Keep the hoster up to date on its transfers so it can show who
+        // downloaded what.
+        let (stop_notifications_tx, stop_notifications_rx) = oneshot::channel::<()>();
+        let mut stop_notifications_tx = Some(stop_notifications_tx);
+
+        let notifications_id = id.clone();
+        let notifications_mux = mux.clone();
+        let notifications = transfer_stats.subscribe_hoster(&notifications_id)
+            .for_each(move |event| {
+                if let Some(notification) = transfer_notification(&event) {
+                    notifications_mux.lock().expect("get lock")
+                        .send_control_message(notification.to_string().as_bytes().to_vec());
+                }
+
+                Ok(())
+            })
+            .select(stop_notifications_rx.map_err(|_| ()))
+            .map(|_| ())
+            .map_err(|_| ());
+
+        warp::spawn(notifications);
+
         let id_clone = id.clone();
+        let malformed_close_handle = close_handle.clone();
 
         warp::spawn(events.for_each(move |event| {
 
@@ -63,28 +395,98 @@ impl HosterManager {
 
             match event {
                 MultiplexerEvent::Close => {
-                    done_tx.unbounded_send(id).expect("signal done");
+                    holdings_clone.release();
+
+                    if let Some(stop_notifications_tx) = stop_notifications_tx.take() {
+                        match stop_notifications_tx.send(()) {
+                            Ok(_) => (),
+                            Err(_) => (),
+                        }
+                    }
+
+                    done_tx.unbounded_send((id, done_session_token.clone())).expect("signal done");
                 },
                 MultiplexerEvent::ControlMessage(control_message) => {
-                    let message: Value = serde_json::from_slice(&control_message)
-                        .expect("parse control message");
+                    // A hoster that can't speak JSON-RPC is told so and let go
+                    let message: Value = match serde_json::from_slice(&control_message) {
+                        Ok(message) => message,
+                        Err(e) => {
+                            debug!("malformed control message: {}", e);
+                            mux_clone.lock().expect("get lock")
+                                .send_control_message(parse_error(&e).to_string().as_bytes().to_vec());
+                            malformed_close_handle.close();
+                            return Ok(());
+                        },
+                    };
 
-                    println!("{}", message);
+                    // Control messages include file paths, so keep them out of
+                    // the logs unless asked for.
+                    trace!(%message, "control message");
 
-                    if message.get("error").is_some() {
+                    if message["method"] == "signUrl" {
+                        let response = sign_url(&id, &base_path, url_signer.as_ref().map(|s| &**s), &message);
+                        mux_clone.lock().expect("get lock")
+                            .send_control_message(response.to_string().as_bytes().to_vec());
+                    }
+                    else if message["method"] == "setCredentials" {
+                        let response = set_credentials(&credentials_clone, &message);
+                        mux_clone.lock().expect("get lock")
+                            .send_control_message(response.to_string().as_bytes().to_vec());
+                    }
+                    else if message["method"] == "setApprovalRequired" {
+                        let response = set_approval_required(&approval_rules_clone, &message);
+                        mux_clone.lock().expect("get lock")
+                            .send_control_message(response.to_string().as_bytes().to_vec());
+                    }
+                    else if message["method"] == "claimDomain" {
+                        let mux = mux_clone.clone();
+                        let response = claim_domain(&id, &domain_claims, &message)
+                            .map(move |response| {
+                                mux.lock().expect("get lock")
+                                    .send_control_message(response.to_string().as_bytes().to_vec());
+                            });
+                        warp::spawn(response);
+                    }
+                    else if message["method"] == "releaseDomain" {
+                        let response = release_domain(&id, &domains, &message);
+                        mux_clone.lock().expect("get lock")
+                            .send_control_message(response.to_string().as_bytes().to_vec());
+                    }
+                    else if let Some(approval_tx) = message["id"].as_u64()
+                        .and_then(|rpc_id| approvals_clone.lock().expect("get lock").remove(&(rpc_id as usize))) {
+
+                        // Anything but an explicit yes is a no
+                        let approved = message["result"] == true;
+                        match approval_tx.send(approved) {
+                            Ok(_) => (),
+                            Err(_) => (),
+                        }
+                    }
+                    else if message.get("error").is_some() {
 
                         match &message["id"] {
                             Value::Number(request_id) => {
                                 let request_id = request_id.as_u64().expect("parse u64") as usize;
                                 let mut lock = response_managers_clone.lock().expect("get lock");
-                                let response_manager = lock.remove(&request_id).expect("removed tx");
+                                // Could be the answer to an approval that
+                                // already timed out
+                                let response_manager = match lock.remove(&request_id) {
+                                    Some(response_manager) => response_manager,
+                                    None => return Ok(()),
+                                };
+                                let _enter = response_manager.span.enter();
+
+                                let body = message["error"]["message"].to_string();
+                                debug!(error = %body, "hoster returned an error");
+                                access_log_clone.log(&id, &response_manager.request, 404, body.len(), TransferOutcome::Completed);
 
                                 let response = Response::builder()
                                     .status(404)
-                                    .body(message["error"]["message"].to_string().into())
+                                    .body(body.into())
                                       .expect("error response");
 
-                                response_manager.tx.send(response).expect("error send");
+                                // The client may have gone away already
+                                let _ = response_manager.tx.send(response);
                             },
                             _ => (),
                         }
@@ -94,25 +496,67 @@ impl HosterManager {
 
                     let md: Value = serde_json::from_slice(&metadata).expect("parse metadata");
 
-                    println!("Create conduit");
-                    println!("{}", md);
-
                     let request_id = md["id"].as_u64().expect("parse id") as usize;
 
+                    let span = response_managers_clone.lock().expect("get lock")
+                        .get(&request_id)
+                        .map(|response_manager| response_manager.span.clone())
+                        .unwrap_or_else(Span::none);
+                    let _enter = span.enter();
+
+                    debug!("create conduit");
+                    trace!(metadata = %md, "conduit metadata");
+
                     let (stream_tx, stream_rx) = mpsc::channel::<Vec<u8>>(1);
                     let stream_rx = stream_rx.map_err(|_e| {
                         "stream fail"
                     });
 
-                    // See if there's a way to do this without importing hyper
-                    // directly.
-                    let body = Body::wrap_stream(stream_rx);
-
                     let mut builder = Response::builder();
 
                     let size = md["result"]["size"].as_u64().expect("parse size") as usize;
 
-                    match md["result"].get("range") {
+                    if let Some(max_file_size) = grant.max_file_size {
+                        if size as u64 > max_file_size {
+                            let body = "File exceeds the hoster's size limit";
+
+                            let response = Response::builder()
+                                .status(413)
+                                .body(body.into())
+                                .expect("error response");
+
+                            let mut lock = response_managers_clone.lock().expect("get lock");
+                            let response_manager = lock.remove(&request_id).expect("removed tx");
+                            access_log_clone.log(&id, &response_manager.request, 413, body.len(), TransferOutcome::Completed);
+                            match response_manager.tx.send(response) {
+                                Ok(_) => (),
+                                Err(_) => (),
+                            }
+
+                            // Cancel the stream like a disconnected client
+                            // would, so the hoster can let go of the file.
+                            let mut producer = producer;
+                            producer.cancel(CancelReason::Disconnected);
+
+                            let notification = json!({
+                                "jsonrpc": "2.0",
+                                "method": "transferCancelled",
+                                "params": {
+                                    "id": request_id,
+                                    "reason": "file exceeds size limit",
+                                },
+                            }).to_string();
+
+                            mux_clone.lock().expect("get lock")
+                                .send_control_message(notification.as_bytes().to_vec());
+
+                            return Ok(());
+                        }
+                    }
+
+                    let mut status = 200;
+
+                    let content_length = match md["result"].get("range") {
                         Some(Value::Object(range)) => {
                             let start = range["start"].as_u64().expect("parse start") as usize;
 
@@ -128,34 +572,83 @@ impl HosterManager {
                             // Need to subtract one from end because HTTP ranges are inclusive
                             let content_range = format!("bytes {}-{}/{}", start, end - 1, size);
 
+                            status = 206;
+
                             builder
                                 .status(206)
                                 .header("Content-Range", content_range)
                                 .header("Content-Length", len);
+
+                            len
                         },
                         _ => {
                             builder.header("Content-Length", size);
+
+                            size
                         },
-                    }
+                    };
+
+                    let mut lock = response_managers_clone.lock().expect("get lock");
+                    let response_manager = lock.remove(&request_id).expect("removed tx");
+
+                    let cache = cache_clone.clone();
+                    let cache_key = response_manager.cache_key.clone();
+
+                    let info = TransferInfo {
+                        request_id,
+                        hoster_id: id,
+                        status,
+                        expected_bytes: content_length,
+                        request: response_manager.request,
+                    };
+
+                    let stats_conduit = StatsConduit::new(info, stats_clone.clone(), transfer_stats.clone());
+                    let transfer = stats_conduit.handle();
+                    let end_transfer = transfer.clone();
+
+                    let mut transfers = transfers_clone.lock().expect("get lock");
+                    transfers.retain(|_, transfer| !transfer.is_finished());
+                    transfers.insert(request_id, transfer.clone());
+
+                    // hyper drops the body when the client goes away, which
+                    // is our only sign of it. Cancel the conduit so the
+                    // hoster stops sending, and tell it why.
+                    let disconnect_mux = mux_clone.clone();
+                    let stream_rx = CancelOnDrop::new(stream_rx, move || {
+                        if transfer.cancel(CancelReason::Disconnected) {
+                            let notification = json!({
+                                "jsonrpc": "2.0",
+                                "method": "transferCancelled",
+                                "params": {
+                                    "id": request_id,
+                                    "reason": "client disconnected",
+                                },
+                            }).to_string();
+
+                            disconnect_mux.lock().expect("get lock")
+                                .send_control_message(notification.as_bytes().to_vec());
+                        }
+                    })
+                    // A hoster that ends the stream early leaves the file
+                    // truncated
+                    .on_end(move || end_transfer.end());
+
+                    // See if there's a way to do this without importing hyper
+                    // directly.
+                    let body = Body::wrap_stream(stream_rx);
 
                     let response = builder
                         .header("Accept-Ranges", "bytes")
                         .header("Content-Type", "application/octet-stream")
                         .body(body).expect("response");
 
-
-                    let mut lock = response_managers_clone.lock().expect("get lock");
-                    let response_manager = lock.remove(&request_id).expect("removed tx");
                     match response_manager.tx.send(response) {
                         Ok(_) => (),
                         Err(_) => (),
                     }
 
-                    let cache = cache_clone.clone();
-                    let cache_key = response_manager.cache_key.clone();
-
                     // TODO: this is hacky
-                    let mut cached = if size <= MAX_CACHED_SIZE {
+                    let mut cached = if size <= max_cached_size {
                         vec![0; size]
                     }
                     else {
@@ -165,7 +658,7 @@ impl HosterManager {
                     let mut index = 0;
                     let cache_conduit = MapConduit::new(move |data: Message| {
 
-                        if size <= MAX_CACHED_SIZE {
+                        if size <= max_cached_size {
 
                             for elem in &data {
                                 cached[index] = *elem;
@@ -173,7 +666,7 @@ impl HosterManager {
                             }
 
                             if index == size {
-                                println!("add {} to cache", cache_key.clone());
+                                debug!("added to cache");
                                 cache.lock().expect("lock cache")
                                     // TODO: get rid of this extra clone
                                     .insert(cache_key.clone(), cached.clone());
@@ -186,7 +679,7 @@ impl HosterManager {
                     let consumer = SinkAdapter::new(stream_tx);
                     producer
                         .pipe_through(cache_conduit)
-                        .pipe_through(StatsConduit::new(request_id))
+                        .pipe_through(stats_conduit)
                         .pipe_into(consumer);
                 }
             }
@@ -196,9 +689,24 @@ impl HosterManager {
         Self {
             id: id.clone(),
             next_request_id: 0,
-            mux,
-            response_managers,
-            cache,
+            sender: RequestSender {
+                id,
+                mux,
+                response_managers,
+                cache,
+                stats,
+                access_log,
+            },
+            credentials,
+            approval_rules,
+            approvals,
+            approval_timeout: services.approval_timeout,
+            session_token,
+            connected_at: SystemTime::now(),
+            remote_addr,
+            close_handle,
+            close_watchers,
+            holdings,
         }
     }
 
@@ -206,36 +714,143 @@ impl HosterManager {
         self.id.clone()
     }
 
+    pub fn info(&self) -> HosterInfo {
+        let cache = self.sender.cache.lock().expect("lock cache");
+        let stats = &self.sender.stats;
+
+        HosterInfo {
+            id: self.id.clone(),
+            connected_at: self.connected_at.duration_since(UNIX_EPOCH).expect("system time").as_secs(),
+            remote_address: self.remote_addr.map(|addr| addr.to_string()),
+            active_transfers: stats.active_transfers.load(Ordering::SeqCst),
+            bytes_served: stats.bytes_served.load(Ordering::SeqCst),
+            cached_files: cache.len(),
+            cache_size: cache.values().map(|cached| cached.len()).sum(),
+        }
+    }
+
+    pub fn purge_cache(&self) {
+        self.sender.cache.lock().expect("lock cache").clear();
+    }
+
+    // Lets go of the hoster's domains, transfers and pending approvals right
+    // away, rather than once its connection has finished closing, then closes
+    // it. The caller removes the hoster from the HosterManagers.
+    pub fn disconnect(&self) {
+        self.holdings.release();
+        self.close_handle.close();
+    }
+
+    pub fn authorize(&self, filename: &str, authorization: Option<&str>) -> bool {
+        self.credentials.lock().expect("get lock")
+            .check(&format!("/{}", filename), authorization)
+    }
+
+    pub fn check_session(&self, token: &str) -> bool {
+        constant_time_eq(self.session_token.as_bytes(), token.as_bytes())
+    }
+
+    // Resolves, with an error, once the hoster has disconnected
+    pub fn closed(&self) -> oneshot::Receiver<()> {
+        let (tx, rx) = oneshot::channel();
+        if let Some(watchers) = self.close_watchers.lock().expect("get lock").as_mut() {
+            watchers.push(tx);
+        }
+        rx
+    }
+
     fn next_request_id(&mut self) -> usize {
         let id = self.next_request_id;
         self.next_request_id += 1;
         id
     }
 
-    pub fn process_request(&mut self, filename: String, range_header: String) -> oneshot::Receiver<Response<Body>> {
+    pub fn process_request(&mut self, filename: String, range_header: String, request_info: RequestInfo) -> ResponseFuture {
 
         let request_id = self.next_request_id();
 
+        let span = Span::current();
+        span.record("request_id", &(request_id as u64));
+
+        let path = format!("/{}", filename);
+
+        if !self.approval_rules.lock().expect("get lock").required(&path) {
+            return Box::new(self.sender.get_file(request_id, filename, range_header, request_info, span));
+        }
+
+        // Approval gets its own RPC id so a late answer can't be mistaken for
+        // a getFile error.
+        let rpc_id = self.next_request_id();
+
+        let (approval_tx, approval_rx) = oneshot::channel();
+        self.approvals.lock().expect("get lock").insert(rpc_id, approval_tx);
+
         let mut request = json!({
             "jsonrpc": "2.0",
-            "method": "getFile",
-            "params": json!({
-                "path": format!("/{}", filename),
-            }),
-            "id": request_id,
+            "method": "authorizeRequest",
+            "params": {
+                "path": path,
+                "requestId": request_id,
+                "clientIp": request_info.client_ip(),
+                "userAgent": request_info.user_agent,
+            },
+            "id": rpc_id,
         });
 
-        let (response_tx, response_rx) = oneshot::channel();
+        if let Some(range) = parse_range_header(&range_header) {
+            request["params"]["range"] = range;
+        }
+
+        debug!("waiting for hoster approval");
+        self.sender.mux.lock().expect("get lock").send_control_message(request.to_string().as_bytes().to_vec());
+
+        let sender = self.sender.clone();
+        let approvals = self.approvals.clone();
+
+        let response = Timeout::new(approval_rx, self.approval_timeout).then(move |result| {
+
+            approvals.lock().expect("get lock").remove(&rpc_id);
+
+            match refusal(result) {
+                Ok(None) => {
+                    debug!("approved by hoster");
+                    Either::A(sender.get_file(request_id, filename, range_header, request_info, span))
+                },
+                Ok(Some((status, message))) => {
+                    debug!(status, "not approved by hoster");
+                    Either::B(future::ok(sender.error_response(&request_info, status, message)))
+                },
+                Err(e) => Either::B(future::err(e)),
+            }
+        });
+
+        Box::new(response)
+    }
+}
+
+impl RequestSender {
+    fn get_file(
+        &self,
+        request_id: usize,
+        filename: String,
+        range_header: String,
+        request_info: RequestInfo,
+        span: Span,
+    ) -> oneshot::Receiver<Response<Body>> {
 
         let range = parse_range_header(&range_header);
+        let request = get_file_request(request_id, &filename, range.clone(), &request_info);
 
-        if range.is_some() {
-            request["params"]["range"] = range.expect("set range");
-        }
-        else {
-            match self.cache.lock().expect("lock cache").get(&filename) {
+        let (response_tx, response_rx) = oneshot::channel();
+
+        if range.is_none() {
+            let cache = self.cache.lock().expect("lock cache");
+            self.stats.cache_lookup(cache.contains_key(&filename));
+
+            match cache.get(&filename) {
                 Some(cached) => {
-                    println!("serve {} from cache", filename);
+                    debug!("served from cache");
+                    self.access_log.log(&self.id, &request_info, 200, cached.len(), TransferOutcome::Completed);
                     // TODO: this early return is nastay
                     let response = Response::builder()
                         .body(cached.clone().into()).expect("error response");
@@ -246,40 +861,524 @@ impl HosterManager {
             }
         }
 
-        self.mux.send_control_message(request.to_string().as_bytes().to_vec());
+        self.mux.lock().expect("get lock").send_control_message(request.to_string().as_bytes().to_vec());
 
         let response_manager = ResponseManager {
             cache_key: filename,
+            request: request_info,
+            span,
             tx: response_tx,
         };
         self.response_managers.lock().expect("get lock").insert(request_id, response_manager);
 
         response_rx
     }
+
+    fn error_response(&self, request_info: &RequestInfo, status: u16, body: &str) -> Response<Body> {
+        self.access_log.log(&self.id, request_info, status, body.len(), TransferOutcome::Completed);
+
+        Response::builder()
+            .status(status)
+            .body(body.to_string().into())
+            .expect("error response")
+    }
+}
+
+// Resolves to true once the unix time expires has passed, or false if the
+// hoster disconnected first
+fn expiry(expires: u64, closed: oneshot::Receiver<()>) -> impl Future<Item = bool, Error = ()> + Send {
+    let remaining = Duration::from_secs(expires.saturating_sub(now()));
+
+    Delay::new(Instant::now() + remaining)
+        .select2(closed)
+        .then(|result| {
+            match result {
+                Ok(Either::A(_)) => Ok(true),
+                _ => Ok(false),
+            }
+        })
+}
+
+// The JSON-RPC answer to a control message that isn't JSON
+fn parse_error(e: &serde_json::Error) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "error": {
+            "code": -32700,
+            "message": format!("Parse error: {}", e),
+        },
+        "id": null,
+    })
+}
+
+// Handles a hoster's request to mint a signed link to one of its files:
+//
+//   {"jsonrpc": "2.0", "method": "signUrl", "params": {"path": "/file.txt", "expiresIn": 3600}, "id": 7}
+pub(crate) fn sign_url(id: &str, base_path: &str, url_signer: Option<&UrlSigner>, message: &Value) -> Value {
+
+    let path = message["params"]["path"].as_str().unwrap_or("");
+    // Default to a day
+    let expires_in = message["params"]["expiresIn"].as_u64().unwrap_or(24 * 60 * 60);
+
+    // Signed the way the download route will see it
+    let filename = normalize_path(path)[1..].to_string();
+
+    match url_signer {
+        Some(url_signer) if !filename.is_empty() => {
+            match url_signer.signed_path(id, &filename, expires_in) {
+                Some(signed_path) => {
+                    json!({
+                        "jsonrpc": "2.0",
+                        "result": {
+                            "path": format!("{}{}", base_path, signed_path),
+                        },
+                        "id": message["id"],
+                    })
+                },
+                None => {
+                    json!({
+                        "jsonrpc": "2.0",
+                        "error": {
+                            "code": -32602,
+                            "message": format!("expiresIn can be at most {}", MAX_EXPIRES_IN),
+                        },
+                        "id": message["id"],
+                    })
+                },
+            }
+        },
+        Some(_) => {
+            json!({
+                "jsonrpc": "2.0",
+                "error": {
+                    "code": -32602,
+                    "message": "Invalid path",
+                },
+                "id": message["id"],
+            })
+        },
+        None => {
+            json!({
+                "jsonrpc": "2.0",
+                "error": {
+                    "code": -32601,
+                    "message": "URL signing is not enabled on this proxy",
+                },
+                "id": message["id"],
+            })
+        },
+    }
+}
+
+// Handles a hoster's request to password protect its files. Omitting the path
+// applies the credentials to every file, and an empty list removes them:
+//
+//   {"jsonrpc": "2.0", "method": "setCredentials", "params": {"path": "/file.txt", "credentials": [{"username": "alice", "password": "hunter2"}]}, "id": 8}
+fn set_credentials(credentials: &Mutex<CredentialStore>, message: &Value) -> Value {
+
+    let path = message["params"]["path"].as_str().map(|path| path.to_string());
+
+    match serde_json::from_value::<Vec<Credential>>(message["params"]["credentials"].clone()) {
+        Ok(new_credentials) => {
+            credentials.lock().expect("get lock").set(path, new_credentials);
+            json!({
+                "jsonrpc": "2.0",
+                "result": true,
+                "id": message["id"],
+            })
+        },
+        Err(e) => {
+            json!({
+                "jsonrpc": "2.0",
+                "error": {
+                    "code": -32602,
+                    "message": format!("Invalid credentials: {}", e),
+                },
+                "id": message["id"],
+            })
+        },
+    }
 }
 
+// Handles a hoster's request to approve downloads before they start. Omitting
+// the path applies to every file:
+//
+//   {"jsonrpc": "2.0", "method": "setApprovalRequired", "params": {"path": "/file.txt", "required": true}, "id": 9}
+fn set_approval_required(approval_rules: &Mutex<ApprovalRules>, message: &Value) -> Value {
+
+    let path = message["params"]["path"].as_str().map(|path| path.to_string());
+
+    match message["params"]["required"].as_bool() {
+        Some(required) => {
+            approval_rules.lock().expect("get lock").set(path, required);
+            json!({
+                "jsonrpc": "2.0",
+                "result": true,
+                "id": message["id"],
+            })
+        },
+        None => {
+            json!({
+                "jsonrpc": "2.0",
+                "error": {
+                    "code": -32602,
+                    "message": "Missing required",
+                },
+                "id": message["id"],
+            })
+        },
+    }
+}
+
+// What a hoster's claimDomain messages are checked against
+struct DomainClaims {
+    domains: HosterDomains,
+    verifier: Option<Arc<dyn DomainVerifier + Send + Sync>>,
+    host: String,
+    hoster_domain: Option<String>,
+    // Whether the hoster's credentials only allow it its id
+    id_bound: bool,
+    // Set once the hoster disconnects
+    closed: Arc<AtomicBool>,
+    // Set while the verifier runs
+    pending: Arc<AtomicBool>,
+}
+
+// Handles a hoster's request to have its files served on a domain of its own,
+// e.g. https://files.example.com/file.txt. The answer comes once the proxy's
+// verifier has agreed:
+//
+//   {"jsonrpc": "2.0", "method": "claimDomain", "params": {"domain": "files.example.com", "secret": "..."}, "id": 9}
+fn claim_domain(id: &str, claims: &DomainClaims, message: &Value) -> Box<dyn Future<Item = Value, Error = ()> + Send> {
+
+    let rpc_id = message["id"].clone();
+    let error = move |code: i32, message: String| {
+        json!({
+            "jsonrpc": "2.0",
+            "error": {
+                "code": code,
+                "message": message,
+            },
+            "id": rpc_id,
+        })
+    };
+
+    let verifier = match &claims.verifier {
+        Some(verifier) => verifier.clone(),
+        None => return Box::new(future::ok(error(-32601, "Custom domains are not enabled on this proxy".to_string()))),
+    };
+
+    let domain = custom_domains::normalize(message["params"]["domain"].as_str().unwrap_or(""));
+
+    if !custom_domains::is_valid_domain(&domain) {
+        return Box::new(future::ok(error(-32602, "Invalid domain".to_string())));
+    }
+
+    if custom_domains::is_reserved_domain(&domain, &claims.host, claims.hoster_domain.as_ref().map(|domain| domain.as_str())) {
+        return Box::new(future::ok(error(-32000, "Domain is reserved by the proxy".to_string())));
+    }
+
+    // Verifiers may block, e.g. on DNS, which takes a thread. One claim at a
+    // time per hoster keeps a hoster from tying up any number of them.
+    if claims.pending.swap(true, Ordering::SeqCst) {
+        return Box::new(future::ok(error(-32000, "Another domain claim is pending".to_string())));
+    }
+
+    let (result_tx, result_rx) = oneshot::channel();
+    let verify_domain = domain.clone();
+    let verify_id = id.to_string();
+    let id_bound = claims.id_bound;
+    let secret = message["params"]["secret"].as_str().map(|secret| secret.to_string());
+    let pending = claims.pending.clone();
+    thread::spawn(move || {
+        let claim = DomainClaim {
+            domain: &verify_domain,
+            hoster_id: &verify_id,
+            id_bound,
+            secret: secret.as_ref().map(|secret| secret.as_str()),
+        };
+        let result = verifier.verify(&claim);
+        pending.store(false, Ordering::SeqCst);
+        match result_tx.send(result) {
+            Ok(_) => (),
+            Err(_) => (),
+        }
+    });
+
+    let id = id.to_string();
+    let domains = claims.domains.clone();
+    let closed = claims.closed.clone();
+    let rpc_id = message["id"].clone();
+
+    Box::new(result_rx.map_err(|_| ()).map(move |result| {
+        if let Err(e) = result {
+            debug!(hoster_id = %id, domain = %domain, "domain claim failed: {}", e);
+            return error(-32000, e.to_string());
+        }
+
+        let mut domains = domains.lock().expect("get lock");
+
+        if closed.load(Ordering::SeqCst) {
+            return error(-32000, "Hoster disconnected".to_string());
+        }
+
+        match domains.get(&domain) {
+            Some(owner) if owner != &id => {
+                error(-32000, "Domain is claimed by another hoster".to_string())
+            },
+            _ => {
+                domains.insert(domain.clone(), id.clone());
+                info!(hoster_id = %id, domain = %domain, "domain claimed");
+                json!({
+                    "jsonrpc": "2.0",
+                    "result": {
+                        "domain": domain,
+                    },
+                    "id": rpc_id,
+                })
+            },
+        }
+    }))
+}
+
+// Stops serving the hoster's files on a domain it claimed. Disconnecting
+// releases all of them.
+//
+//   {"jsonrpc": "2.0", "method": "releaseDomain", "params": {"domain": "files.example.com"}, "id": 10}
+fn release_domain(id: &str, domains: &HosterDomains, message: &Value) -> Value {
+    let domain = custom_domains::normalize(message["params"]["domain"].as_str().unwrap_or(""));
+
+    let mut domains = domains.lock().expect("get lock");
+
+    let released = match domains.get(&domain) {
+        Some(owner) if owner == id => {
+            domains.remove(&domain);
+            true
+        },
+        _ => false,
+    };
+
+    json!({
+        "jsonrpc": "2.0",
+        "result": released,
+        "id": message["id"],
+    })
+}
+
+// The status and message a download is refused with, given the hoster's
+// answer to its authorizeRequest, or None if it was approved. An error if
+// the hoster went away without answering.
+fn refusal(answer: Result<bool, timeout::Error<oneshot::Canceled>>) -> Result<Option<(u16, &'static str)>, oneshot::Canceled> {
+    match answer {
+        Ok(true) => Ok(None),
+        Ok(false) => Ok(Some((403, "Download denied by the hoster"))),
+        Err(ref e) if e.is_elapsed() => Ok(Some((504, "Timed out waiting for the hoster's approval"))),
+        Err(_) => Err(oneshot::Canceled),
+    }
+}
+
+// Asks the hoster for a file on a client's behalf, telling it who's asking:
+//
+//   {"jsonrpc": "2.0", "method": "getFile", "params": {"path": "/file.txt", "requestId": 3, "clientIp": "192.0.2.1", "userAgent": "curl/7.64.0", "range": {"start": 0, "end": 1024}}, "id": 3}
+fn get_file_request(request_id: usize, filename: &str, range: Option<Value>, request_info: &RequestInfo) -> Value {
+    let mut request = json!({
+        "jsonrpc": "2.0",
+        "method": "getFile",
+        "params": {
+            "path": format!("/{}", filename),
+            "requestId": request_id,
+            "clientIp": request_info.client_ip(),
+            "userAgent": request_info.user_agent,
+        },
+        "id": request_id,
+    });
+
+    if let Some(range) = range {
+        request["params"]["range"] = range;
+    }
+
+    request
+}
+
+// What a hoster hears about its transfers as they go. It already knows they
+// started from the getFile request.
+//
+//   {"jsonrpc": "2.0", "method": "transferComplete", "params": {"requestId": 3, "bytes": 1024, "durationMs": 20, "throughput": 51200.0, "outcome": "completed"}}
+fn transfer_notification(event: &TransferEvent) -> Option<Value> {
+    let method = match event {
+        TransferEvent::Started(_) => return None,
+        TransferEvent::Progress(_) => "transferProgress",
+        TransferEvent::Finished(_) => "transferComplete",
+    };
+
+    Some(json!({
+        "jsonrpc": "2.0",
+        "method": method,
+        "params": event.to_json(),
+    }))
+}
+
+// Only a single range with a start is passed on to the hoster. Anything
+// else, malformed or not, is ignored and the whole file sent, which HTTP
+// allows.
 fn parse_range_header(header: &str) -> Option<Value> {
-    if header == "" {
+    let header = header.trim();
+    if !header.starts_with("bytes=") {
         return None;
     }
-    else {
 
-        // TODO: error handling
-        let parts: Vec<&str> = header.split('=').collect();
-        let range_str: Vec<&str> = parts[1].split('-').collect();
-        let start = range_str[0].parse::<usize>().expect("parse range start");
-        let end_str = range_str[1];
+    let mut bounds = header["bytes=".len()..].splitn(2, '-');
+    let start = bounds.next()?.trim().parse::<usize>().ok()?;
+    let end = bounds.next()?.trim();
 
-        let mut range = json!({
-            "start": start,
-        });
+    let mut range = json!({
+        "start": start,
+    });
+
+    if !end.is_empty() {
+        let end = end.parse::<usize>().ok().filter(|end| *end >= start)?;
+        // Need to add one because HTTP ranges are inclusive
+        range["end"] = json!(end.checked_add(1)?);
+    }
+
+    Some(range)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use warp::http::{HeaderMap, Version};
+    use tokio::runtime::current_thread::Runtime;
+    use crate::transfer_stats::{TransferProgress, TransferStats};
+
+    fn request_info() -> RequestInfo {
+        let mut headers = HeaderMap::new();
+        headers.insert("user-agent", "curl/7.64.0".parse().unwrap());
+        RequestInfo::new("GET", "/my-files/file.txt".to_string(), Version::HTTP_11, "192.0.2.1:4711".parse().ok(), &headers)
+    }
+
+    #[test]
+    fn parses_single_ranges() {
+        assert_eq!(parse_range_header("bytes=0-1023"), Some(json!({ "start": 0, "end": 1024 })));
+        assert_eq!(parse_range_header("bytes=100-"), Some(json!({ "start": 100 })));
+        assert_eq!(parse_range_header(" bytes=5-5 "), Some(json!({ "start": 5, "end": 6 })));
+    }
 
-        if end_str.len() > 0 {
-            // Need to add one because HTTP ranges are inclusive
-            let end = 1 + end_str.parse::<usize>().expect("parse range end");
-            range["end"] = json!(end);
+    #[test]
+    fn ignores_ranges_it_cant_pass_on() {
+        for header in &["", "bytes", "bytes=", "bytes=-500", "bytes=abc-", "bytes=0-abc",
+                "bytes=10-5", "bytes=0-1,5-6", "items=0-10", "bytes=0", "=", "bytes=0-18446744073709551615"] {
+            assert_eq!(parse_range_header(header), None, "{}", header);
         }
+    }
+
+    #[test]
+    fn refuses_unapproved_downloads() {
+        let mut runtime = Runtime::new().unwrap();
+        let timeout = Duration::from_millis(20);
+
+        let answer = |runtime: &mut Runtime, approval_rx: oneshot::Receiver<bool>| {
+            let result = runtime.block_on(Timeout::new(approval_rx, timeout).then(Ok::<_, ()>)).unwrap();
+            refusal(result)
+        };
+
+        let (approval_tx, approval_rx) = oneshot::channel();
+        approval_tx.send(true).unwrap();
+        assert_eq!(answer(&mut runtime, approval_rx), Ok(None));
+
+        let (approval_tx, approval_rx) = oneshot::channel();
+        approval_tx.send(false).unwrap();
+        assert!(matches!(answer(&mut runtime, approval_rx), Ok(Some((403, _)))));
+
+        // The hoster never answers
+        let (_approval_tx, approval_rx) = oneshot::channel();
+        assert!(matches!(answer(&mut runtime, approval_rx), Ok(Some((504, _)))));
+
+        // The hoster disconnects, dropping its pending approvals
+        let (approval_tx, approval_rx) = oneshot::channel::<bool>();
+        drop(approval_tx);
+        assert!(answer(&mut runtime, approval_rx).is_err());
+    }
+
+    #[test]
+    fn get_file_says_who_is_asking() {
+        let request = get_file_request(3, "dir/file.txt", None, &request_info());
+
+        assert_eq!(request["method"], "getFile");
+        assert_eq!(request["id"], 3);
+        assert_eq!(request["params"], json!({
+            "path": "/dir/file.txt",
+            "requestId": 3,
+            "clientIp": "192.0.2.1",
+            "userAgent": "curl/7.64.0",
+        }));
+
+        let range = parse_range_header("bytes=0-1023");
+        let request = get_file_request(4, "file.txt", range, &request_info());
+        assert_eq!(request["params"]["range"], json!({ "start": 0, "end": 1024 }));
+        assert_eq!(request["params"]["requestId"], 4);
+    }
+
+    #[test]
+    fn notifies_hosters_of_progress_and_completion() {
+        let info = TransferInfo {
+            request_id: 3,
+            hoster_id: "my-files".to_string(),
+            status: 200,
+            expected_bytes: 1024,
+            request: request_info(),
+        };
+        assert!(transfer_notification(&TransferEvent::Started(info)).is_none());
+
+        let progress = transfer_notification(&TransferEvent::Progress(TransferProgress {
+            request_id: 3,
+            hoster_id: "my-files".to_string(),
+            bytes: 512,
+            expected_bytes: 1024,
+            elapsed: Duration::from_secs(1),
+        })).unwrap();
+        assert_eq!(progress["method"], "transferProgress");
+        assert_eq!(progress["params"]["requestId"], 3);
+        assert_eq!(progress["params"]["bytes"], 512);
+        assert_eq!(progress["params"]["size"], 1024);
+        assert!(progress.get("id").is_none());
+
+        let complete = transfer_notification(&TransferEvent::Finished(TransferStats {
+            request_id: 3,
+            hoster_id: "my-files".to_string(),
+            status: 200,
+            request: request_info(),
+            bytes: 1024,
+            duration: Duration::from_secs(2),
+            time_to_first_byte: None,
+            outcome: TransferOutcome::Completed,
+        })).unwrap();
+        assert_eq!(complete["method"], "transferComplete");
+        assert_eq!(complete["params"]["bytes"], 1024);
+        assert_eq!(complete["params"]["durationMs"], 2000);
+        assert_eq!(complete["params"]["outcome"], "completed");
+        // Client addresses stay with the proxy
+        assert!(complete["params"].get("clientIp").is_none());
+    }
+
+    #[test]
+    fn expired_credentials_end_the_connection() {
+        let mut runtime = Runtime::new().unwrap();
+
+        let (_closed_tx, closed_rx) = oneshot::channel();
+        assert_eq!(runtime.block_on(expiry(now() - 10, closed_rx)), Ok(true));
+
+        let (closed_tx, closed_rx) = oneshot::channel::<()>();
+        drop(closed_tx);
+        assert_eq!(runtime.block_on(expiry(now() + 3600, closed_rx)), Ok(false));
+    }
+
+    #[test]
+    fn malformed_control_messages_get_a_parse_error() {
+        let e = serde_json::from_slice::<Value>(b"{\"method\": ").unwrap_err();
+        let response = parse_error(&e);
 
-        Some(range)
+        assert_eq!(response["error"]["code"], -32700);
+        assert_eq!(response["id"], Value::Null);
     }
 }
@@ -1,3 +1,6 @@
+use std::fmt;
+use std::collections::HashSet;
+use std::str::FromStr;
 use rand::Rng;
 use uuid::Uuid;
 
@@ -6,7 +9,7 @@ pub trait IdGenerator {
     fn gen(&self) -> String;
 }
 
-#[derive(Clone)]
+#[derive(Clone, Default)]
 pub struct UuidGenerator {
 }
 
@@ -23,7 +26,7 @@ impl IdGenerator for UuidGenerator {
     }
 }
 
-#[derive(Clone)]
+#[derive(Clone, Default)]
 pub struct ShortIdGenerator {
 }
 
@@ -60,7 +63,7 @@ impl IdGenerator for ShortIdGenerator {
     }
 }
 
-pub fn create_generator(gen_type: &str) -> Box<IdGenerator + Send + Sync> {
+pub fn create_generator(gen_type: &str) -> Box<dyn IdGenerator + Send + Sync> {
     if gen_type == "uuid" {
         Box::new(UuidGenerator::new())
     }
@@ -68,3 +71,224 @@ pub fn create_generator(gen_type: &str) -> Box<IdGenerator + Send + Sync> {
         Box::new(ShortIdGenerator::new())
     }
 }
+
+const MIN_ID_LENGTH: usize = 3;
+const MAX_ID_LENGTH: usize = 64;
+const MAX_ID_ATTEMPTS: usize = 1000;
+
+// Ids live in the first path segment, so anything the proxy serves itself
+// can't be handed out to a hoster.
+const RESERVED_IDS: [&str; 8] = [
+    "omnistreams", "admin", "api", "metrics", "static", "assets", "www", "index.html",
+];
+
+// Ids the proxy can't hand out on top of RESERVED_IDS, which depend on how
+// it's configured
+#[derive(Clone, Debug, Default)]
+pub struct IdRules {
+    reserved: HashSet<String>,
+    hostnames: bool,
+}
+
+impl IdRules {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // A first path segment the proxy serves something else at
+    pub fn reserve(&mut self, segment: &str) {
+        self.reserved.insert(segment.to_string());
+    }
+
+    // Ids that must also work as a DNS label, e.g. as <id>.<hoster domain>.
+    // Labels can't contain underscores.
+    pub fn require_hostnames(&mut self) {
+        self.hostnames = true;
+    }
+
+    fn is_reserved(&self, id: &str) -> bool {
+        RESERVED_IDS.contains(&id) || self.reserved.contains(id)
+    }
+}
+
+#[derive(Debug)]
+pub enum IdError {
+    Invalid(String),
+    Reserved(String),
+    Taken(String),
+    Exhausted,
+}
+
+impl fmt::Display for IdError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IdError::Invalid(id) => write!(f, "Invalid id '{}'", id),
+            IdError::Reserved(id) => write!(f, "Id '{}' is reserved", id),
+            IdError::Taken(id) => write!(f, "Id '{}' is already in use", id),
+            IdError::Exhausted => write!(f, "Out of ids"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IdConflictPolicy {
+    // Refuse the connection if the requested id is in use
+    Reject,
+    // Hand out a generated id instead
+    Fallback,
+}
+
+impl FromStr for IdConflictPolicy {
+    type Err = String;
+
+    fn from_str(policy: &str) -> Result<Self, Self::Err> {
+        match policy {
+            "reject" => Ok(IdConflictPolicy::Reject),
+            "fallback" => Ok(IdConflictPolicy::Fallback),
+            _ => Err(format!("Unknown conflict policy '{}', expected fallback or reject", policy)),
+        }
+    }
+}
+
+pub fn validate_id(id: &str, rules: &IdRules) -> Result<(), IdError> {
+    if id.len() < MIN_ID_LENGTH || id.len() > MAX_ID_LENGTH {
+        return Err(IdError::Invalid(id.to_string()));
+    }
+
+    let valid_chars = id.chars().all(|c| {
+        c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_'
+    });
+
+    if !valid_chars || id.starts_with('-') || id.ends_with('-') {
+        return Err(IdError::Invalid(id.to_string()));
+    }
+
+    if rules.hostnames && id.contains('_') {
+        return Err(IdError::Invalid(id.to_string()));
+    }
+
+    if rules.is_reserved(id) {
+        return Err(IdError::Reserved(id.to_string()));
+    }
+
+    Ok(())
+}
+
+pub fn allocate_id<F>(
+    generator: &dyn IdGenerator,
+    requested: Option<&str>,
+    policy: IdConflictPolicy,
+    rules: &IdRules,
+    is_taken: F,
+) -> Result<String, IdError>
+    where F: Fn(&str) -> bool
+{
+    if let Some(requested) = requested {
+        validate_id(requested, rules)?;
+
+        if !is_taken(requested) {
+            return Ok(requested.to_string());
+        }
+
+        if policy == IdConflictPolicy::Reject {
+            return Err(IdError::Taken(requested.to_string()));
+        }
+    }
+
+    for _ in 0..MAX_ID_ATTEMPTS {
+        let id = generator.gen();
+        if !rules.is_reserved(&id) && !is_taken(&id) {
+            return Ok(id);
+        }
+    }
+
+    Err(IdError::Exhausted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct FixedGenerator(&'static str);
+
+    impl IdGenerator for FixedGenerator {
+        fn gen(&self) -> String {
+            self.0.to_string()
+        }
+    }
+
+    #[test]
+    fn validates_ids() {
+        assert!(validate_id("my-files", &IdRules::new()).is_ok());
+        assert!(validate_id("abc_123", &IdRules::new()).is_ok());
+
+        assert!(matches!(validate_id("ab", &IdRules::new()), Err(IdError::Invalid(_))));
+        assert!(matches!(validate_id(&"a".repeat(65), &IdRules::new()), Err(IdError::Invalid(_))));
+        assert!(matches!(validate_id("My-Files", &IdRules::new()), Err(IdError::Invalid(_))));
+        assert!(matches!(validate_id("-abc", &IdRules::new()), Err(IdError::Invalid(_))));
+        assert!(matches!(validate_id("abc-", &IdRules::new()), Err(IdError::Invalid(_))));
+        assert!(matches!(validate_id("a/b/c", &IdRules::new()), Err(IdError::Invalid(_))));
+        assert!(matches!(validate_id("omnistreams", &IdRules::new()), Err(IdError::Reserved(_))));
+    }
+
+    #[test]
+    fn configured_ids_are_reserved() {
+        let mut rules = IdRules::new();
+        rules.reserve("favicon.ico");
+        rules.reserve("docs");
+
+        assert!(matches!(validate_id("docs", &rules), Err(IdError::Reserved(_))));
+        assert!(validate_id("docs", &IdRules::new()).is_ok());
+
+        let id = allocate_id(&FixedGenerator("docs"), None, IdConflictPolicy::Fallback, &rules, |_| false);
+        assert!(matches!(id, Err(IdError::Exhausted)));
+    }
+
+    #[test]
+    fn hostname_ids_have_no_underscores() {
+        let mut rules = IdRules::new();
+        rules.require_hostnames();
+
+        assert!(matches!(validate_id("abc_123", &rules), Err(IdError::Invalid(_))));
+        assert!(validate_id("abc-123", &rules).is_ok());
+
+        let id = allocate_id(&FixedGenerator("gen-id"), Some("my_files"), IdConflictPolicy::Fallback, &rules, |_| false);
+        assert!(matches!(id, Err(IdError::Invalid(_))));
+    }
+
+    #[test]
+    fn allocates_the_requested_id_when_free() {
+        let id = allocate_id(&FixedGenerator("gen-id"), Some("my-files"), IdConflictPolicy::Reject, &IdRules::new(), |_| false);
+        assert_eq!(id.unwrap(), "my-files");
+    }
+
+    #[test]
+    fn rejects_or_falls_back_when_taken() {
+        let taken = |id: &str| id == "my-files";
+
+        let id = allocate_id(&FixedGenerator("gen-id"), Some("my-files"), IdConflictPolicy::Reject, &IdRules::new(), taken);
+        assert!(matches!(id, Err(IdError::Taken(_))));
+
+        let id = allocate_id(&FixedGenerator("gen-id"), Some("my-files"), IdConflictPolicy::Fallback, &IdRules::new(), taken);
+        assert_eq!(id.unwrap(), "gen-id");
+    }
+
+    #[test]
+    fn never_falls_back_for_invalid_ids() {
+        let id = allocate_id(&FixedGenerator("gen-id"), Some("admin"), IdConflictPolicy::Fallback, &IdRules::new(), |_| false);
+        assert!(matches!(id, Err(IdError::Reserved(_))));
+    }
+
+    #[test]
+    fn gives_up_when_every_generated_id_is_taken() {
+        let id = allocate_id(&FixedGenerator("gen-id"), None, IdConflictPolicy::Fallback, &IdRules::new(), |_| true);
+        assert!(matches!(id, Err(IdError::Exhausted)));
+    }
+
+    #[test]
+    fn parses_conflict_policies() {
+        assert_eq!("reject".parse::<IdConflictPolicy>(), Ok(IdConflictPolicy::Reject));
+        assert_eq!("fallback".parse::<IdConflictPolicy>(), Ok(IdConflictPolicy::Fallback));
+        assert!("rejcet".parse::<IdConflictPolicy>().is_err());
+    }
+}
@@ -0,0 +1,70 @@
+// The proxy as a library, for embedding it in other services or testing it.
+// ProxyServer::builder() sets it up; the fibridge-proxy-rs binary is a thin
+// command line over the same builder.
+//
+//   let server = ProxyServer::builder()
+//       .listener(Listener {
+//           address: ListenAddress::Tcp("0.0.0.0:9001".parse().unwrap()),
+//           mode: ListenerMode::Plain,
+//           proxy_protocol: false,
+//       })
+//       .authenticator(Box::new(ApiKeyAuthenticator::from_file("api-keys.txt")?))
+//       .build();
+//
+//   let handle = server.handle();
+//   hyper::rt::run(server.run()?);
+
+mod transport;
+mod stats_conduit;
+mod basic_auth;
+mod admin;
+mod request_info;
+mod approval;
+#[cfg(unix)]
+mod unix_socket;
+mod proxy_protocol;
+mod connection;
+mod static_files;
+mod transfer_stats;
+mod transfer_feed;
+mod server;
+
+pub mod hoster_manager;
+pub mod id_generator;
+pub mod auth;
+pub mod signed_url;
+pub mod metrics;
+pub mod access_log;
+pub mod config;
+pub mod forwarded;
+pub mod tls;
+pub mod acme;
+pub mod custom_domains;
+
+use std::sync::{Arc, Mutex};
+use std::collections::{HashMap, HashSet};
+use std::time::{SystemTime, UNIX_EPOCH};
+use warp::{self, Filter};
+use crate::hoster_manager::HosterManager;
+
+pub use crate::server::{ProxyServer, ProxyServerBuilder, ProxyHandle};
+pub use crate::config::{Config, ConfigError, Listener, ListenerMode, ListenAddress};
+
+pub type HosterManagers = Arc<Mutex<HashMap<String, HosterManager>>>;
+pub type BlockedIds = Arc<Mutex<HashSet<String>>>;
+// Custom domains claimed by connected hosters, mapped to their ids
+pub type HosterDomains = Arc<Mutex<HashMap<String, String>>>;
+
+
+// warp::query rejects requests without a query string, so fall back to an
+// empty map.
+fn query_params() -> impl Filter<Extract = (HashMap<String, String>,), Error = warp::Rejection> + Clone {
+    warp::query::<HashMap<String, String>>()
+        .or(warp::any().map(HashMap::new))
+        .unify()
+}
+
+// Seconds since the unix epoch, as used for expiry times
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).expect("system time").as_secs()
+}
@@ -1,216 +1,360 @@
-mod transport;
-mod hoster_manager;
-mod stats_conduit;
-mod id_generator;
-
-use std::sync::{Arc, Mutex};
-use std::collections::HashMap;
-use warp::{self, Filter};
-use warp::http::{Response, Uri};
-use warp::path::FullPath;
-use hoster_manager::HosterManager;
-use futures::{Future, Stream};
-use futures::sync::{mpsc};
-use futures::future::Either;
+use std::env;
+use std::io;
+use std::process;
 use clap::{App, Arg};
-use std::net::SocketAddrV4;
 use hyper::rt;
-use crate::id_generator::{create_generator};
-
-type HosterManagers = Arc<Mutex<HashMap<String, HosterManager>>>;
+use tracing_subscriber::EnvFilter;
+use fibridge_proxy_rs::{Config, ConfigError, ProxyServerBuilder};
 
 
 fn main() {
     let matches = App::new("fibridge proxy")
         .about("Share local files via HTTP streaming")
+        .arg(Arg::with_name("config")
+             .short("c")
+             .long("config")
+             .env("FIBRIDGE_CONFIG")
+             .value_name("CONFIG_FILE")
+             .help("TOML config file. Flags and their environment variables override it")
+             .takes_value(true))
         .arg(Arg::with_name("host")
              .short("h")
              .long("host")
+             .env("FIBRIDGE_HOST")
              .value_name("HOST")
              .takes_value(true))
         .arg(Arg::with_name("ip")
              .short("i")
              .long("ip-address")
+             .env("FIBRIDGE_IP_ADDRESS")
              .value_name("IP")
              .takes_value(true))
         .arg(Arg::with_name("port")
              .short("p")
              .long("port")
+             .env("FIBRIDGE_PORT")
              .value_name("PORT")
              .takes_value(true))
+        .arg(Arg::with_name("hoster-domain")
+             .long("hoster-domain")
+             .env("FIBRIDGE_HOSTER_DOMAIN")
+             .value_name("DOMAIN")
+             .help("Also serve each hoster at <id>.DOMAIN, giving it its own origin. Needs a wildcard DNS record")
+             .takes_value(true))
+        .arg(Arg::with_name("domain-verification")
+             .long("domain-verification")
+             .env("FIBRIDGE_DOMAIN_VERIFICATION")
+             .value_name("VERIFICATION")
+             .help("Let hosters claim custom domains, verified with dns or allowlist")
+             .takes_value(true))
+        .arg(Arg::with_name("allow-domain")
+             .long("allow-domain")
+             .value_name("DOMAIN=ID")
+             .help("Let hoster ID claim DOMAIN with --domain-verification allowlist. Can be repeated")
+             .takes_value(true)
+             .multiple(true)
+             .number_of_values(1))
+        .arg(Arg::with_name("base-path")
+             .long("base-path")
+             .env("FIBRIDGE_BASE_PATH")
+             .value_name("PATH")
+             .help("Serve everything under this path, e.g. /fibridge, for sharing a domain with other services")
+             .takes_value(true))
+        .arg(Arg::with_name("websocket-path")
+             .long("websocket-path")
+             .env("FIBRIDGE_WEBSOCKET_PATH")
+             .value_name("PATH")
+             .help("Where hosters connect, under --base-path. Defaults to /omnistreams")
+             .takes_value(true))
+        .arg(Arg::with_name("listen")
+             .long("listen")
+             .value_name("ADDRESS")
+             .help("Serve plain HTTP on this address, e.g. [::]:80. Can be repeated, and replaces --ip-address and --port")
+             .takes_value(true)
+             .multiple(true)
+             .number_of_values(1))
+        .arg(Arg::with_name("listen-tls")
+             .long("listen-tls")
+             .value_name("ADDRESS")
+             .help("Serve HTTPS on this address using --key and --cert. Can be repeated")
+             .takes_value(true)
+             .multiple(true)
+             .number_of_values(1))
+        .arg(Arg::with_name("listen-unix")
+             .long("listen-unix")
+             .value_name("PATH")
+             .help("Serve plain HTTP on a Unix domain socket, e.g. for a reverse proxy on the same machine. Can be repeated")
+             .takes_value(true)
+             .multiple(true)
+             .number_of_values(1))
+        .arg(Arg::with_name("unix-socket-permissions")
+             .long("unix-socket-permissions")
+             .env("FIBRIDGE_UNIX_SOCKET_PERMISSIONS")
+             .value_name("MODE")
+             .help("Octal permissions for --listen-unix sockets, e.g. 660")
+             .takes_value(true))
+        .arg(Arg::with_name("proxy-protocol")
+             .long("proxy-protocol")
+             .help("Expect a HAProxy PROXY protocol header on every --listen connection"))
+        .arg(Arg::with_name("trusted-proxy")
+             .long("trusted-proxy")
+             .value_name("CIDR")
+             .help("Believe X-Forwarded-* and Forwarded headers from this address or network. Can be repeated")
+             .takes_value(true)
+             .multiple(true)
+             .number_of_values(1))
+        .arg(Arg::with_name("static-dir")
+             .long("static-dir")
+             .env("FIBRIDGE_STATIC_DIR")
+             .value_name("DIR")
+             .help("Serve this directory at /, e.g. a build of the GUI")
+             .takes_value(true))
+        .arg(Arg::with_name("index-page")
+             .long("index-page")
+             .env("FIBRIDGE_INDEX_PAGE")
+             .value_name("FILE")
+             .help("Serve this file at / instead of the GUI")
+             .takes_value(true))
+        .arg(Arg::with_name("static-cache-max-age")
+             .long("static-cache-max-age")
+             .env("FIBRIDGE_STATIC_CACHE_MAX_AGE")
+             .value_name("SECONDS")
+             .help("How long browsers may cache files from --static-dir, other than HTML. Defaults to 3600")
+             .takes_value(true))
+        .arg(Arg::with_name("redirect-port")
+             .long("redirect-port")
+             .env("FIBRIDGE_REDIRECT_PORT")
+             .value_name("HANDLING")
+             .possible_values(&["omit-default", "keep"])
+             .help("Whether HTTPS redirects leave out :443 (omit-default) or always include the secure port (keep)")
+             .takes_value(true))
+        .arg(Arg::with_name("redirect-status")
+             .long("redirect-status")
+             .env("FIBRIDGE_REDIRECT_STATUS")
+             .value_name("STATUS")
+             .possible_values(&["301", "308"])
+             .help("Status for HTTPS redirects. 308 keeps the method and body. Defaults to 301")
+             .takes_value(true))
+        .arg(Arg::with_name("plain-path")
+             .long("plain-path")
+             .value_name("PATH")
+             .help("Serve requests under this path over plain HTTP instead of redirecting them. Can be repeated")
+             .takes_value(true)
+             .multiple(true)
+             .number_of_values(1))
+        .arg(Arg::with_name("hsts-max-age")
+             .long("hsts-max-age")
+             .env("FIBRIDGE_HSTS_MAX_AGE")
+             .value_name("SECONDS")
+             .help("Send Strict-Transport-Security with this max-age on HTTPS responses")
+             .takes_value(true))
+        .arg(Arg::with_name("hsts-include-subdomains")
+             .long("hsts-include-subdomains")
+             .help("Add includeSubDomains to Strict-Transport-Security"))
+        .arg(Arg::with_name("hsts-preload")
+             .long("hsts-preload")
+             .help("Add preload to Strict-Transport-Security"))
         .arg(Arg::with_name("id-type")
              .long("id-type")
+             .env("FIBRIDGE_ID_TYPE")
              .value_name("ID TYPE")
              .takes_value(true))
+        .arg(Arg::with_name("id-conflict")
+             .long("id-conflict")
+             .env("FIBRIDGE_ID_CONFLICT")
+             .value_name("ID_CONFLICT")
+             .help("What to do when a requested id is taken: fallback or reject")
+             .takes_value(true))
+        .arg(Arg::with_name("api-keys")
+             .long("api-keys")
+             .env("FIBRIDGE_API_KEYS")
+             .value_name("API_KEYS_FILE")
+             .help("Require hosters to present one of the keys in this file")
+             .takes_value(true))
+        .arg(Arg::with_name("jwt-secret")
+             .long("jwt-secret")
+             .env("FIBRIDGE_JWT_SECRET")
+             .value_name("JWT_SECRET_FILE")
+             .help("Accept hoster JWTs signed with this HS256 secret")
+             .takes_value(true))
+        .arg(Arg::with_name("jwt-public-key")
+             .long("jwt-public-key")
+             .env("FIBRIDGE_JWT_PUBLIC_KEY")
+             .value_name("JWT_PUBLIC_KEY_FILE")
+             .help("Accept hoster JWTs signed with this RS256 key (PEM)")
+             .takes_value(true))
+        .arg(Arg::with_name("jwks")
+             .long("jwks")
+             .env("FIBRIDGE_JWKS")
+             .value_name("JWKS_FILE")
+             .help("Accept hoster JWTs signed with any RS256 key in this JWKS file")
+             .takes_value(true))
+        .arg(Arg::with_name("url-signing-key")
+             .long("url-signing-key")
+             .env("FIBRIDGE_URL_SIGNING_KEY")
+             .value_name("URL_SIGNING_KEY_FILE")
+             .help("Only serve downloads whose links are signed with this key")
+             .takes_value(true))
+        .arg(Arg::with_name("approval-timeout")
+             .long("approval-timeout")
+             .env("FIBRIDGE_APPROVAL_TIMEOUT")
+             .value_name("SECONDS")
+             .help("How long to wait for a hoster to approve a download before giving up (default 60)")
+             .takes_value(true))
+        .arg(Arg::with_name("id-request-timeout")
+             .long("id-request-timeout")
+             .env("FIBRIDGE_ID_REQUEST_TIMEOUT")
+             .value_name("MILLISECONDS")
+             .help("How long a hoster that didn't ask for an id when connecting has to send claimId (default 200, 0 turns claimId off)")
+             .takes_value(true))
+        .arg(Arg::with_name("cache-max-file-size")
+             .long("cache-max-file-size")
+             .env("FIBRIDGE_CACHE_MAX_FILE_SIZE")
+             .value_name("BYTES")
+             .help("Keep downloaded files up to this size in memory")
+             .takes_value(true))
+        .arg(Arg::with_name("cors-allowed-origin")
+             .long("cors-allowed-origin")
+             .value_name("ORIGIN")
+             .help("Allow scripts on this origin to fetch downloads. Can be repeated")
+             .takes_value(true)
+             .multiple(true)
+             .number_of_values(1))
+        .arg(Arg::with_name("admin-address")
+             .long("admin-address")
+             .env("FIBRIDGE_ADMIN_ADDRESS")
+             .value_name("ADMIN_ADDRESS")
+             .help("Serve the admin API on this address, e.g. 127.0.0.1:9003")
+             .requires("admin-token-file")
+             .takes_value(true))
+        .arg(Arg::with_name("admin-token-file")
+             .long("admin-token-file")
+             .env("FIBRIDGE_ADMIN_TOKEN_FILE")
+             .value_name("ADMIN_TOKEN_FILE")
+             .help("File containing the bearer token required by the admin API")
+             .takes_value(true))
+        .arg(Arg::with_name("access-log")
+             .long("access-log")
+             .env("FIBRIDGE_ACCESS_LOG")
+             .value_name("ACCESS_LOG")
+             .help("Where to write the access log: stdout (default) or a file path")
+             .takes_value(true))
+        .arg(Arg::with_name("access-log-format")
+             .long("access-log-format")
+             .env("FIBRIDGE_ACCESS_LOG_FORMAT")
+             .value_name("ACCESS_LOG_FORMAT")
+             .help("combined (default) or json")
+             .takes_value(true))
+        .arg(Arg::with_name("access-log-max-size")
+             .long("access-log-max-size")
+             .env("FIBRIDGE_ACCESS_LOG_MAX_SIZE")
+             .value_name("MEGABYTES")
+             .help("Rotate the access log file once it reaches this size")
+             .takes_value(true))
+        .arg(Arg::with_name("access-log-max-files")
+             .long("access-log-max-files")
+             .env("FIBRIDGE_ACCESS_LOG_MAX_FILES")
+             .value_name("COUNT")
+             .help("Number of rotated access log files to keep")
+             .takes_value(true))
+        .arg(Arg::with_name("log-level")
+             .long("log-level")
+             .env("FIBRIDGE_LOG")
+             .value_name("LOG_LEVEL")
+             .help("Log verbosity, e.g. info or fibridge_proxy_rs=debug. Defaults to $FIBRIDGE_LOG, then the config file, then $RUST_LOG, then info")
+             .takes_value(true))
         .arg(Arg::with_name("key")
              .long("key")
+             .env("FIBRIDGE_KEY")
              .value_name("TLS_KEY")
              .takes_value(true))
         .arg(Arg::with_name("cert")
              .long("cert")
+             .env("FIBRIDGE_CERT")
              .value_name("TLS_CERT")
              .takes_value(true))
+        .arg(Arg::with_name("sni-cert")
+             .long("sni-cert")
+             .value_name("NAMES:CERT:KEY")
+             .help("An extra certificate for the given comma separated names, e.g. example.com,*.example.com:cert.pem:key.pem. Can be repeated")
+             .takes_value(true)
+             .multiple(true)
+             .number_of_values(1))
+        .arg(Arg::with_name("tls-reload-interval")
+             .long("tls-reload-interval")
+             .env("FIBRIDGE_TLS_RELOAD_INTERVAL")
+             .value_name("SECONDS")
+             .help("How often to check certificate files for changes. 0 only reloads on SIGHUP. Defaults to 60")
+             .takes_value(true))
+        .arg(Arg::with_name("acme")
+             .long("acme")
+             .help("Obtain and renew a certificate for --host automatically, instead of using --key and --cert"))
+        .arg(Arg::with_name("acme-email")
+             .long("acme-email")
+             .env("FIBRIDGE_ACME_EMAIL")
+             .value_name("EMAIL")
+             .help("Contact address for the ACME account")
+             .takes_value(true))
+        .arg(Arg::with_name("acme-directory-url")
+             .long("acme-directory-url")
+             .env("FIBRIDGE_ACME_DIRECTORY_URL")
+             .value_name("URL")
+             .help("ACME server directory. Defaults to Let's Encrypt")
+             .takes_value(true))
+        .arg(Arg::with_name("acme-state-dir")
+             .long("acme-state-dir")
+             .env("FIBRIDGE_ACME_STATE_DIR")
+             .value_name("DIR")
+             .help("Where the ACME account and certificates are kept. Defaults to ./acme")
+             .takes_value(true))
         .arg(Arg::with_name("secure-port")
              .long("secure-port")
+             .env("FIBRIDGE_SECURE_PORT")
              .value_name("SECURE_PORT")
              .takes_value(true))
         .get_matches();
 
-    let port = matches.value_of("port").unwrap_or("9001");
-    let ip = matches.value_of("ip").unwrap_or("127.0.0.1");
-    let id_type = matches.value_of("id-type").unwrap_or("short-code");
-    let addr = format!("{}:{}", ip, port);
-
-    let hoster_managers = Arc::new(Mutex::new(HashMap::new()));
-    let hoster_managers_clone = hoster_managers.clone();
-    let range_clone = hoster_managers.clone();
-    let done_clone = hoster_managers.clone();
-
-    let (done_tx, done_rx) = mpsc::unbounded::<String>();
-
-    let done_stream = done_rx.for_each(move |done_id| {
-        done_clone.lock().expect("get lock").remove(&done_id);
-        Ok(())
-    }).map_err(|_| ());
-
-    let id_generator = Arc::new(create_generator(id_type));
-
-    let omnis = warp::path("omnistreams")
-        .map(move || hoster_managers.clone())
-        .and(warp::ws2())
-        .map(move |hoster_managers: HosterManagers, ws: warp::ws::Ws2| {
-
-            let done_tx = done_tx.clone();
-            let id_generator = id_generator.clone();
-
-            ws.on_upgrade(move |socket| {
-                
-                let mut id = id_generator.gen();
-
-                // TODO: this is pretty hacky
-                let mut id_attempts = 0;
-                while hoster_managers.lock().expect("get lock").get(&id).is_some() {
-                    id = id_generator.gen();
-                    id_attempts += 1;
-                    if id_attempts > 1000 {
-                        panic!("Out of ids");
-                    }
-                }
-
-                let hoster = HosterManager::new(id.to_string(), socket, done_tx);
-
-                hoster_managers.lock().expect("get lock").insert(hoster.id(), hoster);
+    let path = matches.value_of("config");
 
-                // TODO: eventually need to actually remove the old ones
-                dbg!(hoster_managers.lock().expect("get lock").keys());
+    let mut config = match path {
+        Some(path) => Config::from_file(path).unwrap_or_else(|errors| exit_with_errors(Some(path), errors)),
+        None => Config::default(),
+    };
 
-                futures::future::ok(())
-            })
-        });
-
-    // TODO: reduce duplication with non_ranged below
-    let ranged = warp::header::<String>("Range")
-        .and(warp::path::param())
-        .and(warp::path::param())
-        .and_then(move |range, id: String, filename: String| {
-            let mut lock = range_clone.lock().expect("get lock");
-
-            println!("GET /{}/{} {}", id, filename, range);
-
-            match lock.get_mut(&id) {
-                Some(manager) => {
-                    Either::A(manager.process_request(filename, range)
-                        .map_err(|_e| warp::reject::not_found()))
-                },
-                None => {
-                    // TODO: This still feels super hacky. There's got to be some way to have these
-                    // all be part of the same future.
-                    Either::B(futures::future::ok(Response::builder()
-                            .status(404)
-                            .body("Not found".into())
-                            .expect("error response")))
-                },
-            }
-        });
-
-    let non_ranged = warp::path::param()
-        .and(warp::path::param())
-        .and_then(move |id: String, filename: String| {
-
-            println!("GET /{}/{}", id, filename);
-
-            let mut lock = hoster_managers_clone.lock().expect("get lock");
-
-            match lock.get_mut(&id) {
-                Some(manager) => {
-                    Either::A(manager.process_request(filename, "".to_string())
-                        .map_err(|_e| warp::reject::not_found()))
-                },
-                None => {
-                    // TODO: This still feels super hacky. There's got to be some way to have these
-                    // all be part of the same future.
-                    Either::B(futures::future::ok(Response::builder()
-                            .status(404)
-                            .body("Not found".into())
-                            .expect("error response")))
-                },
-            }
-        });
-
-    let download = warp::get2().and(ranged.or(non_ranged));
-
-    let index = warp::path::end().map(|| {
-        warp::reply::html(include_str!("../../fibridge-gui-js/dist/index.html"))
-    });
+    if let Err(errors) = config.apply_args(&matches) {
+        exit_with_errors(None, errors);
+    }
 
-    let routes = index
-        .or(omnis)
-        .or(download);
+    if let Err(errors) = config.validate() {
+        exit_with_errors(path, errors);
+    }
 
+    let log_level = config.logging.filter(env::var("RUST_LOG").ok());
 
-    let key = matches.value_of("key");
-    let cert = matches.value_of("cert");
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::try_new(&log_level).expect("parse log level"))
+        .init();
 
+    let server = ProxyServerBuilder::from_config(&config)
+        .unwrap_or_else(|e| exit_with_error(e))
+        .build();
 
-    if key.is_some() && cert.is_some() {
+    let server = server.run().unwrap_or_else(|e| exit_with_error(e));
 
-        let secure_port = matches.value_of("secure-port").unwrap_or("9002");
-        
-        // if host was specified, use that value, otherwise use the ip
-        let host = matches.value_of("host").unwrap_or(ip);
-        let secure_redirect_link = format!("{}:{}", host, secure_port);
+    rt::run(server);
+}
 
-        // redirect http to https
-        let http_routes = warp::path::full().map(move |path: FullPath| {
-            let uri = Uri::builder()
-                .scheme("https")
-                .authority(secure_redirect_link.as_str())
-                .path_and_query(path.as_str())
-                .build()
-                .expect("parse uri");
-            warp::redirect(uri) 
-        });
-        let http_server_future = warp::serve(http_routes)
-            .bind(addr.parse::<SocketAddrV4>()
-            .expect("parse address"));
+fn exit_with_error(e: io::Error) -> ! {
+    eprintln!("{}", e);
+    process::exit(1);
+}
 
-        let secure_addr = format!("{}:{}", ip, secure_port);
-        let https_server_future = warp::serve(routes)
-            .tls(cert.unwrap(), key.unwrap())
-            .bind(secure_addr.parse::<SocketAddrV4>().expect("parse address"));
-        rt::run(rt::lazy(|| {
-            rt::spawn(done_stream);
-            rt::spawn(http_server_future);
-            rt::spawn(https_server_future);
-            Ok(())
-        }));
-    }
-    else {
-        let server_future = warp::serve(routes).bind(addr.parse::<SocketAddrV4>().expect("parse address"));
-        rt::run(rt::lazy(|| {
-            rt::spawn(done_stream);
-            rt::spawn(server_future);
-            Ok(())
-        }));
+fn exit_with_errors(path: Option<&str>, errors: Vec<ConfigError>) -> ! {
+    for e in errors {
+        match path {
+            Some(path) => eprintln!("{}: {}", path, e),
+            None => eprintln!("{}", e),
+        }
     }
+    process::exit(1);
 }
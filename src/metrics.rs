@@ -0,0 +1,274 @@
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use crate::transfer_stats::{TransferStats, TransferOutcome, as_secs_f64};
+
+
+// Upper bounds in seconds
+const TTFB_BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+struct Histogram {
+    counts: [u64; 11],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new() -> Self {
+        Self {
+            counts: [0; 11],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        for (i, bound) in TTFB_BUCKETS.iter().enumerate() {
+            if value <= *bound {
+                self.counts[i] += 1;
+            }
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+// Process wide telemetry, rendered in the Prometheus text format. Per hoster
+// numbers are read from the connected HosterManagers at render time so
+// disconnected hosters don't linger as stale series.
+pub struct Metrics {
+    active_conduits: AtomicUsize,
+    bytes_streamed: AtomicUsize,
+    cache_hits: AtomicUsize,
+    cache_misses: AtomicUsize,
+    cancellations: AtomicUsize,
+    errors: AtomicUsize,
+    requests_by_status: Mutex<BTreeMap<u16, u64>>,
+    time_to_first_byte: Mutex<Histogram>,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self {
+            active_conduits: AtomicUsize::new(0),
+            bytes_streamed: AtomicUsize::new(0),
+            cache_hits: AtomicUsize::new(0),
+            cache_misses: AtomicUsize::new(0),
+            cancellations: AtomicUsize::new(0),
+            errors: AtomicUsize::new(0),
+            requests_by_status: Mutex::new(BTreeMap::new()),
+            time_to_first_byte: Mutex::new(Histogram::new()),
+        }
+    }
+
+    pub fn conduit_opened(&self) {
+        self.active_conduits.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub fn conduit_closed(&self) {
+        self.active_conduits.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    // Subscribed to the TransferAggregator
+    pub fn transfer_finished(&self, stats: &TransferStats) {
+        match stats.outcome {
+            TransferOutcome::Completed => (),
+            TransferOutcome::Cancelled => {
+                self.cancellations.fetch_add(1, Ordering::SeqCst);
+            },
+            TransferOutcome::Errored => {
+                self.errors.fetch_add(1, Ordering::SeqCst);
+            },
+        }
+
+        if let Some(time_to_first_byte) = stats.time_to_first_byte {
+            self.time_to_first_byte.lock().expect("lock").observe(as_secs_f64(time_to_first_byte));
+        }
+    }
+
+    pub fn bytes_streamed(&self, num_bytes: usize) {
+        self.bytes_streamed.fetch_add(num_bytes, Ordering::SeqCst);
+    }
+
+    pub fn cache_lookup(&self, hit: bool) {
+        if hit {
+            self.cache_hits.fetch_add(1, Ordering::SeqCst);
+        }
+        else {
+            self.cache_misses.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    pub fn request_completed(&self, status: u16) {
+        *self.requests_by_status.lock().expect("lock").entry(status).or_insert(0) += 1;
+    }
+
+    // hosters is (id, bytes served) for every connected hoster
+    pub fn render(&self, hosters: &[(String, usize)]) -> String {
+        let mut out = String::new();
+
+        let cache_hits = self.cache_hits.load(Ordering::SeqCst);
+        let cache_misses = self.cache_misses.load(Ordering::SeqCst);
+        let cache_hit_ratio = if cache_hits + cache_misses > 0 {
+            cache_hits as f64 / (cache_hits + cache_misses) as f64
+        }
+        else {
+            0.0
+        };
+
+        metric(&mut out, "fibridge_connected_hosters", "gauge",
+            "Number of connected hosters", hosters.len());
+        metric(&mut out, "fibridge_active_conduits", "gauge",
+            "Number of transfers currently streaming", self.active_conduits.load(Ordering::SeqCst));
+        metric(&mut out, "fibridge_bytes_streamed_total", "counter",
+            "Bytes streamed to HTTP clients", self.bytes_streamed.load(Ordering::SeqCst));
+
+        header(&mut out, "fibridge_hoster_bytes_streamed_total", "counter",
+            "Bytes streamed per connected hoster");
+        for (id, bytes) in hosters {
+            writeln!(out, "fibridge_hoster_bytes_streamed_total{{hoster=\"{}\"}} {}",
+                escape_label(id), bytes).expect("write");
+        }
+
+        header(&mut out, "fibridge_requests_total", "counter", "Download requests by status");
+        for (status, count) in self.requests_by_status.lock().expect("lock").iter() {
+            writeln!(out, "fibridge_requests_total{{status=\"{}\"}} {}", status, count).expect("write");
+        }
+
+        metric(&mut out, "fibridge_cache_hits_total", "counter",
+            "Requests served from the cache", cache_hits);
+        metric(&mut out, "fibridge_cache_misses_total", "counter",
+            "Cacheable requests forwarded to the hoster", cache_misses);
+        metric(&mut out, "fibridge_cache_hit_ratio", "gauge",
+            "Cache hits over cacheable requests", cache_hit_ratio);
+        metric(&mut out, "fibridge_cancellations_total", "counter",
+            "Transfers cancelled before completing", self.cancellations.load(Ordering::SeqCst));
+        metric(&mut out, "fibridge_transfer_errors_total", "counter",
+            "Transfers that failed, e.g. because the hoster disconnected", self.errors.load(Ordering::SeqCst));
+
+        let histogram = self.time_to_first_byte.lock().expect("lock");
+        let name = "fibridge_time_to_first_byte_seconds";
+        header(&mut out, name, "histogram", "Time from request to the first byte from the hoster");
+        for (bound, count) in TTFB_BUCKETS.iter().zip(histogram.counts.iter()) {
+            writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, count).expect("write");
+        }
+        writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, histogram.count).expect("write");
+        writeln!(out, "{}_sum {}", name, histogram.sum).expect("write");
+        writeln!(out, "{}_count {}", name, histogram.count).expect("write");
+
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, metric_type: &str, help: &str) {
+    writeln!(out, "# HELP {} {}", name, help).expect("write");
+    writeln!(out, "# TYPE {} {}", name, metric_type).expect("write");
+}
+
+fn metric<T: std::fmt::Display>(out: &mut String, name: &str, metric_type: &str, help: &str, value: T) {
+    header(out, name, metric_type, help);
+    writeln!(out, "{} {}", name, value).expect("write");
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use warp::http::{HeaderMap, Version};
+    use crate::request_info::RequestInfo;
+
+    fn finished(outcome: TransferOutcome, time_to_first_byte: Option<Duration>) -> TransferStats {
+        TransferStats {
+            request_id: 0,
+            hoster_id: "my-files".to_string(),
+            status: 200,
+            request: RequestInfo::new("GET", "/my-files/file.txt".to_string(), Version::HTTP_11, None, &HeaderMap::new()),
+            bytes: 1024,
+            duration: Duration::from_secs(1),
+            time_to_first_byte,
+            outcome,
+        }
+    }
+
+    fn lines(rendered: &str) -> Vec<&str> {
+        rendered.lines().collect()
+    }
+
+    #[test]
+    fn renders_counters_with_help_and_type() {
+        let metrics = Metrics::new();
+        metrics.request_completed(200);
+        metrics.request_completed(200);
+        metrics.request_completed(404);
+        metrics.bytes_streamed(1000);
+        metrics.bytes_streamed(24);
+        metrics.cache_lookup(true);
+        metrics.cache_lookup(false);
+        metrics.transfer_finished(&finished(TransferOutcome::Cancelled, None));
+
+        let rendered = metrics.render(&[("my-files".to_string(), 1024)]);
+        let lines = lines(&rendered);
+
+        assert!(lines.contains(&"# HELP fibridge_bytes_streamed_total Bytes streamed to HTTP clients"));
+        assert!(lines.contains(&"# TYPE fibridge_bytes_streamed_total counter"));
+        assert!(lines.contains(&"fibridge_bytes_streamed_total 1024"));
+        assert!(lines.contains(&"# TYPE fibridge_connected_hosters gauge"));
+        assert!(lines.contains(&"fibridge_connected_hosters 1"));
+        assert!(lines.contains(&"fibridge_requests_total{status=\"200\"} 2"));
+        assert!(lines.contains(&"fibridge_requests_total{status=\"404\"} 1"));
+        assert!(lines.contains(&"fibridge_cache_hit_ratio 0.5"));
+        assert!(lines.contains(&"fibridge_cancellations_total 1"));
+        assert!(lines.contains(&"fibridge_transfer_errors_total 0"));
+        assert!(lines.contains(&"fibridge_hoster_bytes_streamed_total{hoster=\"my-files\"} 1024"));
+
+        // Every sample comes after its metric's HELP and TYPE
+        for line in lines.iter().filter(|line| !line.starts_with('#')) {
+            let name = line.split(|c| c == '{' || c == ' ').next().unwrap();
+            let name = name.trim_end_matches("_bucket").trim_end_matches("_sum").trim_end_matches("_count");
+            assert!(rendered.contains(&format!("# TYPE {} ", name)), "no TYPE for {}", line);
+        }
+    }
+
+    #[test]
+    fn renders_a_cumulative_histogram() {
+        let metrics = Metrics::new();
+        metrics.transfer_finished(&finished(TransferOutcome::Completed, Some(Duration::from_millis(250))));
+        metrics.transfer_finished(&finished(TransferOutcome::Completed, Some(Duration::from_nanos(7_812_500))));
+        metrics.transfer_finished(&finished(TransferOutcome::Completed, Some(Duration::from_secs(20))));
+        metrics.transfer_finished(&finished(TransferOutcome::Errored, None));
+
+        let rendered = metrics.render(&[]);
+        let lines = lines(&rendered);
+        let name = "fibridge_time_to_first_byte_seconds";
+
+        assert!(lines.contains(&format!("# TYPE {} histogram", name).as_str()));
+        assert!(lines.contains(&format!("{}_bucket{{le=\"0.005\"}} 0", name).as_str()));
+        assert!(lines.contains(&format!("{}_bucket{{le=\"0.01\"}} 1", name).as_str()));
+        assert!(lines.contains(&format!("{}_bucket{{le=\"0.1\"}} 1", name).as_str()));
+        assert!(lines.contains(&format!("{}_bucket{{le=\"0.25\"}} 2", name).as_str()));
+        assert!(lines.contains(&format!("{}_bucket{{le=\"10\"}} 2", name).as_str()));
+        assert!(lines.contains(&format!("{}_bucket{{le=\"+Inf\"}} 3", name).as_str()));
+        assert!(lines.contains(&format!("{}_sum 20.2578125", name).as_str()));
+        assert!(lines.contains(&format!("{}_count 3", name).as_str()));
+        assert!(lines.contains(&"fibridge_transfer_errors_total 1"));
+    }
+
+    #[test]
+    fn escapes_hoster_ids_in_labels() {
+        let metrics = Metrics::new();
+        let rendered = metrics.render(&[("a\"b\\c\nd".to_string(), 1)]);
+
+        assert!(rendered.lines().any(|line| line == "fibridge_hoster_bytes_streamed_total{hoster=\"a\\\"b\\\\c\\nd\"} 1"));
+    }
+}
@@ -0,0 +1,284 @@
+use std::io;
+use std::sync::Arc;
+use std::error::Error as StdError;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use futures::{Async, Future, Poll, Stream};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::io::AsyncRead;
+use tokio::timer::Timeout;
+use hyper::Body;
+use hyper::service::Service;
+use tracing::warn;
+use crate::connection;
+use crate::forwarded::Peer;
+
+
+const V2_SIGNATURE: [u8; 12] = [0x0D, 0x0A, 0x0D, 0x0A, 0x00, 0x0D, 0x0A, 0x51, 0x55, 0x49, 0x54, 0x0A];
+// "PROXY TCP6 <39 chars> <39 chars> 65535 65535\r\n"
+const V1_MAX_LENGTH: usize = 107;
+// Load balancers send the header as soon as they connect, so a connection
+// that hasn't is only holding a file descriptor.
+const HEADER_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Serves HTTP on a listener whose connections all start with a HAProxy PROXY
+// protocol (v1 or v2) header. The client address from the header stands in
+// for the connection's peer address.
+pub fn serve<F, S>(address: SocketAddr, new_service: F) -> io::Result<impl Future<Item = (), Error = ()> + Send>
+where
+    F: Fn() -> S + Send + Sync + 'static,
+    S: Service<ReqBody = Body, ResBody = Body> + 'static,
+    S::Future: Send + 'static,
+    S::Error: Into<Box<dyn StdError + Send + Sync>>,
+{
+    let listener = TcpListener::bind(&address)?;
+    let new_service = Arc::new(new_service);
+
+    let server = connection::Incoming::new(listener)
+        .for_each(move |(stream, peer): (TcpStream, SocketAddr)| {
+            let new_service = new_service.clone();
+
+            let connection = Timeout::new(ReadHeader::new(stream), HEADER_TIMEOUT)
+                .map_err(|e| {
+                    if e.is_elapsed() {
+                        warn!("No PROXY protocol header within {}s", HEADER_TIMEOUT.as_secs());
+                    }
+                    else if let Some(e) = e.into_inner() {
+                        warn!("Invalid PROXY protocol header: {}", e);
+                    }
+                    else {
+                        warn!("Failed to read PROXY protocol header");
+                    }
+                })
+                // LOCAL and UNKNOWN connections come from the load balancer
+                // itself
+                .and_then(move |(stream, client)| {
+                    connection::serve(stream, Some(Peer::Addr(client.unwrap_or(peer))), new_service)
+                });
+
+            hyper::rt::spawn(connection);
+            Ok(())
+        });
+
+    Ok(server)
+}
+
+enum Step {
+    Need(usize),
+    Done(Option<SocketAddr>),
+}
+
+// Reads exactly the PROXY header off the stream, leaving the HTTP request
+// that follows it unread.
+struct ReadHeader {
+    stream: Option<TcpStream>,
+    buf: Vec<u8>,
+    wanted: usize,
+}
+
+impl ReadHeader {
+    fn new(stream: TcpStream) -> Self {
+        Self {
+            stream: Some(stream),
+            buf: Vec::new(),
+            // Enough to tell v1 from v2, and shorter than any v1 header
+            wanted: V2_SIGNATURE.len(),
+        }
+    }
+
+    fn next_step(&self) -> io::Result<Step> {
+        let buf = &self.buf;
+
+        if buf[..V2_SIGNATURE.len()] == V2_SIGNATURE {
+            if buf.len() < 16 {
+                return Ok(Step::Need(16));
+            }
+
+            let length = 16 + ((buf[14] as usize) << 8 | buf[15] as usize);
+
+            if buf.len() < length {
+                Ok(Step::Need(length))
+            }
+            else {
+                parse_v2(buf).map(Step::Done)
+            }
+        }
+        else if buf.starts_with(b"PROXY ") {
+            if buf.ends_with(b"\r\n") {
+                parse_v1(buf).map(Step::Done)
+            }
+            else if buf.len() >= V1_MAX_LENGTH {
+                Err(invalid("v1 header too long"))
+            }
+            else {
+                // The end of the line can't be found without reading one
+                // byte at a time.
+                Ok(Step::Need(buf.len() + 1))
+            }
+        }
+        else {
+            Err(invalid("missing PROXY header"))
+        }
+    }
+}
+
+impl Future for ReadHeader {
+    type Item = (TcpStream, Option<SocketAddr>);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            while self.buf.len() < self.wanted {
+                let mut chunk = vec![0; self.wanted - self.buf.len()];
+                let stream = self.stream.as_mut().expect("poll after completion");
+
+                let read = match stream.poll_read(&mut chunk)? {
+                    Async::Ready(read) => read,
+                    Async::NotReady => return Ok(Async::NotReady),
+                };
+
+                if read == 0 {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed"));
+                }
+
+                self.buf.extend_from_slice(&chunk[..read]);
+            }
+
+            match self.next_step()? {
+                Step::Need(wanted) => self.wanted = wanted,
+                Step::Done(client) => {
+                    let stream = self.stream.take().expect("poll after completion");
+                    return Ok(Async::Ready((stream, client)));
+                },
+            }
+        }
+    }
+}
+
+// PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n
+fn parse_v1(header: &[u8]) -> io::Result<Option<SocketAddr>> {
+    let line = std::str::from_utf8(&header[..header.len() - 2]).map_err(|_| invalid("v1 header isn't text"))?;
+    let parts: Vec<&str> = line.split(' ').collect();
+
+    match parts.get(1) {
+        Some(&"TCP4") | Some(&"TCP6") if parts.len() == 6 => {
+            let ip = parts[2].parse::<IpAddr>().map_err(|_| invalid("bad v1 source address"))?;
+            let port = parts[4].parse::<u16>().map_err(|_| invalid("bad v1 source port"))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        },
+        // Health checks from the load balancer itself
+        Some(&"UNKNOWN") => Ok(None),
+        _ => Err(invalid("bad v1 header")),
+    }
+}
+
+fn parse_v2(header: &[u8]) -> io::Result<Option<SocketAddr>> {
+    let version = header[12] >> 4;
+    let command = header[12] & 0x0F;
+    let family = header[13] >> 4;
+    let addresses = &header[16..];
+
+    if version != 2 {
+        return Err(invalid("unsupported v2 version"));
+    }
+
+    // LOCAL connections come from the load balancer itself
+    if command == 0 {
+        return Ok(None);
+    }
+
+    let port = |offset: usize| (addresses[offset] as u16) << 8 | addresses[offset + 1] as u16;
+
+    match family {
+        // AF_INET
+        1 if addresses.len() >= 12 => {
+            let ip = Ipv4Addr::new(addresses[0], addresses[1], addresses[2], addresses[3]);
+            Ok(Some(SocketAddr::new(IpAddr::V4(ip), port(8))))
+        },
+        // AF_INET6
+        2 if addresses.len() >= 36 => {
+            let mut octets = [0; 16];
+            octets.copy_from_slice(&addresses[..16]);
+            Ok(Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(octets)), port(32))))
+        },
+        // AF_UNSPEC and AF_UNIX carry no usable address
+        0 | 3 => Ok(None),
+        _ => Err(invalid("bad v2 addresses")),
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(addr: &str) -> Option<SocketAddr> {
+        Some(addr.parse().unwrap())
+    }
+
+    fn v2(command: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.push(0x20 | command);
+        header.push(family << 4 | 1);
+        header.push((addresses.len() >> 8) as u8);
+        header.push(addresses.len() as u8);
+        header.extend_from_slice(addresses);
+        header
+    }
+
+    #[test]
+    fn v1() {
+        assert_eq!(parse_v1(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n").unwrap(), addr("192.0.2.1:56324"));
+        assert_eq!(parse_v1(b"PROXY TCP6 2001:db8::1 2001:db8::2 4711 443\r\n").unwrap(), addr("[2001:db8::1]:4711"));
+        assert_eq!(parse_v1(b"PROXY UNKNOWN\r\n").unwrap(), None);
+    }
+
+    #[test]
+    fn v1_invalid() {
+        for header in &[
+            &b"PROXY TCP4 192.0.2.1 198.51.100.1 56324\r\n"[..],
+            b"PROXY TCP4 nope 198.51.100.1 56324 443\r\n",
+            b"PROXY TCP4 192.0.2.1 198.51.100.1 99999 443\r\n",
+            b"PROXY UDP4 192.0.2.1 198.51.100.1 56324 443\r\n",
+            b"PROXY TCP4 \xff 198.51.100.1 56324 443\r\n",
+        ] {
+            assert!(parse_v1(header).is_err(), "{:?}", String::from_utf8_lossy(header));
+        }
+    }
+
+    #[test]
+    fn v2_inet() {
+        let header = v2(1, 1, &[192, 0, 2, 1, 198, 51, 100, 1, 0xDC, 0x04, 0x01, 0xBB]);
+        assert_eq!(parse_v2(&header).unwrap(), addr("192.0.2.1:56324"));
+    }
+
+    #[test]
+    fn v2_inet6() {
+        let mut addresses = vec![0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
+        addresses.extend_from_slice(&[0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2]);
+        addresses.extend_from_slice(&[0x12, 0x67, 0x01, 0xBB]);
+        assert_eq!(parse_v2(&v2(1, 2, &addresses)).unwrap(), addr("[2001:db8::1]:4711"));
+    }
+
+    #[test]
+    fn v2_without_address() {
+        // LOCAL, then AF_UNSPEC and AF_UNIX
+        assert_eq!(parse_v2(&v2(0, 1, &[])).unwrap(), None);
+        assert_eq!(parse_v2(&v2(1, 0, &[])).unwrap(), None);
+        assert_eq!(parse_v2(&v2(1, 3, &[0; 216])).unwrap(), None);
+    }
+
+    #[test]
+    fn v2_invalid() {
+        // Too short for AF_INET, an unknown family and version 1
+        assert!(parse_v2(&v2(1, 1, &[192, 0, 2, 1])).is_err());
+        assert!(parse_v2(&v2(1, 7, &[0; 12])).is_err());
+
+        let mut header = v2(1, 1, &[0; 12]);
+        header[12] = 0x11;
+        assert!(parse_v2(&header).is_err());
+    }
+}
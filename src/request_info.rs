@@ -0,0 +1,52 @@
+use std::net::SocketAddr;
+use std::time::Instant;
+use chrono::{DateTime, Local};
+use warp::{self, Filter};
+use warp::http::{HeaderMap, Version};
+
+
+// Who asked for what, captured when a download request arrives
+#[derive(Clone, Debug)]
+pub struct RequestInfo {
+    pub client_addr: Option<SocketAddr>,
+    pub method: String,
+    pub path: String,
+    pub version: Version,
+    pub user_agent: Option<String>,
+    pub referer: Option<String>,
+    pub received_at: DateTime<Local>,
+    pub started: Instant,
+}
+
+impl RequestInfo {
+    pub fn new(method: &str, path: String, version: Version, client_addr: Option<SocketAddr>, headers: &HeaderMap) -> Self {
+        let header = |name: &str| {
+            headers.get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(|value| value.to_string())
+        };
+
+        Self {
+            client_addr,
+            method: method.to_string(),
+            path,
+            version,
+            user_agent: header("user-agent"),
+            referer: header("referer"),
+            received_at: Local::now(),
+            started: Instant::now(),
+        }
+    }
+
+    pub fn client_ip(&self) -> Option<String> {
+        self.client_addr.map(|addr| addr.ip().to_string())
+    }
+}
+
+// The request's HTTP version, which connection::serve attaches to every
+// request. HTTP/1.1 for requests that didn't come through it.
+pub fn version() -> impl Filter<Extract = (Version,), Error = warp::Rejection> + Clone {
+    warp::ext::get::<Version>()
+        .or(warp::any().map(|| Version::HTTP_11))
+        .unify()
+}
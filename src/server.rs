@@ -0,0 +1,1438 @@
+use std::sync::{Arc, Mutex};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::fmt;
+use std::io;
+use std::time::Duration;
+use std::net::{SocketAddr, IpAddr};
+use warp::{self, Filter, Reply};
+use warp::filters::BoxedFilter;
+use warp::http::{HeaderMap, HeaderValue, Response, StatusCode, Uri, Version};
+use warp::path::{FullPath, Tail};
+use futures::{Future, Stream};
+use futures::sync::mpsc;
+use futures::future::{self, Either};
+use hyper::{rt, Body};
+use tracing::{info, warn, debug, info_span, field};
+use tracing_futures::Instrument;
+use serde_json::json;
+use crate::{HosterManagers, BlockedIds, HosterDomains, query_params};
+use crate::hoster_manager::{HosterManager, HosterConnection, HosterServices, HosterInfo, HosterDone};
+use crate::id_generator::{IdGenerator, IdRules, create_generator, allocate_id, IdConflictPolicy, IdError};
+use crate::auth::{HosterAuthenticator, AnyAuthenticator, ApiKeyAuthenticator, JwtAuthenticator, HosterGrant, AuthError, request_token};
+use crate::signed_url::UrlSigner;
+use crate::metrics::Metrics;
+use crate::access_log::{AccessLog, LogFormat};
+use crate::request_info::{self, RequestInfo};
+use crate::basic_auth::normalize_path;
+use crate::config::{Config, Listener, ListenerMode, ListenAddress, RedirectConfig, HstsConfig, GuiConfig, CacheConfig, TimeoutConfig};
+use crate::forwarded::{self, TrustedProxies, ForwardedInfo};
+use crate::tls::{self, CertResolver, CertFiles};
+use crate::acme::{self, AcmeClient};
+use crate::custom_domains::{self, DomainVerifier, DnsTxtVerifier, AllowlistVerifier};
+use crate::transfer_stats::{TransferAggregator, TransferOutcome};
+use crate::{admin, connection, proxy_protocol, static_files, transfer_feed};
+#[cfg(unix)]
+use crate::unix_socket;
+
+
+type ServerFuture = Box<dyn Future<Item = (), Error = ()> + Send>;
+
+// Everything the proxy can be configured with. Nothing is served until
+// listeners are added, or the routes are mounted in another warp server.
+pub struct ProxyServerBuilder {
+    listeners: Vec<Listener>,
+    id_generator: Box<dyn IdGenerator + Send + Sync>,
+    id_conflict: IdConflictPolicy,
+    max_cached_size: usize,
+    approval_timeout: Duration,
+    id_request_timeout: Duration,
+    authenticators: Vec<Box<dyn HosterAuthenticator + Send + Sync>>,
+    url_signer: Option<UrlSigner>,
+    access_log: Option<AccessLog>,
+    trusted_proxies: TrustedProxies,
+    cors_allowed_origins: Vec<String>,
+    route_prefix: Vec<String>,
+    websocket_path: Vec<String>,
+    hoster_domain: Option<String>,
+    domain_verifier: Option<Box<dyn DomainVerifier + Send + Sync>>,
+    host: String,
+    secure_port: u16,
+    redirect: RedirectConfig,
+    hsts: HstsConfig,
+    gui: GuiConfig,
+    sni_certificates: Vec<(Vec<String>, CertFiles)>,
+    tls_reload_interval: Option<Duration>,
+    acme: Option<AcmeClient>,
+    admin: Option<(SocketAddr, String)>,
+}
+
+impl Default for ProxyServerBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ProxyServerBuilder {
+    pub fn new() -> Self {
+        Self {
+            listeners: Vec::new(),
+            id_generator: create_generator("short-code"),
+            id_conflict: IdConflictPolicy::Fallback,
+            max_cached_size: CacheConfig::default().max_file_size,
+            approval_timeout: Duration::from_secs(TimeoutConfig::default().approval),
+            id_request_timeout: Duration::from_millis(TimeoutConfig::default().id_request_ms),
+            authenticators: Vec::new(),
+            url_signer: None,
+            access_log: None,
+            trusted_proxies: TrustedProxies::parse(&[]).expect("parse trusted proxies"),
+            cors_allowed_origins: Vec::new(),
+            route_prefix: Vec::new(),
+            websocket_path: vec!["omnistreams".to_string()],
+            hoster_domain: None,
+            domain_verifier: None,
+            host: "127.0.0.1".to_string(),
+            secure_port: 443,
+            redirect: RedirectConfig::default(),
+            hsts: HstsConfig::default(),
+            gui: GuiConfig::default(),
+            sni_certificates: Vec::new(),
+            tls_reload_interval: Some(Duration::from_secs(60)),
+            acme: None,
+            admin: None,
+        }
+    }
+
+    // Everything the command line and config file can set. Files named in
+    // the config are read here. The config should have been validated.
+    pub fn from_config(config: &Config) -> io::Result<Self> {
+        let mut builder = Self::new()
+            .listeners(config.listeners())
+            .id_generator(create_generator(&config.ids.id_type))
+            .id_conflict(config.ids.conflict.parse().map_err(invalid)?)
+            .max_cached_size(config.cache.max_file_size)
+            .approval_timeout(Duration::from_secs(config.timeouts.approval))
+            .id_request_timeout(Duration::from_millis(config.timeouts.id_request_ms))
+            .trusted_proxies(TrustedProxies::parse(&config.proxy.trusted).map_err(invalid)?)
+            .cors_allowed_origins(config.cors.allowed_origins.clone())
+            .route_prefix(&config.server.base_path)
+            .websocket_path(&config.server.websocket_path)
+            // if host was specified, use that value, otherwise use the ip
+            .host(config.server.host.clone().unwrap_or_else(|| config.server.ip.clone()))
+            .secure_port(config.server.secure_port)
+            .redirect(config.redirect.clone())
+            .hsts(config.hsts.clone())
+            .gui(config.gui.clone());
+
+        if let Some(domain) = &config.server.hoster_domain {
+            builder = builder.hoster_domain(domain);
+        }
+
+        match config.domains.verification.as_ref().map(|verification| verification.as_str()) {
+            Some("dns") => {
+                let verifier = DnsTxtVerifier::system().map_err(|e| failed("set up the DNS resolver", e))?;
+                builder = builder.domain_verifier(Box::new(verifier));
+            },
+            Some("allowlist") => {
+                builder = builder.domain_verifier(Box::new(AllowlistVerifier::new(config.domains.allowlist.clone())));
+            },
+            _ => (),
+        }
+
+        let auth = &config.auth;
+
+        if let Some(path) = &auth.api_keys {
+            let authenticator = ApiKeyAuthenticator::from_file(path).map_err(|e| failed(&format!("load API keys from {}", path), e))?;
+            builder = builder.authenticator(Box::new(authenticator));
+        }
+        if let Some(path) = &auth.jwt_secret {
+            let authenticator = JwtAuthenticator::from_secret_file(path).map_err(|e| failed(&format!("load JWT secret from {}", path), e))?;
+            builder = builder.authenticator(Box::new(authenticator));
+        }
+        if let Some(path) = &auth.jwt_public_key {
+            let authenticator = JwtAuthenticator::from_rsa_pem_file(path).map_err(|e| failed(&format!("load JWT public key from {}", path), e))?;
+            builder = builder.authenticator(Box::new(authenticator));
+        }
+        if let Some(path) = &auth.jwks {
+            let authenticator = JwtAuthenticator::from_jwks_file(path).map_err(|e| failed(&format!("load JWKS from {}", path), e))?;
+            builder = builder.authenticator(Box::new(authenticator));
+        }
+
+        if let Some(path) = &auth.url_signing_key {
+            let url_signer = UrlSigner::from_file(path).map_err(|e| failed(&format!("load URL signing key from {}", path), e))?;
+            builder = builder.url_signer(url_signer);
+        }
+
+        let logging = &config.logging;
+        let access_log_format = logging.access_log_format.parse::<LogFormat>().map_err(invalid)?;
+        let access_log = match logging.access_log.as_str() {
+            "stdout" => AccessLog::stdout(access_log_format),
+            path => {
+                AccessLog::file(access_log_format, path, logging.access_log_max_size * 1024 * 1024, logging.access_log_max_files)
+                    .map_err(|e| failed(&format!("open access log {}", path), e))?
+            },
+        };
+        builder = builder.access_log(access_log);
+
+        let sni_certificates = config.tls.certificates.iter()
+            .map(|certificate| {
+                let files = CertFiles { cert: certificate.cert.clone(), key: certificate.key.clone() };
+                (certificate.names.clone(), files)
+            })
+            .collect();
+        builder = builder
+            .sni_certificates(sni_certificates)
+            .tls_reload_interval(match config.tls.reload_interval {
+                0 => None,
+                seconds => Some(Duration::from_secs(seconds)),
+            });
+
+        if config.acme.enabled {
+            let host = config.server.host.clone().ok_or_else(|| invalid("acme requires a host"))?;
+            let email = config.acme.email.clone().ok_or_else(|| invalid("acme requires an email"))?;
+            builder = builder.acme(AcmeClient::new(host, email, config.acme.directory_url.clone(), &config.acme.state_dir, config.acme.renew_days));
+        }
+
+        if let Some(address) = &config.admin.address {
+            let token_path = config.admin.token_file.as_ref().ok_or_else(|| invalid("the admin address requires a token file"))?;
+            let token = fs::read_to_string(token_path).map_err(|e| failed(&format!("read admin token from {}", token_path), e))?;
+            let address = address.parse().map_err(|_| invalid(format!("Invalid admin address '{}'", address)))?;
+            builder = builder.admin(address, token.trim().to_string());
+        }
+
+        Ok(builder)
+    }
+
+    pub fn listener(mut self, listener: Listener) -> Self {
+        self.listeners.push(listener);
+        self
+    }
+
+    pub fn listeners(mut self, listeners: Vec<Listener>) -> Self {
+        self.listeners.extend(listeners);
+        self
+    }
+
+    pub fn id_generator(mut self, id_generator: Box<dyn IdGenerator + Send + Sync>) -> Self {
+        self.id_generator = id_generator;
+        self
+    }
+
+    pub fn id_conflict(mut self, id_conflict: IdConflictPolicy) -> Self {
+        self.id_conflict = id_conflict;
+        self
+    }
+
+    // Files up to this size are cached after the first download
+    pub fn max_cached_size(mut self, max_cached_size: usize) -> Self {
+        self.max_cached_size = max_cached_size;
+        self
+    }
+
+    pub fn approval_timeout(mut self, approval_timeout: Duration) -> Self {
+        self.approval_timeout = approval_timeout;
+        self
+    }
+
+    // How long a hoster that didn't ask for an id in the handshake has to
+    // send a claimId before it's given a generated one. Zero doesn't wait,
+    // which turns claimId off.
+    pub fn id_request_timeout(mut self, id_request_timeout: Duration) -> Self {
+        self.id_request_timeout = id_request_timeout;
+        self
+    }
+
+    // Hosters must present a token one of the authenticators accepts. With
+    // none, anyone can register a hoster.
+    pub fn authenticator(mut self, authenticator: Box<dyn HosterAuthenticator + Send + Sync>) -> Self {
+        self.authenticators.push(authenticator);
+        self
+    }
+
+    pub fn url_signer(mut self, url_signer: UrlSigner) -> Self {
+        self.url_signer = Some(url_signer);
+        self
+    }
+
+    // Defaults to stdout in Combined Log Format
+    pub fn access_log(mut self, access_log: AccessLog) -> Self {
+        self.access_log = Some(access_log);
+        self
+    }
+
+    pub fn trusted_proxies(mut self, trusted_proxies: TrustedProxies) -> Self {
+        self.trusted_proxies = trusted_proxies;
+        self
+    }
+
+    pub fn cors_allowed_origins(mut self, origins: Vec<String>) -> Self {
+        self.cors_allowed_origins = origins;
+        self
+    }
+
+    // Mounts every route under this path, e.g. "/fibridge" serves downloads
+    // at /fibridge/<id>/<file>. Signed links include it.
+    pub fn route_prefix(mut self, prefix: &str) -> Self {
+        self.route_prefix = segments(prefix);
+        self
+    }
+
+    // Where hosters connect, under the route prefix. Defaults to
+    // /omnistreams.
+    pub fn websocket_path(mut self, path: &str) -> Self {
+        self.websocket_path = segments(path);
+        self
+    }
+
+    // Serves each hoster's files at <id>.<domain> as well as at
+    // /<id>/<file>. Needs a wildcard DNS record, and certificate for TLS.
+    pub fn hoster_domain(mut self, domain: &str) -> Self {
+        self.hoster_domain = Some(domain.trim_start_matches("*.").trim_end_matches('.').to_lowercase());
+        self
+    }
+
+    // Lets hosters serve their files on domains of their own with the
+    // claimDomain control message, once verifier agrees they control them.
+    // DNS has to point the domain at the proxy.
+    pub fn domain_verifier(mut self, verifier: Box<dyn DomainVerifier + Send + Sync>) -> Self {
+        self.domain_verifier = Some(verifier);
+        self
+    }
+
+    // Where plain HTTP requests are redirected, when the client didn't come
+    // through a trusted proxy that says otherwise
+    pub fn host(mut self, host: String) -> Self {
+        self.host = host;
+        self
+    }
+
+    // The redirect port if there are no TLS listeners, e.g. when something
+    // in front terminates TLS
+    pub fn secure_port(mut self, secure_port: u16) -> Self {
+        self.secure_port = secure_port;
+        self
+    }
+
+    pub fn redirect(mut self, redirect: RedirectConfig) -> Self {
+        self.redirect = redirect;
+        self
+    }
+
+    pub fn hsts(mut self, hsts: HstsConfig) -> Self {
+        self.hsts = hsts;
+        self
+    }
+
+    pub fn gui(mut self, gui: GuiConfig) -> Self {
+        self.gui = gui;
+        self
+    }
+
+    pub fn sni_certificates(mut self, certificates: Vec<(Vec<String>, CertFiles)>) -> Self {
+        self.sni_certificates = certificates;
+        self
+    }
+
+    // None only reloads certificates on SIGHUP
+    pub fn tls_reload_interval(mut self, interval: Option<Duration>) -> Self {
+        self.tls_reload_interval = interval;
+        self
+    }
+
+    // TLS listeners using the client's certificate files wait for it to be
+    // issued rather than failing to start
+    pub fn acme(mut self, acme: AcmeClient) -> Self {
+        self.acme = Some(acme);
+        self
+    }
+
+    pub fn admin(mut self, address: SocketAddr, token: String) -> Self {
+        self.admin = Some((address, token));
+        self
+    }
+
+    pub fn build(self) -> ProxyServer {
+        let hoster_managers: HosterManagers = Arc::new(Mutex::new(HashMap::new()));
+        let blocked_ids: BlockedIds = Arc::new(Mutex::new(HashSet::new()));
+        let domains: HosterDomains = Arc::new(Mutex::new(HashMap::new()));
+        let metrics = Arc::new(Metrics::new());
+        let trusted_proxies = Arc::new(self.trusted_proxies);
+
+        let access_log = Arc::new(self.access_log.unwrap_or_else(|| AccessLog::stdout(LogFormat::Combined)));
+
+        // Completed, cancelled and failed transfers are reported once each by
+        // their StatsConduit.
+        let transfer_stats = Arc::new(TransferAggregator::new());
+
+        let metrics_subscriber = metrics.clone();
+        let metrics_stream = transfer_stats.subscribe_finished().for_each(move |stats| {
+            metrics_subscriber.transfer_finished(&stats);
+            Ok(())
+        });
+
+        let access_log_subscriber = access_log.clone();
+        let access_log_stream = transfer_stats.subscribe_finished().for_each(move |stats| {
+            debug!(
+                request_id = stats.request_id,
+                bytes = stats.bytes,
+                throughput = stats.throughput(),
+                outcome = stats.outcome.as_str(),
+                "transfer finished");
+            access_log_subscriber.log_transfer(&stats);
+            Ok(())
+        });
+
+        let (done_tx, done_rx) = mpsc::unbounded::<HosterDone>();
+
+        let done_clone = hoster_managers.clone();
+        let done_stream = done_rx.for_each(move |(done_id, session_token)| {
+            info!(hoster_id = %done_id, "hoster disconnected");
+
+            // The id may already belong to a hoster that connected since
+            let mut hoster_managers = done_clone.lock().expect("get lock");
+            let same_hoster = hoster_managers.get(&done_id)
+                .map(|manager| manager.check_session(&session_token))
+                .unwrap_or(false);
+
+            if same_hoster {
+                hoster_managers.remove(&done_id);
+            }
+
+            Ok(())
+        }).map_err(|_| ());
+
+        let base_path = join_path(&self.route_prefix);
+        let custom_domains_enabled = self.domain_verifier.is_some();
+
+        let services = HosterServices {
+            url_signer: self.url_signer.map(Arc::new),
+            metrics: metrics.clone(),
+            access_log,
+            transfer_stats: transfer_stats.clone(),
+            approval_timeout: self.approval_timeout,
+            max_cached_size: self.max_cached_size,
+            base_path: base_path.clone(),
+            domains: domains.clone(),
+            domain_verifier: self.domain_verifier.map(Arc::from),
+            host: custom_domains::normalize(&self.host),
+            hoster_domain: self.hoster_domain.clone(),
+        };
+
+        // No authenticators configured means anyone can register a hoster
+        let authenticator = if self.authenticators.is_empty() {
+            None
+        }
+        else {
+            Some(Arc::new(AnyAuthenticator::new(self.authenticators)))
+        };
+
+        let mut id_rules = id_rules(&self.websocket_path, self.gui.static_dir.as_ref().map(|dir| dir.as_str()));
+
+        if self.hoster_domain.is_some() {
+            id_rules.require_hostnames();
+        }
+
+        let registration = Registration {
+            hoster_managers: hoster_managers.clone(),
+            blocked_ids: blocked_ids.clone(),
+            id_generator: Arc::from(self.id_generator),
+            id_conflict: self.id_conflict,
+            id_rules: Arc::new(id_rules),
+            id_request_timeout: self.id_request_timeout,
+            authenticator,
+            done_tx,
+        };
+
+        let omnis = omnis_route(registration, services.clone(), trusted_proxies.clone(), path_segments(&self.websocket_path));
+
+        // /<id>/<file>, under the route prefix
+        let by_path = warp::path::param()
+            .and(warp::path::param())
+            .boxed();
+        let download = download_route(by_path, hoster_managers.clone(), services.clone(), metrics.clone(), trusted_proxies.clone(), &self.cors_allowed_origins);
+
+        // <id>.<hoster domain>/<file>, which gives every hoster its own
+        // origin. Such hosts belong entirely to their hoster, so nothing else
+        // is served on them.
+        let subdomain_download = self.hoster_domain.as_ref().map(|domain| {
+            let by_host = hoster_from_host(domain.clone(), trusted_proxies.clone())
+                .and(warp::path::tail().map(|tail: Tail| tail.as_str().to_string()))
+                .boxed();
+            download_route(by_host, hoster_managers.clone(), services.clone(), metrics.clone(), trusted_proxies.clone(), &self.cors_allowed_origins)
+        });
+
+        // Domains claimed by hosters, which belong to them the same way
+        let custom_domain_download = if custom_domains_enabled {
+            let by_domain = hoster_from_domain(domains.clone(), trusted_proxies.clone())
+                .and(warp::path::tail().map(|tail: Tail| tail.as_str().to_string()))
+                .boxed();
+            Some(download_route(by_domain, hoster_managers.clone(), services.clone(), metrics.clone(), trusted_proxies.clone(), &self.cors_allowed_origins))
+        }
+        else {
+            None
+        };
+
+        let index = static_files::routes(
+            self.gui.static_dir.as_ref().map(|dir| dir.as_str()),
+            self.gui.index_page.as_ref().map(|page| page.as_str()),
+            self.gui.cache_max_age);
+
+        let transfer_feed = transfer_feed::routes(hoster_managers.clone(), transfer_stats, path_segments(&self.websocket_path));
+
+        // How a GUI served from anywhere under the prefix finds the rest of
+        // the proxy. Ids can't start with a dot, so this can't shadow one.
+        let gui_config = json!({
+            "basePath": if base_path.is_empty() { "/".to_string() } else { base_path.clone() },
+            "websocketPath": format!("{}{}", base_path, join_path(&self.websocket_path)),
+            "hosterDomain": self.hoster_domain,
+        });
+        let gui_config = warp::get2()
+            .and(warp::path(".fibridge"))
+            .and(warp::path("config"))
+            .and(warp::path::end())
+            .map(move || warp::reply::json(&gui_config));
+
+        // Static files come before downloads, so a file shadows a hoster id
+        // with the same name
+        let routes = gui_config
+            .or(index)
+            .or(transfer_feed)
+            .or(omnis)
+            .or(download)
+            .map(|reply| Box::new(reply) as Box<dyn Reply>);
+
+        let routes = path_segments(&self.route_prefix)
+            .and(routes)
+            .boxed();
+
+        // Relative links in the GUI only resolve below the prefix if it ends
+        // in a slash
+        let routes = if base_path.is_empty() {
+            routes
+        }
+        else {
+            let bare_prefix = base_path.clone();
+            let location = format!("{}/", base_path);
+
+            warp::path::full()
+                .and_then(move |path: FullPath| {
+                    if path.as_str() == bare_prefix {
+                        let response = Response::builder()
+                            .status(StatusCode::MOVED_PERMANENTLY)
+                            .header("location", location.as_str())
+                            .body(Body::empty())
+                            .expect("build redirect");
+                        Ok(Box::new(response) as Box<dyn Reply>)
+                    }
+                    else {
+                        Err(warp::reject::not_found())
+                    }
+                })
+                .or(routes)
+                .unify()
+                .boxed()
+        };
+
+        let routes = match subdomain_download {
+            Some(subdomain_download) => subdomain_download.or(routes).unify().boxed(),
+            None => routes,
+        };
+
+        let routes = match custom_domain_download {
+            Some(custom_domain_download) => custom_domain_download.or(routes).unify().boxed(),
+            None => routes,
+        };
+
+        // Everything served over HTTPS gets the HSTS header, if there is one.
+        // TLS listeners add it to rejections too, with WithHsts.
+        let hsts = self.hsts.header_value();
+        let secure_routes_hsts = hsts.clone();
+        let secure_routes = routes.clone()
+            .map(move |reply| {
+                match &secure_routes_hsts {
+                    Some(hsts) => Box::new(warp::reply::with_header(reply, "strict-transport-security", hsts.as_str())) as Box<dyn Reply>,
+                    None => reply,
+                }
+            })
+            .boxed();
+
+        // Redirects go to the first TLS listener
+        let secure_port = self.listeners.iter()
+            .filter_map(|listener| {
+                match (&listener.address, &listener.mode) {
+                    (ListenAddress::Tcp(address), ListenerMode::Tls { .. }) => Some(address.port()),
+                    _ => None,
+                }
+            })
+            .next()
+            .unwrap_or(self.secure_port);
+
+        let acme_challenges = self.acme.as_ref()
+            .map(|client| client.challenges())
+            .unwrap_or_default();
+
+        let redirect_target = RedirectTarget {
+            host: &self.host,
+            secure_port,
+            config: &self.redirect,
+            hoster_domain: self.hoster_domain.clone(),
+            domains: domains.clone(),
+        };
+
+        let http_routes = http_routes(routes.clone(), secure_routes.clone(), hsts.clone(), redirect_target, acme_challenges, trusted_proxies);
+
+        let background = vec![
+            Box::new(done_stream) as ServerFuture,
+            Box::new(metrics_stream) as ServerFuture,
+            Box::new(access_log_stream) as ServerFuture,
+        ];
+
+        ProxyServer {
+            handle: ProxyHandle {
+                hoster_managers,
+                blocked_ids,
+                domains,
+                metrics,
+            },
+            routes,
+            secure_routes,
+            http_routes,
+            listeners: self.listeners,
+            background,
+            hsts: hsts.map(|hsts| HeaderValue::from_str(&hsts).expect("hsts header value")),
+            sni_certificates: self.sni_certificates,
+            tls_reload_interval: self.tls_reload_interval,
+            acme: self.acme,
+            admin: self.admin,
+        }
+    }
+}
+
+// A configured proxy, ready to serve
+pub struct ProxyServer {
+    handle: ProxyHandle,
+    routes: BoxedFilter<(Box<dyn Reply>,)>,
+    secure_routes: BoxedFilter<(Box<dyn Reply>,)>,
+    http_routes: BoxedFilter<(Box<dyn Reply>,)>,
+    hsts: Option<HeaderValue>,
+    listeners: Vec<Listener>,
+    background: Vec<ServerFuture>,
+    sni_certificates: Vec<(Vec<String>, CertFiles)>,
+    tls_reload_interval: Option<Duration>,
+    acme: Option<AcmeClient>,
+    admin: Option<(SocketAddr, String)>,
+}
+
+impl ProxyServer {
+    pub fn builder() -> ProxyServerBuilder {
+        ProxyServerBuilder::new()
+    }
+
+    pub fn handle(&self) -> ProxyHandle {
+        self.handle.clone()
+    }
+
+    // The GUI, hoster WebSocket, transfer feed and download routes, for
+    // serving from another warp server. run() must still be spawned, without
+    // listeners if need be, for hosters to be cleaned up when they leave.
+    pub fn routes(&self) -> BoxedFilter<(Box<dyn Reply>,)> {
+        self.routes.clone()
+    }
+
+    // routes(), plus HSTS if configured. Rejections don't get the header,
+    // so wrap the service in tls::WithHsts as well to cover them.
+    pub fn secure_routes(&self) -> BoxedFilter<(Box<dyn Reply>,)> {
+        self.secure_routes.clone()
+    }
+
+    // What plain HTTP redirect listeners serve: ACME challenges, plain
+    // paths, and redirects to HTTPS for everything else
+    pub fn http_routes(&self) -> BoxedFilter<(Box<dyn Reply>,)> {
+        self.http_routes.clone()
+    }
+
+    // Binds every listener and the admin API, then starts serving once
+    // spawned on a tokio runtime. Fails if an address can't be bound or a
+    // certificate can't be loaded.
+    pub fn run(self) -> io::Result<impl Future<Item = (), Error = ()> + Send> {
+        let routes = self.routes;
+        let http_routes = self.http_routes;
+        let hsts = self.hsts;
+        let sni_certificates = self.sni_certificates;
+        let acme_cert_files = self.acme.as_ref().map(|client| client.cert_files());
+
+        let mut cert_resolvers = Vec::new();
+        let mut servers = Vec::new();
+
+        // Every listener shares the same routes, and so the same hosters
+        for listener in self.listeners {
+            info!(address = %listener.address, mode = ?listener.mode, proxy_protocol = listener.proxy_protocol, "listening");
+
+            let proxy_protocol = listener.proxy_protocol;
+            let listen_address = listener.address.to_string();
+            let bind_failed = |e| failed(&format!("listen on {}", listen_address), e);
+
+            let server = match (listener.address, listener.mode) {
+                (ListenAddress::Tcp(address), ListenerMode::Plain) if proxy_protocol => {
+                    let routes = routes.clone();
+                    Box::new(proxy_protocol::serve(address, move || warp::service(routes.clone()))
+                        .map_err(bind_failed)?) as ServerFuture
+                },
+                (ListenAddress::Tcp(address), ListenerMode::Redirect) if proxy_protocol => {
+                    let http_routes = http_routes.clone();
+                    Box::new(proxy_protocol::serve(address, move || warp::service(http_routes.clone()))
+                        .map_err(bind_failed)?) as ServerFuture
+                },
+                (ListenAddress::Tcp(address), ListenerMode::Plain) => {
+                    let routes = routes.clone();
+                    Box::new(connection::listen(address, move || warp::service(routes.clone()))
+                        .map_err(bind_failed)?) as ServerFuture
+                },
+                (ListenAddress::Tcp(address), ListenerMode::Tls { key, cert }) => {
+                    let files = CertFiles { cert, key };
+                    let resolver = if acme_cert_files.as_ref() == Some(&files) {
+                        CertResolver::provisioned(files, &sni_certificates)
+                    }
+                    else {
+                        CertResolver::new(files, &sni_certificates)
+                    };
+                    let resolver = Arc::new(resolver.map_err(|e| failed("load certificates", e))?);
+                    cert_resolvers.push(resolver.clone());
+                    let routes = routes.clone();
+                    let hsts = hsts.clone();
+                    Box::new(tls::serve(address, resolver, move || tls::WithHsts::new(warp::service(routes.clone()), hsts.clone()))
+                        .map_err(bind_failed)?) as ServerFuture
+                },
+                (ListenAddress::Tcp(address), ListenerMode::Redirect) => {
+                    let http_routes = http_routes.clone();
+                    Box::new(connection::listen(address, move || warp::service(http_routes.clone()))
+                        .map_err(bind_failed)?) as ServerFuture
+                },
+                #[cfg(unix)]
+                (ListenAddress::Unix { path, permissions }, ListenerMode::Plain) => {
+                    let routes = routes.clone();
+                    Box::new(unix_socket::serve(&path, permissions, move || warp::service(routes.clone()))
+                        .map_err(bind_failed)?) as ServerFuture
+                },
+                #[cfg(unix)]
+                (ListenAddress::Unix { path, permissions }, ListenerMode::Redirect) => {
+                    let http_routes = http_routes.clone();
+                    Box::new(unix_socket::serve(&path, permissions, move || warp::service(http_routes.clone()))
+                        .map_err(bind_failed)?) as ServerFuture
+                },
+                #[cfg(unix)]
+                (ListenAddress::Unix { .. }, ListenerMode::Tls { .. }) => {
+                    return Err(invalid(format!("TLS isn't supported on unix sockets, such as {}", listen_address)));
+                },
+            };
+
+            servers.push(server);
+        }
+
+        if let Some((address, token)) = self.admin {
+            let handle = self.handle;
+            let admin_routes = admin::routes(handle.hoster_managers, handle.blocked_ids, handle.metrics, token);
+            let server = connection::listen(address, move || warp::service(admin_routes.clone()))
+                .map_err(|e| failed(&format!("listen on admin address {}", address), e))?;
+            servers.push(Box::new(server));
+        }
+
+        if let Some(acme) = self.acme {
+            acme.start(cert_resolvers.clone());
+        }
+
+        if !cert_resolvers.is_empty() {
+            servers.push(Box::new(tls::watch(cert_resolvers, self.tls_reload_interval)));
+        }
+
+        let background = self.background;
+
+        Ok(future::lazy(move || {
+            for future in background.into_iter().chain(servers) {
+                rt::spawn(future);
+            }
+            Ok(())
+        }))
+    }
+}
+
+// Shared access to a running proxy's hosters
+#[derive(Clone)]
+pub struct ProxyHandle {
+    hoster_managers: HosterManagers,
+    blocked_ids: BlockedIds,
+    domains: HosterDomains,
+    metrics: Arc<Metrics>,
+}
+
+impl ProxyHandle {
+    // The registry of connected hosters, keyed by id
+    pub fn hoster_managers(&self) -> HosterManagers {
+        self.hoster_managers.clone()
+    }
+
+    pub fn blocked_ids(&self) -> BlockedIds {
+        self.blocked_ids.clone()
+    }
+
+    // Custom domains claimed by connected hosters, mapped to their ids
+    pub fn domains(&self) -> HosterDomains {
+        self.domains.clone()
+    }
+
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.metrics.clone()
+    }
+
+    pub fn hosters(&self) -> Vec<HosterInfo> {
+        self.hoster_managers.lock().expect("get lock")
+            .values()
+            .map(|manager| manager.info())
+            .collect()
+    }
+
+    pub fn hoster(&self, id: &str) -> Option<HosterInfo> {
+        self.hoster_managers.lock().expect("get lock")
+            .get(id)
+            .map(|manager| manager.info())
+    }
+
+    // Returns whether the hoster was connected
+    pub fn disconnect(&self, id: &str) -> bool {
+        let removed = self.hoster_managers.lock().expect("get lock").remove(id);
+
+        match removed {
+            Some(manager) => {
+                manager.disconnect();
+                true
+            },
+            None => false,
+        }
+    }
+}
+
+// The host the request was for, from the Host header or the host a trusted
+// proxy says was asked for, lowercase and without the port. Empty if there's
+// neither.
+fn request_host(trusted_proxies: Arc<TrustedProxies>) -> BoxedFilter<(String,)> {
+    forwarded::forwarded(trusted_proxies)
+        .and(warp::header::optional::<String>("host"))
+        .map(|info: ForwardedInfo, host: Option<String>| {
+            custom_domains::normalize(&info.host.or(host).unwrap_or_default())
+        })
+        .boxed()
+}
+
+// Ids that would be shadowed by another route under the prefix. The hoster
+// WebSocket and transfer feed live under the websocket path, so
+// /<first segment>/<file> would never reach a hoster with that id. Static
+// files come before downloads too, but files added later aren't covered.
+fn id_rules(websocket_path: &[String], static_dir: Option<&str>) -> IdRules {
+    let mut rules = IdRules::new();
+
+    if let Some(segment) = websocket_path.first() {
+        rules.reserve(segment);
+    }
+
+    if let Some(static_dir) = static_dir {
+        match static_files::top_level_names(static_dir) {
+            Ok(names) => {
+                for name in names {
+                    rules.reserve(&name);
+                }
+            },
+            Err(e) => warn!(static_dir = %static_dir, "Failed to list static directory: {}", e),
+        }
+    }
+
+    rules
+}
+
+// Whether path is prefix or inside it, e.g. /public/a for /public but not
+// /publicity
+fn is_under(path: &str, prefix: &str) -> bool {
+    path == prefix ||
+        (path.starts_with(prefix) && (prefix.ends_with('/') || path[prefix.len()..].starts_with('/')))
+}
+
+// The hoster id from a Host like <id>.<domain> or <id>.<domain>:8443
+fn hoster_from_host(domain: String, trusted_proxies: Arc<TrustedProxies>) -> BoxedFilter<(String,)> {
+    let suffix = format!(".{}", domain);
+
+    request_host(trusted_proxies)
+        .and_then(move |host: String| {
+            subdomain_id(&host, &suffix)
+                .map(|id| id.to_string())
+                .ok_or_else(warp::reject::not_found)
+        })
+        .boxed()
+}
+
+fn subdomain_id<'a>(host: &'a str, suffix: &str) -> Option<&'a str> {
+    if host.ends_with(suffix) {
+        let id = &host[..host.len() - suffix.len()];
+        if !id.is_empty() && !id.contains('.') {
+            return Some(id);
+        }
+    }
+
+    None
+}
+
+// The id of the hoster that claimed the requested host
+fn hoster_from_domain(domains: HosterDomains, trusted_proxies: Arc<TrustedProxies>) -> BoxedFilter<(String,)> {
+    request_host(trusted_proxies)
+        .and_then(move |host: String| {
+            domains.lock().expect("get lock")
+                .get(&host)
+                .cloned()
+                .ok_or_else(warp::reject::not_found)
+        })
+        .boxed()
+}
+
+fn segments(path: &str) -> Vec<String> {
+    path.split('/')
+        .filter(|segment| !segment.is_empty())
+        .map(|segment| segment.to_string())
+        .collect()
+}
+
+// "/a/b" for ["a", "b"], and "" for none, so it can be put in front of
+// another path
+fn join_path(segments: &[String]) -> String {
+    segments.iter()
+        .map(|segment| format!("/{}", segment))
+        .collect()
+}
+
+// Matches and consumes a path of any number of segments
+fn path_segments(segments: &[String]) -> BoxedFilter<()> {
+    segments.iter().fold(warp::any().boxed(), |filter, segment| {
+        // warp::path only takes static strings. The prefix is set once at
+        // startup, so leaking it is fine.
+        let segment: &'static str = Box::leak(segment.clone().into_boxed_str());
+        filter.and(warp::path(segment)).boxed()
+    })
+}
+
+// Who may register, under which ids, and where registered hosters go
+#[derive(Clone)]
+struct Registration {
+    hoster_managers: HosterManagers,
+    blocked_ids: BlockedIds,
+    id_generator: Arc<dyn IdGenerator + Send + Sync>,
+    id_conflict: IdConflictPolicy,
+    id_rules: Arc<IdRules>,
+    id_request_timeout: Duration,
+    authenticator: Option<Arc<AnyAuthenticator>>,
+    done_tx: mpsc::UnboundedSender<HosterDone>,
+}
+
+fn omnis_route(
+    registration: Registration,
+    services: HosterServices,
+    trusted_proxies: Arc<TrustedProxies>,
+    websocket_path: BoxedFilter<()>,
+) -> impl Filter<Extract = (Box<dyn Reply>,), Error = warp::Rejection> + Clone {
+
+    let Registration {
+        hoster_managers,
+        blocked_ids: omnis_blocked_ids,
+        id_generator,
+        id_conflict,
+        id_rules,
+        id_request_timeout,
+        authenticator,
+        done_tx,
+    } = registration;
+
+    websocket_path
+        .map(move || hoster_managers.clone())
+        .and(query_params())
+        .and(warp::header::headers_cloned())
+        .and(forwarded::client_addr(trusted_proxies))
+        .and(warp::ws2())
+        .map(move |hoster_managers: HosterManagers, params: HashMap<String, String>, headers, remote_addr: Option<SocketAddr>, ws: warp::ws::Ws2| {
+
+            let done_tx = done_tx.clone();
+            let id_generator = id_generator.clone();
+            let id_rules = id_rules.clone();
+            let services = services.clone();
+
+            let grant = match &authenticator {
+                Some(authenticator) => {
+                    match request_token(&headers, &params) {
+                        Some(token) => authenticator.authenticate(&token),
+                        None => Err(AuthError::Missing),
+                    }
+                },
+                None => Ok(HosterGrant::default()),
+            };
+
+            let grant = match grant {
+                Ok(grant) => grant,
+                Err(e) => {
+                    return Box::new(warp::reply::with_status(e.to_string(), StatusCode::UNAUTHORIZED)) as Box<dyn Reply>;
+                },
+            };
+
+            let mut requested_id = params.get("id").map(|id| id.as_str());
+            let mut id_conflict = id_conflict;
+
+            // A grant bound to an id can only ever register that id
+            if let Some(allowed_id) = &grant.id {
+                if requested_id.is_some() && requested_id != Some(allowed_id.as_str()) {
+                    let message = format!("Not allowed to register id '{}'", requested_id.unwrap());
+                    return Box::new(warp::reply::with_status(message, StatusCode::FORBIDDEN)) as Box<dyn Reply>;
+                }
+                requested_id = Some(allowed_id.as_str());
+                id_conflict = IdConflictPolicy::Reject;
+            }
+
+            if let Some(requested_id) = requested_id {
+                if omnis_blocked_ids.lock().expect("get lock").contains(requested_id) {
+                    let message = format!("Id '{}' is blocked", requested_id);
+                    return Box::new(warp::reply::with_status(message, StatusCode::FORBIDDEN)) as Box<dyn Reply>;
+                }
+            }
+
+            let blocked_ids = omnis_blocked_ids.clone();
+
+            // Hosters are told up front if the id they asked for in the
+            // handshake can't be had
+            if let Some(requested_id) = requested_id {
+                let lock = hoster_managers.lock().expect("get lock");
+                let blocked = blocked_ids.lock().expect("get lock");
+                let id = allocate_id(&*id_generator, Some(requested_id), id_conflict, &id_rules, |id| {
+                    lock.contains_key(id) || blocked.contains(id)
+                });
+
+                if let Err(e) = id {
+                    let status = match e {
+                        IdError::Exhausted => StatusCode::SERVICE_UNAVAILABLE,
+                        IdError::Taken(_) => StatusCode::CONFLICT,
+                        _ => StatusCode::BAD_REQUEST,
+                    };
+                    return Box::new(warp::reply::with_status(e.to_string(), status)) as Box<dyn Reply>;
+                }
+            }
+
+            let requested_id = requested_id.map(|id| id.to_string());
+
+            Box::new(ws.on_upgrade(move |socket| {
+
+                let connection = HosterConnection::new(socket);
+
+                // Without an id in the handshake, the hoster can still ask for
+                // one in its first control message. Hosters that gave one, or
+                // whose credentials pick it, get their setId straight away.
+                let id_request = match requested_id {
+                    Some(_) => Either::A(future::ok((None, connection))),
+                    None if id_request_timeout == Duration::from_secs(0) => Either::A(future::ok((None, connection))),
+                    None => Either::B(connection.id_request(id_request_timeout)),
+                };
+
+                id_request.map(move |(id_request, mut connection)| {
+
+                    let requested_id = requested_id.or_else(|| id_request.as_ref().map(|request| request.id.clone()));
+
+                    let mut lock = hoster_managers.lock().expect("get lock");
+
+                    // Someone else may have grabbed the id while the upgrade
+                    // was in flight, in which case Reject turns the hoster
+                    // away after all
+                    let id = {
+                        let blocked = blocked_ids.lock().expect("get lock");
+                        allocate_id(&*id_generator, requested_id.as_ref().map(|id| id.as_str()), id_conflict, &id_rules, |id| {
+                            lock.contains_key(id) || blocked.contains(id)
+                        })
+                    };
+
+                    let id = match id {
+                        Ok(id) => id,
+                        Err(e) => {
+                            warn!("{}", e);
+                            if let Some(id_request) = &id_request {
+                                connection.respond(id_request, Err(e.to_string()));
+                            }
+                            connection.close();
+                            return;
+                        },
+                    };
+
+                    if let Some(id_request) = &id_request {
+                        connection.respond(id_request, Ok(id.as_str()));
+                    }
+
+                    let hoster = HosterManager::new(id, connection, done_tx, grant, remote_addr, services);
+
+                    info!(hoster_id = %hoster.id(), "hoster connected");
+
+                    lock.insert(hoster.id(), hoster);
+
+                    debug!(hosters = ?lock.keys().collect::<Vec<_>>(), "connected hosters");
+                })
+            })) as Box<dyn Reply>
+        })
+}
+
+// Downloads from whichever hoster and file target picks out of the request
+fn download_route(
+    target: BoxedFilter<(String, String)>,
+    hoster_managers: HosterManagers,
+    services: HosterServices,
+    metrics: Arc<Metrics>,
+    trusted_proxies: Arc<TrustedProxies>,
+    cors_allowed_origins: &[String],
+) -> BoxedFilter<(Box<dyn Reply>,)> {
+
+    let download = warp::get2()
+        .and(warp::path::full())
+        .and(target)
+        .and(query_params())
+        .and(warp::header::headers_cloned())
+        .and(forwarded::client_addr(trusted_proxies))
+        .and(request_info::version())
+        .and_then(move |path: FullPath, id: String, filename: String, params: HashMap<String, String>, headers: HeaderMap, remote_addr: Option<SocketAddr>, version: Version| {
+
+            let metrics = metrics.clone();
+            let access_log = services.access_log.clone();
+
+            let span = info_span!("request",
+                hoster_id = %id,
+                path = %path.as_str(),
+                request_id = field::Empty);
+
+            let request_info = RequestInfo::new("GET", path.as_str().to_string(), version, remote_addr, &headers);
+            let log_id = id.clone();
+            let log_request = request_info.clone();
+
+            let response = span.in_scope(|| {
+                handle_download(&hoster_managers, &services, id, filename, params, headers, request_info)
+            });
+
+            response
+                .then(move |result| {
+                    match &result {
+                        Ok(response) => metrics.request_completed(response.status().as_u16()),
+                        // The hoster went away before responding
+                        Err(_) => {
+                            metrics.request_completed(404);
+                            access_log.log(&log_id, &log_request, 404, 0, TransferOutcome::Errored);
+                        },
+                    }
+                    result
+                })
+                .instrument(span)
+        });
+
+    // Only downloads are offered to other origins. Hosters and the GUI don't
+    // need it.
+    if cors_allowed_origins.is_empty() {
+        download.map(|response| Box::new(response) as Box<dyn Reply>).boxed()
+    }
+    else {
+        let cors = warp::cors()
+            .allow_origins(cors_allowed_origins.iter().map(|origin| origin.as_str()))
+            .allow_methods(vec!["GET"])
+            .allow_headers(vec!["authorization", "range"]);
+
+        download.with(cors).map(|reply| Box::new(reply) as Box<dyn Reply>).boxed()
+    }
+}
+
+fn handle_download(
+    hoster_managers: &HosterManagers,
+    services: &HosterServices,
+    id: String,
+    filename: String,
+    params: HashMap<String, String>,
+    headers: HeaderMap,
+    request_info: RequestInfo,
+) -> impl Future<Item = Response<Body>, Error = warp::Rejection> {
+
+    let range = headers.get("range")
+        .and_then(|range| range.to_str().ok())
+        .unwrap_or("")
+        .to_string();
+
+    // Links to files with spaces or slashes in their names come percent
+    // encoded. Everything from here on, from signatures to the hoster's
+    // credential rules, sees the decoded and normalized name.
+    let filename = normalize_path(&filename)[1..].to_string();
+
+    let error_response = |status: u16, body: String| {
+        services.access_log.log(&id, &request_info, status, body.len(), TransferOutcome::Completed);
+        Response::builder()
+            .status(status)
+            .body(body.into())
+            .expect("error response")
+    };
+
+    // GET / on a hoster's own host names no file. Nothing else is served
+    // there, so it's not found rather than the GUI.
+    if filename.is_empty() {
+        return Either::B(futures::future::ok(error_response(404, "Not found".to_string())));
+    }
+
+    if let Some(url_signer) = &services.url_signer {
+        if let Err(e) = url_signer.verify(&id, &filename, &params) {
+            return Either::B(futures::future::ok(error_response(403, e.to_string())));
+        }
+    }
+
+    let mut lock = hoster_managers.lock().expect("get lock");
+
+    match lock.get_mut(&id) {
+        Some(manager) => {
+            let authorization = headers.get("authorization")
+                .and_then(|authorization| authorization.to_str().ok());
+
+            if !manager.authorize(&filename, authorization) {
+                let mut response = error_response(401, "Unauthorized".to_string());
+                let challenge = format!("Basic realm=\"{}\"", id);
+                response.headers_mut().insert("WWW-Authenticate", challenge.parse().expect("header value"));
+                return Either::B(futures::future::ok(response));
+            }
+
+            Either::A(manager.process_request(filename, range, request_info.clone())
+                .map_err(|_e| warp::reject::not_found()))
+        },
+        None => {
+            // TODO: This still feels super hacky. There's got to be some way to have these
+            // all be part of the same future.
+            Either::B(futures::future::ok(error_response(404, "Not found".to_string())))
+        },
+    }
+}
+
+// Where plain HTTP requests are redirected to, and the hosts that keep their
+// own name
+struct RedirectTarget<'a> {
+    host: &'a str,
+    secure_port: u16,
+    config: &'a RedirectConfig,
+    hoster_domain: Option<String>,
+    domains: HosterDomains,
+}
+
+fn http_routes(
+    routes: BoxedFilter<(Box<dyn Reply>,)>,
+    secure_routes: BoxedFilter<(Box<dyn Reply>,)>,
+    hsts: Option<String>,
+    target: RedirectTarget,
+    acme_challenges: acme::Challenges,
+    trusted_proxies: Arc<TrustedProxies>,
+) -> BoxedFilter<(Box<dyn Reply>,)> {
+
+    let RedirectTarget { host, secure_port, config: redirect, hoster_domain, domains } = target;
+
+    let host = match host.parse::<IpAddr>() {
+        Ok(IpAddr::V6(_)) => format!("[{}]", host),
+        _ => host.to_string(),
+    };
+    let secure_port_suffix = if secure_port == 443 && redirect.port == "omit-default" {
+        String::new()
+    }
+    else {
+        format!(":{}", secure_port)
+    };
+    let secure_redirect_link = format!("{}{}", host, secure_port_suffix);
+    let redirect_status = StatusCode::from_u16(redirect.status).expect("parse redirect status");
+
+    // A load balancer that terminates TLS itself sends secure requests here
+    // as plain HTTP, and redirecting those would loop forever. Anything the
+    // routes don't serve is a 404, so it can't fall through to the redirect.
+    let not_found = warp::any()
+        .map(move || {
+            let reply = warp::reply::with_status("Not Found", StatusCode::NOT_FOUND);
+            match &hsts {
+                Some(hsts) => Box::new(warp::reply::with_header(reply, "strict-transport-security", hsts.as_str())) as Box<dyn Reply>,
+                None => Box::new(reply) as Box<dyn Reply>,
+            }
+        });
+    let already_https = forwarded::forwarded(trusted_proxies.clone())
+        .and_then(|info: ForwardedInfo| {
+            match info.proto.as_ref().map(|proto| proto.as_str()) {
+                Some("https") => Ok(()),
+                _ => Err(warp::reject::not_found()),
+            }
+        })
+        .and(secure_routes.or(not_found).unify())
+        .map(|_, reply| reply);
+
+    let plain_paths = redirect.plain_paths.clone();
+    let plain_http = warp::path::full()
+        .and_then(move |path: FullPath| {
+            if plain_paths.iter().any(|plain_path| is_under(path.as_str(), plain_path)) {
+                Ok(())
+            }
+            else {
+                Err(warp::reject::not_found())
+            }
+        })
+        .and(routes)
+        .map(|_, reply| reply);
+
+    // Hoster subdomains and custom domains stay on the host that was asked
+    // for
+    let hoster_suffix = hoster_domain.map(|domain| format!(".{}", domain));
+    let is_hoster_host = move |host: &str| {
+        hoster_suffix.as_ref().map(|suffix| subdomain_id(host, suffix).is_some()).unwrap_or(false) ||
+            domains.lock().expect("get lock").contains_key(host)
+    };
+
+    // redirect http to https, to the host the client asked a trusted proxy
+    // for if there is one
+    let redirect = warp::path::full()
+        .and(forwarded::forwarded(trusted_proxies.clone()))
+        .and(request_host(trusted_proxies))
+        .map(move |path: FullPath, info: ForwardedInfo, host: String| {
+            let authority = match info.host {
+                Some(forwarded_host) => forwarded_host,
+                None if is_hoster_host(&host) => format!("{}{}", host, secure_port_suffix),
+                None => secure_redirect_link.clone(),
+            };
+            // The authority comes from the client, and may not be one
+            let uri = match Uri::builder()
+                .scheme("https")
+                .authority(authority.as_str())
+                .path_and_query(path.as_str())
+                .build() {
+                Ok(uri) => uri,
+                Err(_) => {
+                    return Box::new(warp::reply::with_status("Invalid host", StatusCode::BAD_REQUEST)) as Box<dyn Reply>;
+                },
+            };
+            let response = Response::builder()
+                .status(redirect_status)
+                .header("location", uri.to_string())
+                .body(Body::empty())
+                .expect("build redirect");
+            Box::new(response) as Box<dyn Reply>
+        });
+
+    let acme_challenge = acme::challenge_route(acme_challenges)
+        .map(|proof| Box::new(proof) as Box<dyn Reply>);
+
+    acme_challenge
+        .or(already_https)
+        .unify()
+        .or(plain_http)
+        .unify()
+        .or(redirect)
+        .unify()
+        .boxed()
+}
+
+fn invalid<E: ToString>(message: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message.to_string())
+}
+
+// Says what was being set up when a file or resource couldn't be
+fn failed<E: fmt::Display>(what: &str, e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, format!("Failed to {}: {}", what, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, process};
+    use crate::id_generator::validate_id;
+    use crate::hoster_manager::sign_url;
+
+    fn services(url_signer: UrlSigner) -> HosterServices {
+        let log = env::temp_dir().join(format!("fibridge-download-log-{}", process::id()));
+
+        HosterServices {
+            url_signer: Some(Arc::new(url_signer)),
+            metrics: Arc::new(Metrics::new()),
+            access_log: Arc::new(AccessLog::file(LogFormat::Json, log.to_str().unwrap(), 1024 * 1024, 0).unwrap()),
+            transfer_stats: Arc::new(TransferAggregator::new()),
+            approval_timeout: Duration::from_secs(30),
+            max_cached_size: 0,
+            base_path: String::new(),
+            domains: Arc::new(Mutex::new(HashMap::new())),
+            domain_verifier: None,
+            host: "localhost".to_string(),
+            hoster_domain: None,
+        }
+    }
+
+    #[test]
+    fn plain_paths_match_whole_segments() {
+        assert!(is_under("/public", "/public"));
+        assert!(is_under("/public/a.txt", "/public"));
+        assert!(is_under("/public/a.txt", "/public/"));
+        assert!(!is_under("/publicity", "/public"));
+        assert!(!is_under("/public", "/public/"));
+        assert!(!is_under("/private/public", "/public"));
+        assert!(is_under("/anything", "/"));
+    }
+
+    #[test]
+    fn websocket_path_is_reserved() {
+        let rules = id_rules(&segments("/connect/hosters"), None);
+        assert!(matches!(validate_id("connect", &rules), Err(IdError::Reserved(_))));
+        assert!(validate_id("hosters", &rules).is_ok());
+
+        let rules = id_rules(&[], None);
+        assert!(validate_id("connect", &rules).is_ok());
+    }
+
+    #[test]
+    fn static_names_are_reserved() {
+        let dir = env::temp_dir().join(format!("fibridge-static-ids-{}", process::id()));
+        fs::create_dir_all(dir.join("docs")).unwrap();
+        fs::write(dir.join("about"), b"").unwrap();
+
+        let rules = id_rules(&segments("/omnistreams"), dir.to_str());
+        assert!(matches!(validate_id("docs", &rules), Err(IdError::Reserved(_))));
+        assert!(matches!(validate_id("about", &rules), Err(IdError::Reserved(_))));
+        assert!(validate_id("files", &rules).is_ok());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn signed_links_are_accepted_for_unnormalized_paths() {
+        let key = env::temp_dir().join(format!("fibridge-url-key-{}", process::id()));
+        fs::write(&key, "hunter2").unwrap();
+        let url_signer = UrlSigner::from_file(key.to_str().unwrap()).unwrap();
+
+        let message = json!({
+            "jsonrpc": "2.0",
+            "method": "signUrl",
+            "params": { "path": "/docs/./my%20file.txt" },
+            "id": 1,
+        });
+        let response = sign_url("my-files", "", Some(&url_signer), &message);
+        let signed = response["result"]["path"].as_str().expect("signed path").to_string();
+
+        // /my-files/<filename>?expires=...&signature=...
+        let mut parts = signed.splitn(2, '?');
+        let filename = parts.next().unwrap().splitn(3, '/').nth(2).unwrap().to_string();
+        let params: HashMap<String, String> = parts.next().unwrap()
+            .split('&')
+            .map(|pair| {
+                let mut pair = pair.splitn(2, '=');
+                (pair.next().unwrap().to_string(), pair.next().unwrap().to_string())
+            })
+            .collect();
+
+        let services = services(url_signer);
+        let hoster_managers: HosterManagers = Arc::new(Mutex::new(HashMap::new()));
+        let download = |filename: &str| {
+            let request_info = RequestInfo::new("GET", signed.clone(), Version::HTTP_11, None, &HeaderMap::new());
+            handle_download(&hoster_managers, &services, "my-files".to_string(), filename.to_string(),
+                params.clone(), HeaderMap::new(), request_info).wait().unwrap()
+        };
+
+        // Past the signature check, there's no such hoster
+        assert_eq!(download(&filename).status(), StatusCode::NOT_FOUND);
+        assert_eq!(download("docs%2Fother.txt").status(), StatusCode::FORBIDDEN);
+
+        fs::remove_file(&key).unwrap();
+    }
+}
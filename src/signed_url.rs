@@ -0,0 +1,193 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::collections::HashMap;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use crate::now;
+
+
+type HmacSha256 = Hmac<Sha256>;
+
+// Links can be valid for up to a year
+pub const MAX_EXPIRES_IN: u64 = 365 * 24 * 60 * 60;
+
+// Everything but unreserved characters, so the filename stays a single path
+// segment even if it contains slashes
+const FILENAME: &AsciiSet = &NON_ALPHANUMERIC.remove(b'-').remove(b'.').remove(b'_').remove(b'~');
+
+#[derive(Debug)]
+pub enum SignatureError {
+    Missing,
+    Malformed,
+    Expired,
+    Mismatch,
+}
+
+impl fmt::Display for SignatureError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SignatureError::Missing => write!(f, "Missing signature"),
+            SignatureError::Malformed => write!(f, "Malformed signature"),
+            SignatureError::Expired => write!(f, "Link expired"),
+            SignatureError::Mismatch => write!(f, "Invalid signature"),
+        }
+    }
+}
+
+// Signs download links as HMAC-SHA256 over "<id>/<filename>\n<expires>",
+// where expires is a unix timestamp and filename isn't percent-encoded. The
+// hex encoded signature and the expiry travel in the query string:
+//
+//   /<id>/<filename>?expires=1893456000&signature=5f2c...
+pub struct UrlSigner {
+    key: Vec<u8>,
+}
+
+impl UrlSigner {
+    pub fn from_file(path: &str) -> io::Result<Self> {
+        let key = fs::read_to_string(path)?.trim().as_bytes().to_vec();
+
+        if key.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                format!("{}: empty signing key", path)));
+        }
+
+        Ok(Self {
+            key,
+        })
+    }
+
+    fn mac(&self, id: &str, filename: &str, expires: u64) -> HmacSha256 {
+        let mut mac = HmacSha256::new_varkey(&self.key).expect("hmac key");
+        mac.input(format!("{}/{}\n{}", id, filename, expires).as_bytes());
+        mac
+    }
+
+    pub fn sign(&self, id: &str, filename: &str, expires: u64) -> String {
+        hex::encode(self.mac(id, filename, expires).result().code())
+    }
+
+    // None if expires_in is more than MAX_EXPIRES_IN
+    pub fn signed_path(&self, id: &str, filename: &str, expires_in: u64) -> Option<String> {
+        if expires_in > MAX_EXPIRES_IN {
+            return None;
+        }
+
+        let expires = now().checked_add(expires_in)?;
+        let signature = self.sign(id, filename, expires);
+        let filename = utf8_percent_encode(filename, FILENAME);
+        Some(format!("/{}/{}?expires={}&signature={}", id, filename, expires, signature))
+    }
+
+    pub fn verify(&self, id: &str, filename: &str, params: &HashMap<String, String>) -> Result<(), SignatureError> {
+
+        let (expires, signature) = match (params.get("expires"), params.get("signature")) {
+            (Some(expires), Some(signature)) => (expires, signature),
+            _ => return Err(SignatureError::Missing),
+        };
+
+        let expires = expires.parse::<u64>().map_err(|_| SignatureError::Malformed)?;
+        let signature = hex::decode(signature).map_err(|_| SignatureError::Malformed)?;
+
+        // Check the signature first so an attacker can't probe expiry
+        // handling with forged links.
+        self.mac(id, filename, expires).verify(&signature)
+            .map_err(|_| SignatureError::Mismatch)?;
+
+        if expires <= now() {
+            return Err(SignatureError::Expired);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signer() -> UrlSigner {
+        UrlSigner {
+            key: b"hunter2".to_vec(),
+        }
+    }
+
+    // The query string of a signed path, as the download route sees it
+    fn params(path: &str) -> HashMap<String, String> {
+        let query = path.splitn(2, '?').nth(1).expect("query");
+        query.split('&')
+            .map(|pair| {
+                let mut parts = pair.splitn(2, '=');
+                (parts.next().unwrap().to_string(), parts.next().unwrap().to_string())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn verifies_its_own_links() {
+        let path = signer().signed_path("my-files", "file.txt", 3600).expect("sign");
+        assert!(path.starts_with("/my-files/file.txt?expires="));
+        assert!(signer().verify("my-files", "file.txt", &params(&path)).is_ok());
+    }
+
+    #[test]
+    fn rejects_links_for_other_files_or_keys() {
+        let path = signer().signed_path("my-files", "file.txt", 3600).expect("sign");
+
+        assert!(matches!(signer().verify("my-files", "other.txt", &params(&path)), Err(SignatureError::Mismatch)));
+        assert!(matches!(signer().verify("other-id", "file.txt", &params(&path)), Err(SignatureError::Mismatch)));
+
+        let other = UrlSigner { key: b"hunter3".to_vec() };
+        assert!(matches!(other.verify("my-files", "file.txt", &params(&path)), Err(SignatureError::Mismatch)));
+    }
+
+    #[test]
+    fn rejects_extended_expiry() {
+        let path = signer().signed_path("my-files", "file.txt", 3600).expect("sign");
+        let mut params = params(&path);
+        let expires: u64 = params["expires"].parse().unwrap();
+        params.insert("expires".to_string(), (expires + 1).to_string());
+
+        assert!(matches!(signer().verify("my-files", "file.txt", &params), Err(SignatureError::Mismatch)));
+    }
+
+    #[test]
+    fn rejects_expired_links() {
+        let expires = now() - 1;
+        let mut params = HashMap::new();
+        params.insert("expires".to_string(), expires.to_string());
+        params.insert("signature".to_string(), signer().sign("my-files", "file.txt", expires));
+
+        assert!(matches!(signer().verify("my-files", "file.txt", &params), Err(SignatureError::Expired)));
+    }
+
+    #[test]
+    fn rejects_missing_and_malformed_signatures() {
+        assert!(matches!(signer().verify("my-files", "file.txt", &HashMap::new()), Err(SignatureError::Missing)));
+
+        let mut params = HashMap::new();
+        params.insert("expires".to_string(), "soon".to_string());
+        params.insert("signature".to_string(), "00".to_string());
+        assert!(matches!(signer().verify("my-files", "file.txt", &params), Err(SignatureError::Malformed)));
+
+        params.insert("expires".to_string(), "4102444800".to_string());
+        params.insert("signature".to_string(), "not hex".to_string());
+        assert!(matches!(signer().verify("my-files", "file.txt", &params), Err(SignatureError::Malformed)));
+    }
+
+    #[test]
+    fn caps_the_expiry() {
+        assert!(signer().signed_path("my-files", "file.txt", MAX_EXPIRES_IN).is_some());
+        assert!(signer().signed_path("my-files", "file.txt", MAX_EXPIRES_IN + 1).is_none());
+        assert!(signer().signed_path("my-files", "file.txt", u64::max_value()).is_none());
+    }
+
+    #[test]
+    fn encodes_filenames_into_one_segment() {
+        let path = signer().signed_path("my-files", "dir/my file.txt", 3600).expect("sign");
+        assert!(path.starts_with("/my-files/dir%2Fmy%20file.txt?"));
+        assert!(signer().verify("my-files", "dir/my file.txt", &params(&path)).is_ok());
+    }
+}
@@ -0,0 +1,75 @@
+use std::fs;
+use std::io;
+use warp::{self, Filter, Reply};
+use warp::filters::BoxedFilter;
+
+
+// The page and assets served at /. A landing page replaces just the index; a
+// static directory serves a whole tree, with its index.html at /. Without
+// either, the GUI compiled in with the embedded-gui feature is used, if any.
+pub fn routes(static_dir: Option<&str>, index_page: Option<&str>, cache_max_age: u64) -> BoxedFilter<(Box<dyn Reply>,)> {
+    let index = match index_page {
+        Some(index_page) => {
+            warp::path::end()
+                .and(warp::fs::file(index_page.to_string()))
+                .map(move |file: warp::fs::File| cached(file, cache_max_age))
+                .boxed()
+        },
+        None => embedded_index(),
+    };
+
+    match static_dir {
+        Some(static_dir) => {
+            let files = warp::get2()
+                .and(warp::fs::dir(static_dir.to_string()))
+                .map(move |file: warp::fs::File| cached(file, cache_max_age));
+
+            // A landing page takes precedence over the directory's index.html
+            index.or(files).unify().boxed()
+        },
+        None => index,
+    }
+}
+
+// The files and directories at the top of static_dir. They're served ahead
+// of downloads, so hosters can't be given these as ids.
+pub fn top_level_names(static_dir: &str) -> io::Result<Vec<String>> {
+    fs::read_dir(static_dir)?
+        .map(|entry| entry.map(|entry| entry.file_name().to_string_lossy().to_string()))
+        .collect()
+}
+
+// warp sets the Content-Type from the extension and Last-Modified, and
+// answers If-Modified-Since itself. HTML is always revalidated so a new
+// release of the GUI shows up straight away, while the assets it references
+// can be cached for cache_max_age seconds.
+fn cached(file: warp::fs::File, cache_max_age: u64) -> Box<dyn Reply> {
+    let is_html = file.path().extension()
+        .map(|extension| extension == "html" || extension == "htm")
+        .unwrap_or(false);
+
+    let cache_control = if is_html {
+        "no-cache".to_string()
+    }
+    else {
+        format!("public, max-age={}", cache_max_age)
+    };
+
+    Box::new(warp::reply::with_header(file, "cache-control", cache_control))
+}
+
+#[cfg(feature = "embedded-gui")]
+fn embedded_index() -> BoxedFilter<(Box<dyn Reply>,)> {
+    warp::path::end()
+        .map(|| {
+            Box::new(warp::reply::html(include_str!("../../fibridge-gui-js/dist/index.html"))) as Box<dyn Reply>
+        })
+        .boxed()
+}
+
+#[cfg(not(feature = "embedded-gui"))]
+fn embedded_index() -> BoxedFilter<(Box<dyn Reply>,)> {
+    warp::path::end()
+        .and_then(|| Err::<Box<dyn Reply>, _>(warp::reject::not_found()))
+        .boxed()
+}
@@ -1,26 +1,229 @@
 use std::sync::{Arc, Mutex};
-use futures::{Future, Stream, Sink};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use futures::{Async, Future, Stream, Sink, Poll};
 use futures::sync::{mpsc};
 use omnistreams::{
     Streamer, Producer, Consumer, Conduit, CancelReason,
     ProducerEventRx, ConsumerEventRx, MapConduit, Message,
     MapConsumer, MapProducer, ConsumerEvent,
 };
+use tracing::{info, Span};
+use tracing_futures::Instrument;
+use crate::metrics::Metrics;
+use crate::transfer_stats::{
+    TransferInfo, TransferStats, TransferProgress, TransferOutcome, TransferEvent,
+    TransferAggregator,
+};
+
+
+// How often progress events are published for each transfer
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(500);
+
+
+// Running totals across all of a hoster's transfers, which are also fed into
+// the process wide metrics.
+pub struct HosterStats {
+    pub active_transfers: AtomicUsize,
+    pub bytes_served: AtomicUsize,
+    metrics: Arc<Metrics>,
+}
+
+impl HosterStats {
+    pub fn new(metrics: Arc<Metrics>) -> Self {
+        Self {
+            active_transfers: AtomicUsize::new(0),
+            bytes_served: AtomicUsize::new(0),
+            metrics,
+        }
+    }
+
+    pub fn cache_lookup(&self, hit: bool) {
+        self.metrics.cache_lookup(hit);
+    }
+
+    fn transfer_started(&self) {
+        self.active_transfers.fetch_add(1, Ordering::SeqCst);
+        self.metrics.conduit_opened();
+    }
+
+    fn bytes_sent(&self, num_bytes: usize) {
+        self.bytes_served.fetch_add(num_bytes, Ordering::SeqCst);
+        self.metrics.bytes_streamed(num_bytes);
+    }
+
+    fn transfer_finished(&self) {
+        self.active_transfers.fetch_sub(1, Ordering::SeqCst);
+        self.metrics.conduit_closed();
+    }
+}
+
+struct TransferState {
+    info: TransferInfo,
+    hoster_stats: Arc<HosterStats>,
+    aggregator: Arc<TransferAggregator>,
+    bytes: usize,
+    first_byte_at: Option<Instant>,
+    last_progress_at: Instant,
+    finished: bool,
+}
+
+impl TransferState {
+    fn new(info: TransferInfo, hoster_stats: Arc<HosterStats>, aggregator: Arc<TransferAggregator>) -> Self {
+        hoster_stats.transfer_started();
+        aggregator.publish(TransferEvent::Started(info.clone()));
+
+        let mut state = Self {
+            info,
+            hoster_stats,
+            aggregator,
+            bytes: 0,
+            first_byte_at: None,
+            last_progress_at: Instant::now(),
+            finished: false,
+        };
+
+        // Empty files never see any data, so they're done before they start
+        if state.info.expected_bytes == 0 {
+            state.finish(TransferOutcome::Completed);
+        }
+
+        state
+    }
+
+    // Counts a chunk on its way to the client
+    fn record(&mut self, num_bytes: usize) {
+        if self.first_byte_at.is_none() && num_bytes > 0 {
+            self.first_byte_at = Some(Instant::now());
+        }
+
+        self.bytes += num_bytes;
+        self.hoster_stats.bytes_sent(num_bytes);
+
+        if self.bytes >= self.info.expected_bytes {
+            self.finish(TransferOutcome::Completed);
+        }
+        else {
+            self.progress();
+        }
+    }
+
+    // The stream ended. Short of the expected size, the client got a
+    // truncated file.
+    fn end(&mut self) {
+        if self.bytes < self.info.expected_bytes {
+            self.finish(TransferOutcome::Errored);
+        }
+        else {
+            self.finish(TransferOutcome::Completed);
+        }
+    }
+
+    // A transfer can end in more than one way at once, e.g. the last chunk
+    // arriving as the client hangs up, but is only reported once.
+    fn finish(&mut self, outcome: TransferOutcome) {
+        if self.finished {
+            return;
+        }
+        self.finished = true;
+
+        self.hoster_stats.transfer_finished();
+
+        let started = self.info.request.started;
+
+        self.aggregator.publish(TransferEvent::Finished(TransferStats {
+            request_id: self.info.request_id,
+            hoster_id: self.info.hoster_id.clone(),
+            status: self.info.status,
+            request: self.info.request.clone(),
+            bytes: self.bytes,
+            duration: started.elapsed(),
+            time_to_first_byte: self.first_byte_at.map(|at| at.duration_since(started)),
+            outcome,
+        }));
+    }
+
+    fn progress(&mut self) {
+        let now = Instant::now();
+        if self.finished || now.duration_since(self.last_progress_at) < PROGRESS_INTERVAL {
+            return;
+        }
+        self.last_progress_at = now;
+
+        self.aggregator.publish(TransferEvent::Progress(TransferProgress {
+            request_id: self.info.request_id,
+            hoster_id: self.info.hoster_id.clone(),
+            bytes: self.bytes,
+            expected_bytes: self.info.expected_bytes,
+            elapsed: self.info.request.started.elapsed(),
+        }));
+    }
+}
+
+// Lets the owner of a transfer end it after the conduit has been piped.
+#[derive(Clone)]
+pub struct TransferHandle {
+    state: Arc<Mutex<TransferState>>,
+    cancel_tx: mpsc::UnboundedSender<ConsumerEvent>,
+}
+
+impl TransferHandle {
+    // Tells the upstream producer to stop sending and reports the transfer
+    // as cancelled. Returns false if the transfer had already finished.
+    pub fn cancel(&self, reason: CancelReason) -> bool {
+        let mut state = self.state.lock().expect("lock");
+
+        if state.finished {
+            return false;
+        }
+
+        info!(request_id = state.info.request_id, bytes = state.bytes, ?reason, "transfer cancelled");
+
+        match self.cancel_tx.unbounded_send(ConsumerEvent::Cancellation(reason)) {
+            Ok(_) => (),
+            Err(_) => (),
+        }
+
+        state.finish(TransferOutcome::Cancelled);
+        true
+    }
+
+    // Reports the transfer as failed, e.g. because the hoster went away
+    pub fn fail(&self) {
+        self.state.lock().expect("lock").finish(TransferOutcome::Errored);
+    }
+
+    // Reports the transfer as over once its stream has ended, which is only
+    // a success if every expected byte made it through
+    pub fn end(&self) {
+        self.state.lock().expect("lock").end();
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.state.lock().expect("lock").finished
+    }
+}
 
 pub struct StatsConduit {
     consumer: MapConsumer<Message>,
     producer: MapProducer<Message>,
+    handle: TransferHandle,
 }
 
 impl StatsConduit {
-    pub fn new(request_id: usize) -> Self {
+    pub fn new(
+        info: TransferInfo,
+        hoster_stats: Arc<HosterStats>,
+        aggregator: Arc<TransferAggregator>,
+    ) -> Self {
 
-        let total_bytes: Arc<Mutex<usize>> = Arc::new(Mutex::new(0));
-        let total_bytes_clone = total_bytes.clone();
+        let request_id = info.request_id;
 
+        let state = Arc::new(Mutex::new(TransferState::new(info, hoster_stats, aggregator)));
+
+        let byte_counter_state = state.clone();
         let byte_counter = MapConduit::new(move |item: Message| {
-            let mut val = total_bytes.lock().expect("lock");
-            *val += item.len();
+            byte_counter_state.lock().expect("lock").record(item.len());
             item
         });
 
@@ -30,13 +233,18 @@ impl StatsConduit {
         let events = c_byte.event_stream().expect("get events");
 
         let (tx, rx) = mpsc::unbounded();
+        let cancel_tx = tx.clone();
         let tx = tx.sink_map_err(|_| ());
 
+        let events_state = state.clone();
         let events_fut = events.map(move |event| {
-            match event {
-                ConsumerEvent::Cancellation(_) => {
-                    let val = total_bytes_clone.lock().expect("lock");
-                    println!("Canceled {} after {} bytes", request_id, *val);
+            match &event {
+                ConsumerEvent::Cancellation(reason) => {
+                    let mut state = events_state.lock().expect("lock");
+                    if !state.finished {
+                        info!(request_id, bytes = state.bytes, ?reason, "transfer cancelled");
+                    }
+                    state.finish(TransferOutcome::Cancelled);
                 },
                 _ => (),
             }
@@ -46,20 +254,77 @@ impl StatsConduit {
         .forward(tx)
         .map(|_| ());
 
-        warp::spawn(events_fut);
+        // Keep the cancellation log inside the request's span
+        warp::spawn(events_fut.instrument(Span::current()));
 
         c_byte.set_event_stream(rx);
 
         Self {
             consumer: c_byte,
             producer: p_byte,
+            handle: TransferHandle {
+                state,
+                cancel_tx,
+            },
         }
     }
+
+    pub fn handle(&self) -> TransferHandle {
+        self.handle.clone()
+    }
 }
 
 impl Streamer for StatsConduit {
-    fn cancel(&mut self, _reason: CancelReason) {
-        // TODO: implement cancel
+    fn cancel(&mut self, reason: CancelReason) {
+        self.handle.cancel(reason);
+    }
+}
+
+// Runs a callback when the wrapped stream is dropped, whether or not it was
+// read to the end, and optionally another once it ends.
+pub struct CancelOnDrop<S> {
+    inner: S,
+    on_end: Option<Box<dyn FnOnce() + Send>>,
+    on_drop: Option<Box<dyn FnOnce() + Send>>,
+}
+
+impl<S> CancelOnDrop<S> {
+    pub fn new<F: FnOnce() + Send + 'static>(inner: S, on_drop: F) -> Self {
+        Self {
+            inner,
+            on_end: None,
+            on_drop: Some(Box::new(on_drop)),
+        }
+    }
+
+    pub fn on_end<F: FnOnce() + Send + 'static>(mut self, on_end: F) -> Self {
+        self.on_end = Some(Box::new(on_end));
+        self
+    }
+}
+
+impl<S: Stream> Stream for CancelOnDrop<S> {
+    type Item = S::Item;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        let result = self.inner.poll();
+
+        if let Ok(Async::Ready(None)) = result {
+            if let Some(on_end) = self.on_end.take() {
+                on_end();
+            }
+        }
+
+        result
+    }
+}
+
+impl<S> Drop for CancelOnDrop<S> {
+    fn drop(&mut self) {
+        if let Some(on_drop) = self.on_drop.take() {
+            on_drop();
+        }
     }
 }
 
@@ -104,3 +369,201 @@ impl Conduit<Message, Message> for StatsConduit {
         (self.consumer, self.producer)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::stream;
+    use warp::http::{HeaderMap, Version};
+    use crate::request_info::RequestInfo;
+    use crate::transfer_stats::TransferStats;
+
+    struct Transfer {
+        handle: TransferHandle,
+        hoster_stats: Arc<HosterStats>,
+        finished: mpsc::UnboundedReceiver<TransferEvent>,
+        cancellations: mpsc::UnboundedReceiver<ConsumerEvent>,
+    }
+
+    fn transfer(expected_bytes: usize) -> Transfer {
+        let hoster_stats = Arc::new(HosterStats::new(Arc::new(Metrics::new())));
+        let aggregator = Arc::new(TransferAggregator::new());
+        let finished = aggregator.subscribe();
+
+        let info = TransferInfo {
+            request_id: 7,
+            hoster_id: "my-files".to_string(),
+            status: 200,
+            expected_bytes,
+            request: RequestInfo::new("GET", "/my-files/file.txt".to_string(), Version::HTTP_11, None, &HeaderMap::new()),
+        };
+
+        let (cancel_tx, cancellations) = mpsc::unbounded();
+
+        Transfer {
+            handle: TransferHandle {
+                state: Arc::new(Mutex::new(TransferState::new(info, hoster_stats.clone(), aggregator))),
+                cancel_tx,
+            },
+            hoster_stats,
+            finished,
+            cancellations,
+        }
+    }
+
+    impl Transfer {
+        fn record(&self, num_bytes: usize) {
+            self.handle.state.lock().unwrap().record(num_bytes);
+        }
+
+        fn active(&self) -> usize {
+            self.hoster_stats.active_transfers.load(Ordering::SeqCst)
+        }
+
+        // Every transfer reported as finished so far
+        fn finished(&mut self) -> Vec<TransferStats> {
+            let finished = &mut self.finished;
+            futures::future::lazy(move || {
+                let mut stats = Vec::new();
+                while let Ok(Async::Ready(Some(event))) = finished.poll() {
+                    if let TransferEvent::Finished(finished) = event {
+                        stats.push(finished);
+                    }
+                }
+                Ok::<_, ()>(stats)
+            }).wait().unwrap()
+        }
+
+        fn cancellations(&mut self) -> usize {
+            let cancellations = &mut self.cancellations;
+            futures::future::lazy(move || {
+                let mut count = 0;
+                while let Ok(Async::Ready(Some(ConsumerEvent::Cancellation(_)))) = cancellations.poll() {
+                    count += 1;
+                }
+                Ok::<_, ()>(count)
+            }).wait().unwrap()
+        }
+    }
+
+    #[test]
+    fn complete_streams_finish_once() {
+        let mut transfer = transfer(10);
+        assert_eq!(transfer.active(), 1);
+
+        transfer.record(4);
+        transfer.record(6);
+        transfer.handle.end();
+
+        let finished = transfer.finished();
+        assert_eq!(finished.len(), 1);
+        assert!(matches!(finished[0].outcome, TransferOutcome::Completed));
+        assert_eq!(finished[0].bytes, 10);
+        assert!(finished[0].time_to_first_byte.is_some());
+        assert_eq!(transfer.active(), 0);
+        assert_eq!(transfer.hoster_stats.bytes_served.load(Ordering::SeqCst), 10);
+    }
+
+    #[test]
+    fn short_streams_are_errors() {
+        let mut transfer = transfer(10);
+
+        transfer.record(4);
+        transfer.handle.end();
+
+        let finished = transfer.finished();
+        assert_eq!(finished.len(), 1);
+        assert!(matches!(finished[0].outcome, TransferOutcome::Errored));
+        assert_eq!(finished[0].bytes, 4);
+        assert_eq!(transfer.active(), 0);
+        assert!(!transfer.handle.cancel(CancelReason::Disconnected));
+    }
+
+    #[test]
+    fn cancelled_streams_tell_the_producer() {
+        let mut transfer = transfer(10);
+
+        transfer.record(4);
+        assert!(transfer.handle.cancel(CancelReason::Disconnected));
+        assert!(!transfer.handle.cancel(CancelReason::Disconnected));
+        transfer.handle.end();
+
+        let finished = transfer.finished();
+        assert_eq!(finished.len(), 1);
+        assert!(matches!(finished[0].outcome, TransferOutcome::Cancelled));
+        assert_eq!(transfer.cancellations(), 1);
+        assert_eq!(transfer.active(), 0);
+    }
+
+    #[test]
+    fn empty_files_are_complete_from_the_start() {
+        let mut transfer = transfer(0);
+
+        assert!(transfer.handle.is_finished());
+        assert!(matches!(transfer.finished()[0].outcome, TransferOutcome::Completed));
+        assert_eq!(transfer.active(), 0);
+    }
+
+    #[test]
+    fn dropped_bodies_cancel_the_transfer() {
+        let mut transfer = transfer(10);
+        transfer.record(4);
+
+        let (_body_tx, body_rx) = mpsc::unbounded::<Message>();
+        let handle = transfer.handle.clone();
+        let body = CancelOnDrop::new(body_rx, move || {
+            handle.cancel(CancelReason::Disconnected);
+        });
+
+        // hyper dropping the body of a client that went away
+        drop(body);
+
+        assert_eq!(transfer.cancellations(), 1);
+        assert!(transfer.handle.is_finished());
+        assert!(matches!(transfer.finished()[0].outcome, TransferOutcome::Cancelled));
+        assert_eq!(transfer.active(), 0);
+    }
+
+    #[test]
+    fn dropping_a_finished_body_cancels_nothing() {
+        let mut transfer = transfer(4);
+
+        let (body_tx, body_rx) = mpsc::unbounded::<Message>();
+        let handle = transfer.handle.clone();
+        let end_handle = transfer.handle.clone();
+        let mut body = CancelOnDrop::new(body_rx, move || {
+            handle.cancel(CancelReason::Disconnected);
+        })
+        .on_end(move || end_handle.end());
+
+        transfer.record(4);
+        body_tx.unbounded_send(vec![0; 4]).unwrap();
+        drop(body_tx);
+        futures::future::lazy(|| {
+            while let Ok(Async::Ready(Some(_))) = body.poll() {}
+            Ok::<_, ()>(())
+        }).wait().unwrap();
+        drop(body);
+
+        assert_eq!(transfer.cancellations(), 0);
+        assert!(matches!(transfer.finished()[0].outcome, TransferOutcome::Completed));
+    }
+
+    #[test]
+    fn runs_the_end_callback_once_the_stream_ends() {
+        let ended = Arc::new(AtomicUsize::new(0));
+        let ended_clone = ended.clone();
+
+        let stream = CancelOnDrop::new(stream::iter_ok::<_, ()>(vec![1, 2]), || ())
+            .on_end(move || {
+                ended_clone.fetch_add(1, Ordering::SeqCst);
+            });
+
+        let mut items = stream.wait();
+        assert_eq!(items.next(), Some(Ok(1)));
+        assert_eq!(items.next(), Some(Ok(2)));
+        assert_eq!(ended.load(Ordering::SeqCst), 0);
+        assert_eq!(items.next(), None);
+        assert_eq!(ended.load(Ordering::SeqCst), 1);
+    }
+}
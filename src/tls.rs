@@ -0,0 +1,373 @@
+use std::io::{self, BufReader};
+use std::fs::{self, File};
+use std::sync::{Arc, RwLock};
+use std::error::Error as StdError;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant, SystemTime};
+use futures::{stream, Future, Stream};
+use futures::future::Map;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::timer::{Interval, Timeout};
+use tokio_rustls::TlsAcceptor;
+#[cfg(unix)]
+use tokio_signal::unix::{Signal, SIGHUP};
+use rustls::{ClientHello, NoClientAuth, ResolvesServerCert, ServerConfig};
+use rustls::internal::pemfile;
+use rustls::sign::{self, CertifiedKey};
+use hyper::{Body, Request, Response};
+use hyper::header::{HeaderValue, STRICT_TRANSPORT_SECURITY};
+use hyper::service::Service;
+use tracing::{info, error, debug};
+use crate::connection;
+use crate::forwarded::Peer;
+
+
+// Clients that connect but never finish a handshake are dropped after this
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// A certificate and its private key, both PEM files
+#[derive(Clone, Debug, PartialEq)]
+pub struct CertFiles {
+    pub cert: String,
+    pub key: String,
+}
+
+struct Entry {
+    // Empty for the listener's default certificate
+    names: Vec<String>,
+    files: CertFiles,
+    // None until a certificate that's still being provisioned turns up
+    key: RwLock<Option<CertifiedKey>>,
+    // Written by the ACME thread, which reloads it once both files are in
+    // place. Reloading it any other time could pair a new key with the old
+    // certificate.
+    provisioned: bool,
+    modified: RwLock<Option<(SystemTime, SystemTime)>>,
+}
+
+impl Entry {
+    fn load(names: Vec<String>, files: CertFiles) -> io::Result<Self> {
+        let key = load_certified_key(&files)?;
+        let modified = modified(&files);
+
+        Ok(Self {
+            names,
+            files,
+            key: RwLock::new(Some(key)),
+            provisioned: false,
+            modified: RwLock::new(modified),
+        })
+    }
+
+    fn pending(files: CertFiles) -> Self {
+        Self {
+            names: Vec::new(),
+            files,
+            key: RwLock::new(None),
+            provisioned: true,
+            modified: RwLock::new(None),
+        }
+    }
+
+    fn matches(&self, server_name: &str) -> bool {
+        self.names.iter().any(|name| name_matches(name, server_name))
+    }
+
+    // A bad or half written file leaves the previous certificate in place
+    fn reload(&self) {
+        match load_certified_key(&self.files) {
+            Ok(key) => {
+                *self.key.write().expect("write lock") = Some(key);
+                *self.modified.write().expect("write lock") = modified(&self.files);
+                info!(cert = %self.files.cert, "reloaded certificate");
+            },
+            Err(e) => {
+                error!(cert = %self.files.cert, "Failed to reload certificate, keeping the old one: {}", e);
+            },
+        }
+    }
+
+    fn reload_if_changed(&self) {
+        let current = modified(&self.files);
+
+        if current.is_some() && current != *self.modified.read().expect("read lock") {
+            self.reload();
+        }
+    }
+}
+
+// Picks a certificate by the server name the client asked for, falling back
+// to the listener's own. Certificates can be swapped out while running; open
+// connections keep the one they were set up with.
+pub struct CertResolver {
+    entries: Vec<Entry>,
+}
+
+impl CertResolver {
+    pub fn new(default: CertFiles, sni: &[(Vec<String>, CertFiles)]) -> io::Result<Self> {
+        Self::with_default(Entry::load(Vec::new(), default)?, sni)
+    }
+
+    // Like new, but the default certificate may not exist yet, e.g. while
+    // waiting for it to be issued over ACME. Clients without a matching SNI
+    // certificate fail the handshake until it does.
+    pub fn provisioned(default: CertFiles, sni: &[(Vec<String>, CertFiles)]) -> io::Result<Self> {
+        let default = match Entry::load(Vec::new(), default.clone()) {
+            Ok(mut entry) => {
+                entry.provisioned = true;
+                entry
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Entry::pending(default),
+            Err(e) => return Err(e),
+        };
+
+        Self::with_default(default, sni)
+    }
+
+    fn with_default(default: Entry, sni: &[(Vec<String>, CertFiles)]) -> io::Result<Self> {
+        let mut entries = vec![default];
+
+        for (names, files) in sni {
+            entries.push(Entry::load(names.clone(), files.clone())?);
+        }
+
+        Ok(Self {
+            entries,
+        })
+    }
+
+    // Everything but the provisioned certificate
+    pub fn reload(&self) {
+        for entry in self.entries.iter().filter(|entry| !entry.provisioned) {
+            entry.reload();
+        }
+    }
+
+    pub fn reload_if_changed(&self) {
+        for entry in self.entries.iter().filter(|entry| !entry.provisioned) {
+            entry.reload_if_changed();
+        }
+    }
+
+    // For the ACME thread, once it's written a new certificate
+    pub fn reload_provisioned(&self) {
+        for entry in self.entries.iter().filter(|entry| entry.provisioned) {
+            entry.reload();
+        }
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<CertifiedKey> {
+        let entry = client_hello.server_name()
+            .and_then(|server_name| {
+                let server_name: &str = server_name.into();
+                self.entries.iter().skip(1).find(|entry| entry.matches(server_name))
+            })
+            .unwrap_or(&self.entries[0]);
+
+        entry.key.read().expect("read lock").clone()
+    }
+}
+
+// *.example.com covers a.example.com but not example.com or a.b.example.com
+fn name_matches(name: &str, server_name: &str) -> bool {
+    let name = name.to_lowercase();
+    let server_name = server_name.to_lowercase();
+
+    if name.starts_with("*.") {
+        match server_name.find('.') {
+            Some(dot) => server_name[dot..] == name[1..],
+            None => false,
+        }
+    }
+    else {
+        name == server_name
+    }
+}
+
+fn load_certified_key(files: &CertFiles) -> io::Result<CertifiedKey> {
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+
+    let certs = pemfile::certs(&mut BufReader::new(File::open(&files.cert)?))
+        .map_err(|_| invalid(format!("Invalid certificate file '{}'", files.cert)))?;
+
+    if certs.is_empty() {
+        return Err(invalid(format!("No certificates in '{}'", files.cert)));
+    }
+
+    // Accept both PKCS#8 and the older RSA format
+    let mut keys = pemfile::pkcs8_private_keys(&mut BufReader::new(File::open(&files.key)?))
+        .map_err(|_| invalid(format!("Invalid key file '{}'", files.key)))?;
+
+    if keys.is_empty() {
+        keys = pemfile::rsa_private_keys(&mut BufReader::new(File::open(&files.key)?))
+            .map_err(|_| invalid(format!("Invalid key file '{}'", files.key)))?;
+    }
+
+    let key = keys.into_iter().next()
+        .ok_or_else(|| invalid(format!("No private key in '{}'", files.key)))?;
+
+    let signing_key = sign::any_supported_type(&key)
+        .map_err(|_| invalid(format!("Unsupported private key in '{}'", files.key)))?;
+
+    Ok(CertifiedKey::new(certs, Arc::new(signing_key)))
+}
+
+fn modified(files: &CertFiles) -> Option<(SystemTime, SystemTime)> {
+    let cert = fs::metadata(&files.cert).and_then(|metadata| metadata.modified()).ok()?;
+    let key = fs::metadata(&files.key).and_then(|metadata| metadata.modified()).ok()?;
+    Some((cert, key))
+}
+
+// Adds a Strict-Transport-Security header to every response from service,
+// including the ones warp makes for rejections, which never pass through a
+// route that could add it.
+pub struct WithHsts<S> {
+    service: S,
+    value: Option<HeaderValue>,
+}
+
+impl<S> WithHsts<S> {
+    pub fn new(service: S, value: Option<HeaderValue>) -> Self {
+        Self {
+            service,
+            value,
+        }
+    }
+}
+
+impl<S> Service for WithHsts<S>
+where
+    S: Service<ResBody = Body>,
+{
+    type ReqBody = S::ReqBody;
+    type ResBody = Body;
+    type Error = S::Error;
+    type Future = Map<S::Future, Box<dyn FnOnce(Response<Body>) -> Response<Body> + Send>>;
+
+    fn call(&mut self, request: Request<S::ReqBody>) -> Self::Future {
+        let value = self.value.clone();
+
+        self.service.call(request).map(Box::new(move |mut response: Response<Body>| {
+            if let Some(value) = value {
+                response.headers_mut().insert(STRICT_TRANSPORT_SECURITY, value);
+            }
+            response
+        }))
+    }
+}
+
+// Reload triggers, true to reload whether or not the files changed
+type Reloads = Box<dyn Stream<Item = bool, Error = ()> + Send>;
+
+// Reloads every certificate on SIGHUP, where there's such a thing, and any
+// whose files changed every interval, if there is one.
+pub fn watch(resolvers: Vec<Arc<CertResolver>>, interval: Option<Duration>) -> impl Future<Item = (), Error = ()> + Send {
+    let ticks = match interval {
+        Some(interval) => {
+            Box::new(Interval::new(Instant::now() + interval, interval)
+                .map(|_| false)
+                .map_err(|e| error!("Certificate reload timer failed: {}", e))) as Reloads
+        },
+        None => Box::new(stream::empty()) as Reloads,
+    };
+
+    sighups().select(ticks).for_each(move |forced| {
+        for resolver in &resolvers {
+            if forced {
+                resolver.reload();
+            }
+            else {
+                resolver.reload_if_changed();
+            }
+        }
+        Ok(())
+    })
+}
+
+#[cfg(unix)]
+fn sighups() -> Reloads {
+    Box::new(Signal::new(SIGHUP)
+        .flatten_stream()
+        .map(|_| true)
+        .map_err(|e| error!("Failed to listen for SIGHUP: {}", e)))
+}
+
+// Only file changes trigger reloads
+#[cfg(not(unix))]
+fn sighups() -> Reloads {
+    Box::new(stream::empty())
+}
+
+// Serves HTTPS on address with certificates from resolver. The TLS handshake
+// happens here rather than in warp so that certificates can change without
+// restarting the listener.
+pub fn serve<F, S>(address: SocketAddr, resolver: Arc<CertResolver>, new_service: F) -> io::Result<impl Future<Item = (), Error = ()> + Send>
+where
+    F: Fn() -> S + Send + Sync + 'static,
+    S: Service<ReqBody = Body, ResBody = Body> + 'static,
+    S::Future: Send + 'static,
+    S::Error: Into<Box<dyn StdError + Send + Sync>>,
+{
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config.cert_resolver = resolver;
+    config.set_protocols(&[b"http/1.1".to_vec()]);
+    let acceptor = TlsAcceptor::from(Arc::new(config));
+
+    let listener = TcpListener::bind(&address)?;
+    let new_service = Arc::new(new_service);
+
+    let server = connection::Incoming::new(listener)
+        .for_each(move |(stream, peer): (TcpStream, SocketAddr)| {
+            let new_service = new_service.clone();
+            let peer = Some(Peer::Addr(peer));
+
+            let connection = Timeout::new(acceptor.accept(stream), HANDSHAKE_TIMEOUT)
+                .map_err(|e| {
+                    if e.is_elapsed() {
+                        debug!("TLS handshake timed out");
+                    }
+                    else if let Some(e) = e.into_inner() {
+                        debug!("TLS handshake failed: {}", e);
+                    }
+                })
+                .and_then(move |stream| connection::serve(stream, peer, new_service));
+
+            hyper::rt::spawn(connection);
+            Ok(())
+        });
+
+    Ok(server)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exact_names() {
+        assert!(name_matches("example.com", "example.com"));
+        assert!(name_matches("Example.COM", "example.com"));
+        assert!(name_matches("example.com", "EXAMPLE.com"));
+        assert!(!name_matches("example.com", "www.example.com"));
+        assert!(!name_matches("www.example.com", "example.com"));
+    }
+
+    #[test]
+    fn wildcards_cover_one_label() {
+        assert!(name_matches("*.example.com", "a.example.com"));
+        assert!(name_matches("*.example.com", "A.Example.com"));
+        assert!(!name_matches("*.example.com", "example.com"));
+        assert!(!name_matches("*.example.com", "a.b.example.com"));
+        assert!(!name_matches("*.example.com", "aexample.com"));
+        assert!(!name_matches("*.example.com", "a.example.org"));
+        assert!(!name_matches("*.example.com", "localhost"));
+    }
+
+    #[test]
+    fn wildcards_are_only_special_in_names() {
+        assert!(!name_matches("example.com", "*.example.com"));
+        assert!(!name_matches("a.example.com", "*.example.com"));
+    }
+}
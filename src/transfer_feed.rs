@@ -0,0 +1,71 @@
+use std::sync::Arc;
+use std::io;
+use std::collections::HashMap;
+use futures::{Future, Stream};
+use futures::sync::oneshot;
+use warp::{self, Filter, Reply};
+use warp::filters::BoxedFilter;
+use warp::http::StatusCode;
+use crate::{HosterManagers, query_params};
+use crate::transfer_stats::{TransferAggregator, TransferEvent};
+
+
+// Live transfer activity for a hoster's GUI, as Server-Sent Events:
+//
+//   GET /omnistreams/<id>/events?token=<session token>
+//
+// under the configured WebSocket path, which websocket_path matches.
+//
+// The session token is handed to the hoster in a setSessionToken message when
+// it connects. EventSource can't set headers, so it goes in the query string.
+// The stream ends once the hoster disconnects.
+pub fn routes(
+    hoster_managers: HosterManagers,
+    transfer_stats: Arc<TransferAggregator>,
+    websocket_path: BoxedFilter<()>,
+) -> impl Filter<Extract = (Box<dyn Reply>,), Error = warp::Rejection> + Clone {
+
+    warp::get2()
+        .and(websocket_path)
+        .and(warp::path::param())
+        .and(warp::path("events"))
+        .and(warp::path::end())
+        .and(query_params())
+        .and(warp::sse())
+        .map(move |id: String, params: HashMap<String, String>, sse: warp::sse::Sse| {
+
+            let token = params.get("token").cloned().unwrap_or_default();
+
+            let closed = match session(&hoster_managers, &id, &token) {
+                Some(closed) => closed,
+                None => {
+                    return Box::new(warp::reply::with_status("Unauthorized", StatusCode::UNAUTHORIZED)) as Box<dyn Reply>;
+                },
+            };
+
+            // Ends the feed as soon as this hoster disconnects, rather than
+            // on the next event, which a new hoster with the same id could
+            // send.
+            let closed = closed.then(|_| Ok::<Option<TransferEvent>, ()>(None)).into_stream();
+
+            let events = transfer_stats.subscribe_hoster(&id)
+                .map(Some)
+                .select(closed)
+                .take_while(|event| Ok(event.is_some()))
+                .filter_map(|event| event)
+                .map(|event| {
+                    (warp::sse::event(event.name()), warp::sse::data(event.to_json().to_string()))
+                })
+                .map_err(|_| io::Error::new(io::ErrorKind::Other, "transfer feed closed"));
+
+            Box::new(sse.reply(warp::sse::keep_alive().stream(events))) as Box<dyn Reply>
+        })
+}
+
+// The hoster's close signal, if the token is its current session's
+fn session(hoster_managers: &HosterManagers, id: &str, token: &str) -> Option<oneshot::Receiver<()>> {
+    hoster_managers.lock().expect("get lock")
+        .get(id)
+        .filter(|manager| manager.check_session(token))
+        .map(|manager| manager.closed())
+}
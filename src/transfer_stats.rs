@@ -0,0 +1,245 @@
+use std::sync::Mutex;
+use std::time::Duration;
+use futures::Stream;
+use futures::sync::mpsc;
+use serde_json::{json, Value};
+use crate::request_info::RequestInfo;
+
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TransferOutcome {
+    Completed,
+    Cancelled,
+    Errored,
+}
+
+impl TransferOutcome {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TransferOutcome::Completed => "completed",
+            TransferOutcome::Cancelled => "cancelled",
+            TransferOutcome::Errored => "errored",
+        }
+    }
+}
+
+// Everything known about a transfer before any data flows
+#[derive(Clone, Debug)]
+pub struct TransferInfo {
+    pub request_id: usize,
+    pub hoster_id: String,
+    pub status: u16,
+    pub expected_bytes: usize,
+    pub request: RequestInfo,
+}
+
+// Emitted by StatsConduit once per transfer, when it completes, is cancelled
+// or fails.
+#[derive(Clone, Debug)]
+pub struct TransferStats {
+    pub request_id: usize,
+    pub hoster_id: String,
+    pub status: u16,
+    pub request: RequestInfo,
+    pub bytes: usize,
+    pub duration: Duration,
+    pub time_to_first_byte: Option<Duration>,
+    pub outcome: TransferOutcome,
+}
+
+impl TransferStats {
+    // Average bytes per second over the whole transfer
+    pub fn throughput(&self) -> f64 {
+        let seconds = as_secs_f64(self.duration);
+        if seconds > 0.0 {
+            self.bytes as f64 / seconds
+        }
+        else {
+            0.0
+        }
+    }
+}
+
+// Emitted periodically by StatsConduit while data is flowing
+#[derive(Clone, Debug)]
+pub struct TransferProgress {
+    pub request_id: usize,
+    pub hoster_id: String,
+    pub bytes: usize,
+    pub expected_bytes: usize,
+    pub elapsed: Duration,
+}
+
+impl TransferProgress {
+    pub fn throughput(&self) -> f64 {
+        let seconds = as_secs_f64(self.elapsed);
+        if seconds > 0.0 {
+            self.bytes as f64 / seconds
+        }
+        else {
+            0.0
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum TransferEvent {
+    Started(TransferInfo),
+    Progress(TransferProgress),
+    Finished(TransferStats),
+}
+
+impl TransferEvent {
+    pub fn hoster_id(&self) -> &str {
+        match self {
+            TransferEvent::Started(info) => &info.hoster_id,
+            TransferEvent::Progress(progress) => &progress.hoster_id,
+            TransferEvent::Finished(stats) => &stats.hoster_id,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            TransferEvent::Started(_) => "transferStarted",
+            TransferEvent::Progress(_) => "transferProgress",
+            TransferEvent::Finished(_) => "transferFinished",
+        }
+    }
+
+    // What the hoster's GUI gets to see. Client addresses are left out.
+    pub fn to_json(&self) -> Value {
+        match self {
+            TransferEvent::Started(info) => json!({
+                "requestId": info.request_id,
+                "path": info.request.path,
+                "status": info.status,
+                "size": info.expected_bytes,
+            }),
+            TransferEvent::Progress(progress) => json!({
+                "requestId": progress.request_id,
+                "bytes": progress.bytes,
+                "size": progress.expected_bytes,
+                "throughput": progress.throughput(),
+            }),
+            TransferEvent::Finished(stats) => json!({
+                "requestId": stats.request_id,
+                "bytes": stats.bytes,
+                "durationMs": (as_secs_f64(stats.duration) * 1000.0) as u64,
+                "throughput": stats.throughput(),
+                "outcome": stats.outcome.as_str(),
+            }),
+        }
+    }
+}
+
+pub fn as_secs_f64(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + f64::from(duration.subsec_nanos()) / 1e9
+}
+
+// Fans TransferEvents out to every interested subsystem. Subscribers that have
+// gone away are dropped on the next publish.
+#[derive(Default)]
+pub struct TransferAggregator {
+    subscribers: Mutex<Vec<Subscriber>>,
+}
+
+struct Subscriber {
+    // Only this hoster's events are queued, if set
+    hoster_id: Option<String>,
+    tx: mpsc::UnboundedSender<TransferEvent>,
+}
+
+impl TransferAggregator {
+    pub fn new() -> Self {
+        Self {
+            subscribers: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn subscribe(&self) -> mpsc::UnboundedReceiver<TransferEvent> {
+        self.add_subscriber(None)
+    }
+
+    // Events for one hoster's transfers. Others' are never queued, so a slow
+    // reader only ever holds on to its own hoster's backlog.
+    pub fn subscribe_hoster(&self, hoster_id: &str) -> mpsc::UnboundedReceiver<TransferEvent> {
+        self.add_subscriber(Some(hoster_id.to_string()))
+    }
+
+    fn add_subscriber(&self, hoster_id: Option<String>) -> mpsc::UnboundedReceiver<TransferEvent> {
+        let (tx, rx) = mpsc::unbounded();
+        self.subscribers.lock().expect("lock").push(Subscriber {
+            hoster_id,
+            tx,
+        });
+        rx
+    }
+
+    pub fn publish(&self, event: TransferEvent) {
+        self.subscribers.lock().expect("lock")
+            .retain(|subscriber| {
+                match &subscriber.hoster_id {
+                    Some(id) if id != event.hoster_id() => !subscriber.tx.is_closed(),
+                    _ => subscriber.tx.unbounded_send(event.clone()).is_ok(),
+                }
+            });
+    }
+
+    // Only the events for transfers that have ended
+    pub fn subscribe_finished(&self) -> impl Stream<Item = TransferStats, Error = ()> {
+        self.subscribe().filter_map(|event| {
+            match event {
+                TransferEvent::Finished(stats) => Some(stats),
+                _ => None,
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{Async, Future};
+
+    fn progress(hoster_id: &str) -> TransferEvent {
+        TransferEvent::Progress(TransferProgress {
+            request_id: 0,
+            hoster_id: hoster_id.to_string(),
+            bytes: 0,
+            expected_bytes: 0,
+            elapsed: Duration::from_secs(0),
+        })
+    }
+
+    #[test]
+    fn subscribe_hoster_only_queues_its_events() {
+        let aggregator = TransferAggregator::new();
+        let mut all = aggregator.subscribe();
+        let mut mine = aggregator.subscribe_hoster("mine");
+
+        aggregator.publish(progress("other"));
+        aggregator.publish(progress("mine"));
+
+        let received = |rx: &mut mpsc::UnboundedReceiver<TransferEvent>| {
+            let mut ids = Vec::new();
+            while let Ok(Async::Ready(Some(event))) = rx.poll() {
+                ids.push(event.hoster_id().to_string());
+            }
+            ids
+        };
+
+        futures::future::lazy(move || {
+            assert_eq!(received(&mut all), vec!["other", "mine"]);
+            assert_eq!(received(&mut mine), vec!["mine"]);
+            Ok::<(), ()>(())
+        }).wait().unwrap();
+    }
+
+    #[test]
+    fn dropped_subscribers_are_removed() {
+        let aggregator = TransferAggregator::new();
+        drop(aggregator.subscribe_hoster("mine"));
+        aggregator.publish(progress("other"));
+        assert!(aggregator.subscribers.lock().unwrap().is_empty());
+    }
+}
@@ -2,6 +2,7 @@ use futures::sync::mpsc;
 use futures::{Future, Stream, Sink};
 use warp::filters::ws::{Message, WebSocket};
 use omnistreams::{Transport};
+use tracing::warn;
 
 type OmniMessage = Vec<u8>;
 type MessageRx = mpsc::UnboundedReceiver<OmniMessage>;
@@ -11,6 +12,25 @@ type MessageTx = mpsc::UnboundedSender<OmniMessage>;
 pub struct WebSocketTransport {
     out_tx: MessageTx,
     in_rx: Option<MessageRx>,
+    close_tx: mpsc::UnboundedSender<()>,
+}
+
+// Lets the owner of a transport shut the WebSocket down after the transport
+// itself has been handed to a Multiplexer.
+#[derive(Clone)]
+pub struct CloseHandle {
+    close_tx: mpsc::UnboundedSender<()>,
+}
+
+impl CloseHandle {
+    pub fn close(&self) {
+        match self.close_tx.unbounded_send(()) {
+            Ok(_) => (),
+            Err(_) => {
+                warn!("Transport attempt to close already closed socket");
+            },
+        }
+    }
 }
 
 impl WebSocketTransport {
@@ -18,14 +38,19 @@ impl WebSocketTransport {
         let (ws_sink, ws_stream) = ws.split();
         let (out_tx, out_rx) = mpsc::unbounded::<OmniMessage>();
         let (in_tx, in_rx) = mpsc::unbounded::<OmniMessage>();
+        let (close_tx, close_rx) = mpsc::unbounded::<()>();
 
         let ws_sink = ws_sink
             .sink_map_err(|_e| ());
 
+        let close_messages = close_rx.map_err(|_e| ())
+            .map(|_| Message::close());
+
         let out_task = out_rx.map_err(|_e| ())
             .map(|omni_message| {
                 Message::binary(omni_message)
             })
+            .select(close_messages)
             .forward(ws_sink)
             .map(|_| ());
 
@@ -46,6 +71,13 @@ impl WebSocketTransport {
         Self {
             out_tx,
             in_rx: Some(in_rx),
+            close_tx,
+        }
+    }
+
+    pub fn close_handle(&self) -> CloseHandle {
+        CloseHandle {
+            close_tx: self.close_tx.clone(),
         }
     }
 }
@@ -56,7 +88,7 @@ impl Transport for WebSocketTransport {
             Ok(_) => {
             },
             Err(_) => {
-                eprintln!("Transport attempt to send on closed out_Tx");
+                warn!("Transport attempt to send on closed out_Tx");
             },
         }
     }
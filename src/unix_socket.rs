@@ -0,0 +1,121 @@
+use std::fs::{self, DirBuilder};
+use std::io;
+use std::sync::Arc;
+use std::error::Error as StdError;
+use std::path::Path;
+use std::process;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use futures::{Future, Stream};
+use tokio_uds::UnixListener;
+use hyper::Body;
+use hyper::service::Service;
+use crate::connection;
+use crate::forwarded::Peer;
+
+
+// Serves HTTP on a Unix domain socket. Requests are marked as coming from a
+// Unix socket peer, so the local reverse proxy's forwarded headers are
+// believed.
+pub fn serve<F, S>(path: &str, permissions: Option<u32>, new_service: F) -> io::Result<impl Future<Item = (), Error = ()> + Send>
+where
+    F: Fn() -> S + Send + Sync + 'static,
+    S: Service<ReqBody = Body, ResBody = Body> + 'static,
+    S::Future: Send + 'static,
+    S::Error: Into<Box<dyn StdError + Send + Sync>>,
+{
+    let listener = bind(path, permissions)?;
+    let new_service = Arc::new(new_service);
+
+    let server = connection::Incoming::new(listener)
+        .for_each(move |stream| {
+            hyper::rt::spawn(connection::serve(stream, Some(Peer::Unix), new_service.clone()));
+            Ok(())
+        });
+
+    Ok(server)
+}
+
+// A socket left behind by a previous run is removed first, since binding
+// would fail on it, but anything else at the path is left alone.
+fn bind(path: &str, permissions: Option<u32>) -> io::Result<UnixListener> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) => {
+            if metadata.file_type().is_socket() {
+                fs::remove_file(path)?;
+            }
+            else {
+                return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} exists and isn't a socket", path)));
+            }
+        },
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+        Err(e) => return Err(e),
+    }
+
+    match permissions {
+        Some(permissions) => bind_with_permissions(path, permissions),
+        None => UnixListener::bind(path),
+    }
+}
+
+// Binding creates the socket with the umask's permissions, so it's bound in a
+// directory only we can enter, given its permissions and then moved into
+// place. Nobody can connect before the permissions are set.
+fn bind_with_permissions(path: &str, permissions: u32) -> io::Result<UnixListener> {
+    let path = Path::new(path);
+    let name = path.file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "socket path has no file name"))?;
+    let dir = path.with_file_name(format!(".{}.{}", name.to_string_lossy(), process::id()));
+
+    DirBuilder::new().mode(0o700).create(&dir)?;
+
+    let tmp_path = dir.join(name);
+
+    let result = UnixListener::bind(&tmp_path).and_then(|listener| {
+        fs::set_permissions(&tmp_path, fs::Permissions::from_mode(permissions))?;
+        fs::rename(&tmp_path, path)?;
+        Ok(listener)
+    });
+
+    // The socket file has been moved out unless something failed
+    let _ = fs::remove_file(&tmp_path);
+    fs::remove_dir(&dir)?;
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn temp_path(name: &str) -> String {
+        env::temp_dir().join(format!("fibridge-{}-{}", process::id(), name)).to_string_lossy().to_string()
+    }
+
+    #[test]
+    fn refuses_to_remove_regular_files() {
+        let path = temp_path("not-a-socket");
+        fs::write(&path, "keep me").unwrap();
+
+        let result = bind(&path, None);
+
+        assert!(result.is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "keep me");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn replaces_stale_sockets_and_sets_permissions() {
+        let path = temp_path("socket");
+
+        drop(bind(&path, None).unwrap());
+        assert!(fs::symlink_metadata(&path).unwrap().file_type().is_socket());
+
+        drop(bind(&path, Some(0o600)).unwrap());
+        let metadata = fs::symlink_metadata(&path).unwrap();
+        assert!(metadata.file_type().is_socket());
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,128 @@
+use std::env;
+use std::fs;
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
+use warp::http::StatusCode;
+use fibridge_proxy_rs::{Config, ProxyServer, ProxyServerBuilder};
+use fibridge_proxy_rs::config::GuiConfig;
+
+
+#[test]
+fn builds_without_listeners() {
+    let server = ProxyServer::builder().build();
+
+    assert!(server.handle().hosters().is_empty());
+    assert!(server.handle().domains().lock().unwrap().is_empty());
+}
+
+#[test]
+fn unknown_hosters_are_not_found() {
+    let server = ProxyServer::builder().build();
+
+    let response = warp::test::request()
+        .path("/nope/file.txt")
+        .reply(&server.routes());
+
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    assert!(server.handle().hoster("nope").is_none());
+}
+
+#[test]
+fn serves_gui_config_under_the_prefix() {
+    let server = ProxyServer::builder()
+        .route_prefix("/files")
+        .websocket_path("/hosters")
+        .build();
+
+    let response = warp::test::request()
+        .path("/files/.fibridge/config")
+        .reply(&server.routes());
+
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let config: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+    assert_eq!(config["basePath"], "/files");
+    assert_eq!(config["websocketPath"], "/files/hosters");
+
+    let response = warp::test::request()
+        .path("/.fibridge/config")
+        .reply(&server.routes());
+
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[test]
+fn hoster_hosts_serve_nothing_at_the_root() {
+    let index_page = env::temp_dir().join(format!("fibridge-index-page-{}.html", process::id()));
+    fs::write(&index_page, "<h1>fibridge</h1>").unwrap();
+
+    let server = ProxyServer::builder()
+        .hoster_domain("files.example.com")
+        .gui(GuiConfig {
+            index_page: Some(index_page.to_string_lossy().to_string()),
+            ..GuiConfig::default()
+        })
+        .build();
+
+    let response = warp::test::request()
+        .path("/")
+        .header("host", "files.example.com")
+        .reply(&server.routes());
+
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = warp::test::request()
+        .path("/")
+        .header("host", "my-files.files.example.com")
+        .reply(&server.routes());
+
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    fs::remove_file(&index_page).unwrap();
+}
+
+#[test]
+fn disconnecting_releases_the_hoster() {
+    let server = ProxyServer::builder()
+        .id_request_timeout(Duration::from_secs(0))
+        .build();
+    let handle = server.handle();
+
+    let _hoster = warp::test::ws()
+        .path("/omnistreams")
+        .handshake(server.routes())
+        .expect("handshake");
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let id = loop {
+        if let Some(hoster) = handle.hosters().pop() {
+            break hoster.id;
+        }
+        assert!(Instant::now() < deadline, "hoster never connected");
+        thread::sleep(Duration::from_millis(10));
+    };
+
+    handle.domains().lock().unwrap().insert("files.example.org".to_string(), id.clone());
+
+    assert!(handle.disconnect(&id));
+    assert!(handle.hoster(&id).is_none());
+    assert!(handle.domains().lock().unwrap().is_empty());
+    assert!(!handle.disconnect(&id));
+}
+
+#[test]
+fn runs_without_listeners() {
+    let server = ProxyServer::builder().build();
+
+    assert!(server.run().is_ok());
+}
+
+#[test]
+fn missing_files_are_errors() {
+    let mut config = Config::default();
+    let path = env::temp_dir().join(format!("fibridge-missing-api-keys-{}", process::id()));
+    config.auth.api_keys = Some(path.to_string_lossy().to_string());
+
+    assert!(ProxyServerBuilder::from_config(&config).is_err());
+}